@@ -0,0 +1,130 @@
+#ifndef BITCASK_H
+#define BITCASK_H
+
+/* Generated by scripts/gen-header.sh from src/ffi.rs, do not edit. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define BITCASK_OK 0
+
+#define BITCASK_NOT_FOUND 1
+
+#define BITCASK_ERROR -1
+
+/**
+ * 打开的存储，由 `bitcask_open` 创建，`bitcask_close` 释放
+ */
+typedef struct Bitcask Bitcask;
+
+/**
+ * 按 key 的字节序遍历，由 `bitcask_iter_new` 创建，`bitcask_iter_free` 释放
+ */
+typedef struct BitcaskIter BitcaskIter;
+
+/**
+ * 当前线程上一次失败的错误信息，没有错误时返回 NULL
+ *
+ * 返回的字符串在当前线程下一次调用 bitcask 函数之前有效。
+ */
+const char *bitcask_last_error(void);
+
+/**
+ * 打开（不存在时创建）`path` 目录下的存储，失败时返回 NULL
+ *
+ * # Safety
+ *
+ * `path` 必须是以 NUL 结尾的字符串。
+ */
+struct Bitcask *bitcask_open(const char *path);
+
+/**
+ * 写 keydir 快照并关闭存储，释放 `db`
+ *
+ * 还有没释放的迭代器时返回 `BITCASK_ERROR`，`db` 保持可用。
+ *
+ * # Safety
+ *
+ * `db` 必须是 `bitcask_open` 返回的、还没有关闭的句柄，并且没有其他线程正在使用它。
+ */
+int bitcask_close(struct Bitcask *db);
+
+/**
+ * 读取 `key`，找到时把 value 写到 `*value` / `*value_len`，用完后用 `bitcask_free` 释放
+ *
+ * # Safety
+ *
+ * `db` 必须是有效的句柄，`key` 指向 `key_len` 个字节，`value` 和 `value_len` 可写。
+ */
+int bitcask_get(const struct Bitcask *db,
+                const uint8_t *key,
+                size_t key_len,
+                uint8_t **value,
+                size_t *value_len);
+
+/**
+ * # Safety
+ *
+ * `db` 必须是有效的句柄，`key` 和 `value` 分别指向 `key_len` 和 `value_len` 个字节。
+ */
+int bitcask_put(const struct Bitcask *db,
+                const uint8_t *key,
+                size_t key_len,
+                const uint8_t *value,
+                size_t value_len);
+
+/**
+ * 删除 `key`，key 原来不存在时返回 `BITCASK_NOT_FOUND`
+ *
+ * # Safety
+ *
+ * `db` 必须是有效的句柄，`key` 指向 `key_len` 个字节。
+ */
+int bitcask_delete(const struct Bitcask *db, const uint8_t *key, size_t key_len);
+
+/**
+ * 遍历以 `prefix` 开头的 key（`prefix_len` 为 0 时遍历全部）
+ *
+ * 遍历的是创建时刻的只读视图：之后的写入和删除都看不到，key 和 value 来自同一时刻。
+ * 迭代器存活期间，被 merge 取代的文件要等它释放之后才删除。
+ * 迭代器必须在 `bitcask_close` 之前用 `bitcask_iter_free` 释放。
+ *
+ * # Safety
+ *
+ * `db` 必须是有效的句柄，`prefix` 指向 `prefix_len` 个字节。
+ */
+struct BitcaskIter *bitcask_iter_new(const struct Bitcask *db,
+                                     const uint8_t *prefix,
+                                     size_t prefix_len);
+
+/**
+ * 取下一个 key 和 value，两个缓冲区都要用 `bitcask_free` 释放；遍历完时返回 `BITCASK_NOT_FOUND`
+ *
+ * # Safety
+ *
+ * `iter` 必须是 `bitcask_iter_new` 返回的、还没有释放的迭代器，输出指针都可写。
+ */
+int bitcask_iter_next(struct BitcaskIter *iter,
+                      uint8_t **key,
+                      size_t *key_len,
+                      uint8_t **value,
+                      size_t *value_len);
+
+/**
+ * # Safety
+ *
+ * `iter` 必须是 `bitcask_iter_new` 返回的、还没有释放的迭代器，或者 NULL。
+ */
+void bitcask_iter_free(struct BitcaskIter *iter);
+
+/**
+ * 释放 bitcask 函数返回的缓冲区
+ *
+ * # Safety
+ *
+ * `buf` 和 `len` 必须是 bitcask 函数返回的、还没有释放的缓冲区和它的长度，或者 `buf` 为 NULL。
+ */
+void bitcask_free(uint8_t *buf,
+                  size_t len);
+
+#endif  /* BITCASK_H */
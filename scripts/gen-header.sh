@@ -0,0 +1,11 @@
+#!/bin/sh
+# 根据 src/ffi.rs 重新生成 C 头文件 include/bitcask.h，需要先 `cargo install cbindgen`
+#
+# 带 --check 时只检查头文件是不是最新的，不修改它
+set -eu
+
+cd "$(dirname "$0")/.."
+if [ "${1:-}" = "--check" ]; then
+    exec cbindgen --config cbindgen.toml --verify --output include/bitcask.h src/ffi.rs
+fi
+exec cbindgen --config cbindgen.toml --output include/bitcask.h src/ffi.rs
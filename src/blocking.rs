@@ -0,0 +1,174 @@
+//! 给同步代码用的阻塞 API
+//!
+//! [`Store`] 在一个专用线程上跑自己的 tokio runtime（后台的轮转任务也在上面），
+//! 每个方法都阻塞当前线程直到对应的异步操作完成。方法只需要 `&self`，
+//! 可以放进 `Arc` 在多个线程间共享。
+//!
+//! 不要在异步上下文里调用这些方法，和 `tokio::runtime::Handle::block_on` 一样会 panic；
+//! 异步代码直接用 [`BitCaskHandle`]。
+
+use std::{
+    io,
+    path::PathBuf,
+    thread::{self, JoinHandle},
+};
+
+use tokio::{runtime, sync::oneshot};
+
+use crate::{
+    BitCaskHandle, MergeReport, PutOptions, PutOutcome, Snapshot, StorageConfig, StoreStats,
+    ValueMeta, VerifyReport,
+};
+
+/// [`BitCaskHandle`] 的同步版本
+pub struct Store<C: StorageConfig> {
+    // 先于 runtime 释放
+    handle: BitCaskHandle<C>,
+    runtime: RuntimeThread,
+}
+
+impl<C> Store<C>
+where
+    C: StorageConfig + Default,
+{
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        Self::open_with_config(dir, C::default())
+    }
+}
+
+impl<C: StorageConfig> Store<C> {
+    pub fn open_with_config(dir: impl Into<PathBuf>, config: C) -> io::Result<Self> {
+        let runtime = RuntimeThread::start()?;
+        let handle = runtime.block_on(BitCaskHandle::open_with_config(dir, config))?;
+        Ok(Self { handle, runtime })
+    }
+
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.runtime.block_on(self.handle.get(key))
+    }
+
+    pub fn get_with_meta(&self, key: &[u8]) -> io::Result<Option<(Vec<u8>, ValueMeta)>> {
+        self.runtime.block_on(self.handle.get_with_meta(key))
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.runtime.block_on(self.handle.put(key, value))
+    }
+
+    pub fn put_with(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        options: PutOptions,
+    ) -> io::Result<PutOutcome> {
+        self.runtime
+            .block_on(self.handle.put_with(key, value, options))
+    }
+
+    /// 返回 key 原来是否存在
+    pub fn delete(&self, key: &[u8]) -> io::Result<bool> {
+        self.runtime.block_on(self.handle.delete(key))
+    }
+
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.handle.keys()
+    }
+
+    /// 以 `prefix` 开头的所有 key 和对应的 value，按 key 的字节序排列
+    pub fn scan_prefix(&self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.runtime.block_on(self.handle.scan_prefix(prefix))
+    }
+
+    pub fn len(&self) -> usize {
+        self.handle.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handle.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.handle.contains_key(key)
+    }
+
+    pub fn stats(&self) -> io::Result<StoreStats> {
+        self.runtime.block_on(self.handle.stats())
+    }
+
+    pub fn merge(&self) -> io::Result<MergeReport> {
+        self.runtime.block_on(self.handle.merge())
+    }
+
+    pub fn verify(&self) -> io::Result<VerifyReport> {
+        self.runtime.block_on(self.handle.verify())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.runtime.block_on(self.handle.sync())
+    }
+
+    pub fn rotate_now(&self) -> io::Result<()> {
+        self.runtime.block_on(self.handle.rotate_now())
+    }
+
+    /// 默认 namespace 在此刻的只读视图，读它要经过 [`Store::block_on`]
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        self.handle.snapshot()
+    }
+
+    pub(crate) fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// 写 keydir 快照后关闭，然后停掉 runtime 线程
+    pub fn close(self) -> io::Result<()> {
+        let Store { handle, runtime } = self;
+        runtime.block_on(handle.close())
+    }
+}
+
+/// 专用线程上的 current-thread runtime，drop 时停掉并等线程退出
+struct RuntimeThread {
+    handle: runtime::Handle,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RuntimeThread {
+    fn start() -> io::Result<Self> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("bitcask-runtime".to_string())
+            .spawn(move || {
+                // 驱动 IO 和定时器，直到 Store 被关闭
+                runtime.block_on(async {
+                    let _ = shutdown_rx.await;
+                });
+            })?;
+
+        Ok(Self {
+            handle,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.handle.block_on(future)
+    }
+}
+
+impl Drop for RuntimeThread {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
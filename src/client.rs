@@ -0,0 +1,187 @@
+//! 二进制协议（[`crate::server::binary`]）的异步客户端
+//!
+//! 方法和 [`BitCaskHandle`](crate::BitCaskHandle) 对应，也只需要 `&self`；
+//! 同一个 `Client` 上的请求会排队走同一个连接。
+
+use std::{io, path::Path};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
+    net::{TcpStream, ToSocketAddrs, UnixStream},
+    sync::Mutex,
+};
+
+use crate::protocol::{self, Request, Response};
+pub use crate::protocol::{BatchOp, ScanPage};
+
+// 客户端的 scan_prefix 每次取的条数
+const SCAN_PAGE_SIZE: u32 = 1000;
+
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+/// 批量请求中每个操作的结果，和 [`BatchOp`] 一一对应
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchResult {
+    Value(Option<Vec<u8>>),
+    Stored,
+    // key 原来是否存在
+    Deleted(bool),
+}
+
+pub struct Client {
+    connection: Mutex<State>,
+}
+
+struct State {
+    stream: BufStream<Box<dyn Connection>>,
+    // 请求发出后没有读完响应（调用被取消或者出了 IO 错误），之后的响应对不上，连接不能再用
+    broken: bool,
+}
+
+impl Client {
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self::new(Box::new(stream)))
+    }
+
+    pub async fn connect_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let stream = UnixStream::connect(path).await?;
+        Ok(Self::new(Box::new(stream)))
+    }
+
+    fn new(connection: Box<dyn Connection>) -> Self {
+        Self {
+            connection: Mutex::new(State {
+                stream: BufStream::new(connection),
+                broken: false,
+            }),
+        }
+    }
+
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match self.call(&Request::Get(key.to_vec())).await? {
+            Response::Value(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        match self
+            .call(&Request::Put(key.to_vec(), value.to_vec()))
+            .await?
+        {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// 返回 key 原来是否存在
+    pub async fn delete(&self, key: &[u8]) -> io::Result<bool> {
+        match self.call(&Request::Delete(key.to_vec())).await? {
+            Response::Ok => Ok(true),
+            Response::NotFound => Ok(false),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// 在一次往返里按顺序执行多个操作；服务端不保证原子性
+    pub async fn batch(&self, ops: Vec<BatchOp>) -> io::Result<Vec<BatchResult>> {
+        let request = Request::Batch(ops);
+        let responses = match self.call(&request).await? {
+            Response::Batch(responses) => responses,
+            response => return Err(unexpected(response)),
+        };
+        let Request::Batch(ops) = request else {
+            unreachable!()
+        };
+        if responses.len() != ops.len() {
+            return Err(invalid_data(format!(
+                "expected {} batch responses, got {}",
+                ops.len(),
+                responses.len()
+            )));
+        }
+
+        ops.iter()
+            .zip(responses)
+            .map(|(op, response)| match (op, response) {
+                (BatchOp::Get(_), Response::Value(value)) => Ok(BatchResult::Value(Some(value))),
+                (BatchOp::Get(_), Response::NotFound) => Ok(BatchResult::Value(None)),
+                (BatchOp::Put(..), Response::Ok) => Ok(BatchResult::Stored),
+                (BatchOp::Delete(_), Response::Ok) => Ok(BatchResult::Deleted(true)),
+                (BatchOp::Delete(_), Response::NotFound) => Ok(BatchResult::Deleted(false)),
+                (_, response) => Err(unexpected(response)),
+            })
+            .collect()
+    }
+
+    /// 取一页以 `prefix` 开头、排在 `cursor` 之后的 key 和 value
+    ///
+    /// 第一页传 `None`，之后传上一页的 `next_cursor`；`limit` 为 0 时由服务端决定页大小。
+    pub async fn scan(
+        &self,
+        prefix: &[u8],
+        cursor: Option<&[u8]>,
+        limit: u32,
+    ) -> io::Result<ScanPage> {
+        let request = Request::Scan {
+            prefix: prefix.to_vec(),
+            cursor: cursor.map(<[u8]>::to_vec),
+            limit,
+        };
+        match self.call(&request).await? {
+            Response::Scan(page) => Ok(page),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// 以 `prefix` 开头的所有 key 和对应的 value，按 key 的字节序排列
+    pub async fn scan_prefix(&self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        let mut cursor: Option<Vec<u8>> = None;
+        loop {
+            let page = self.scan(prefix, cursor.as_deref(), SCAN_PAGE_SIZE).await?;
+            pairs.extend(page.entries);
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return Ok(pairs),
+            }
+        }
+    }
+
+    async fn call(&self, request: &Request) -> io::Result<Response> {
+        let mut state = self.connection.lock().await;
+        if state.broken {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "connection is unusable after an interrupted call",
+            ));
+        }
+        // 完整读到响应之后才清掉，future 在中途被 drop 时保持为 true
+        state.broken = true;
+        let connection = &mut state.stream;
+        protocol::write_frame(&mut *connection, &request.encode()).await?;
+        connection.flush().await?;
+
+        let body = protocol::read_frame(&mut *connection)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        state.broken = false;
+        match Response::decode(&body)? {
+            Response::Error(msg) => Err(io::Error::other(msg)),
+            response => Ok(response),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> io::Error {
+    invalid_data(format!("unexpected response: {response:?}"))
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -1,13 +1,23 @@
-use crate::storage::config::StorageConfig;
+use std::time::Duration;
+
+use crate::{MergePolicy, storage::config::StorageConfig};
 #[derive(Debug, Clone)]
 pub struct BitCaskConfig {
     pub max_active_file_size: u64,
+    pub max_active_file_age: Option<Duration>,
+    pub keydir_snapshot_interval: Option<Duration>,
+    pub merge_policy: Option<MergePolicy>,
+    pub background_io_rate: Option<u64>,
 }
 
 impl Default for BitCaskConfig {
     fn default() -> Self {
         Self {
             max_active_file_size: 64 * 1024 * 1024,
+            max_active_file_age: None,
+            keydir_snapshot_interval: Some(Duration::from_secs(5 * 60)),
+            merge_policy: None,
+            background_io_rate: None,
         }
     }
 }
@@ -16,4 +26,20 @@ impl StorageConfig for BitCaskConfig {
     fn max_active_file_size(&self) -> u64 {
         self.max_active_file_size
     }
+
+    fn max_active_file_age(&self) -> Option<Duration> {
+        self.max_active_file_age
+    }
+
+    fn keydir_snapshot_interval(&self) -> Option<Duration> {
+        self.keydir_snapshot_interval
+    }
+
+    fn merge_policy(&self) -> Option<MergePolicy> {
+        self.merge_policy.clone()
+    }
+
+    fn background_io_rate(&self) -> Option<u64> {
+        self.background_io_rate
+    }
 }
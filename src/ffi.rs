@@ -0,0 +1,337 @@
+//! C 接口，底层是 [`blocking::Store`]
+//!
+//! 头文件是 `include/bitcask.h`，修改这个文件之后用 `scripts/gen-header.sh` 重新生成。约定：
+//!
+//! - 返回 `int` 的函数：`BITCASK_OK` 成功，`BITCASK_NOT_FOUND` key 不存在，
+//!   `BITCASK_ERROR` 出错，错误信息用 `bitcask_last_error` 取
+//! - 返回给调用者的 key / value 缓冲区要用 `bitcask_free` 释放
+//! - 句柄可以在多个线程间共享，迭代器只能在一个线程里用
+//! - 函数内部 panic 会直接 abort
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_int},
+    io, ptr, slice,
+    sync::Arc,
+};
+
+use crate::{BitCaskConfig, Snapshot, blocking::Store};
+
+pub const BITCASK_OK: c_int = 0;
+pub const BITCASK_NOT_FOUND: c_int = 1;
+pub const BITCASK_ERROR: c_int = -1;
+
+/// 打开的存储，由 `bitcask_open` 创建，`bitcask_close` 释放
+pub struct Bitcask {
+    // 迭代器也持有一份
+    store: Arc<Store<BitCaskConfig>>,
+}
+
+/// 按 key 的字节序遍历，由 `bitcask_iter_new` 创建，`bitcask_iter_free` 释放
+pub struct BitcaskIter {
+    // 创建迭代器时的只读视图，value 也从这里读
+    snapshot: Snapshot,
+    // 视图里的 key，倒序存放方便 pop
+    keys: Vec<Vec<u8>>,
+    store: Arc<Store<BitCaskConfig>>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(e: impl ToString) {
+    // 错误信息里不会有 NUL，真有的话换成空格
+    let msg = e.to_string().replace('\0', " ");
+    let msg = CString::new(msg).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// 当前线程上一次失败的错误信息，没有错误时返回 NULL
+///
+/// 返回的字符串在当前线程下一次调用 bitcask 函数之前有效。
+#[unsafe(no_mangle)]
+pub extern "C" fn bitcask_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(msg) => msg.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// 打开（不存在时创建）`path` 目录下的存储，失败时返回 NULL
+///
+/// # Safety
+///
+/// `path` 必须是以 NUL 结尾的字符串。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_open(path: *const c_char) -> *mut Bitcask {
+    clear_last_error();
+    if path.is_null() {
+        set_last_error("path is NULL");
+        return ptr::null_mut();
+    }
+    // SAFETY: 调用者保证 path 是以 NUL 结尾的字符串
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str() else {
+        set_last_error("path is not valid UTF-8");
+        return ptr::null_mut();
+    };
+
+    match Store::open(path) {
+        Ok(store) => Box::into_raw(Box::new(Bitcask {
+            store: Arc::new(store),
+        })),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// 写 keydir 快照并关闭存储，释放 `db`
+///
+/// 还有没释放的迭代器时返回 `BITCASK_ERROR`，`db` 保持可用。
+///
+/// # Safety
+///
+/// `db` 必须是 `bitcask_open` 返回的、还没有关闭的句柄，并且没有其他线程正在使用它。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_close(db: *mut Bitcask) -> c_int {
+    clear_last_error();
+    if db.is_null() {
+        return BITCASK_OK;
+    }
+    // SAFETY: 调用者保证 db 来自 bitcask_open 且没有被释放
+    let strong_count = Arc::strong_count(unsafe { &(*db).store });
+    if strong_count > 1 {
+        set_last_error(format!("{} iterators are still open", strong_count - 1));
+        return BITCASK_ERROR;
+    }
+
+    // SAFETY: 同上，之后调用者不再使用 db
+    let db = unsafe { Box::from_raw(db) };
+    let store = Arc::into_inner(db.store).expect("no iterators are open");
+    status(store.close())
+}
+
+/// 读取 `key`，找到时把 value 写到 `*value` / `*value_len`，用完后用 `bitcask_free` 释放
+///
+/// # Safety
+///
+/// `db` 必须是有效的句柄，`key` 指向 `key_len` 个字节，`value` 和 `value_len` 可写。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_get(
+    db: *const Bitcask,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> c_int {
+    clear_last_error();
+    if db.is_null() || value.is_null() || value_len.is_null() {
+        set_last_error("db, value or value_len is NULL");
+        return BITCASK_ERROR;
+    }
+    // SAFETY: 调用者保证指针有效
+    let (db, key) = unsafe { (&*db, bytes(key, key_len)) };
+    match db.store.get(key) {
+        Ok(Some(found)) => {
+            // SAFETY: 调用者保证输出指针可写
+            unsafe { write_buffer(found, value, value_len) };
+            BITCASK_OK
+        }
+        Ok(None) => BITCASK_NOT_FOUND,
+        Err(e) => {
+            set_last_error(e);
+            BITCASK_ERROR
+        }
+    }
+}
+
+/// # Safety
+///
+/// `db` 必须是有效的句柄，`key` 和 `value` 分别指向 `key_len` 和 `value_len` 个字节。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_put(
+    db: *const Bitcask,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    clear_last_error();
+    if db.is_null() {
+        set_last_error("db is NULL");
+        return BITCASK_ERROR;
+    }
+    // SAFETY: 调用者保证指针有效
+    let (db, key, value) = unsafe { (&*db, bytes(key, key_len), bytes(value, value_len)) };
+    status(db.store.put(key, value))
+}
+
+/// 删除 `key`，key 原来不存在时返回 `BITCASK_NOT_FOUND`
+///
+/// # Safety
+///
+/// `db` 必须是有效的句柄，`key` 指向 `key_len` 个字节。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_delete(
+    db: *const Bitcask,
+    key: *const u8,
+    key_len: usize,
+) -> c_int {
+    clear_last_error();
+    if db.is_null() {
+        set_last_error("db is NULL");
+        return BITCASK_ERROR;
+    }
+    // SAFETY: 调用者保证指针有效
+    let (db, key) = unsafe { (&*db, bytes(key, key_len)) };
+    match db.store.delete(key) {
+        Ok(true) => BITCASK_OK,
+        Ok(false) => BITCASK_NOT_FOUND,
+        Err(e) => {
+            set_last_error(e);
+            BITCASK_ERROR
+        }
+    }
+}
+
+/// 遍历以 `prefix` 开头的 key（`prefix_len` 为 0 时遍历全部）
+///
+/// 遍历的是创建时刻的只读视图：之后的写入和删除都看不到，key 和 value 来自同一时刻。
+/// 迭代器存活期间，被 merge 取代的文件要等它释放之后才删除。
+/// 迭代器必须在 `bitcask_close` 之前用 `bitcask_iter_free` 释放。
+///
+/// # Safety
+///
+/// `db` 必须是有效的句柄，`prefix` 指向 `prefix_len` 个字节。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_iter_new(
+    db: *const Bitcask,
+    prefix: *const u8,
+    prefix_len: usize,
+) -> *mut BitcaskIter {
+    clear_last_error();
+    if db.is_null() {
+        set_last_error("db is NULL");
+        return ptr::null_mut();
+    }
+    // SAFETY: 调用者保证指针有效
+    let (db, prefix) = unsafe { (&*db, bytes(prefix, prefix_len)) };
+    let snapshot = db.store.snapshot();
+    let mut keys: Vec<Vec<u8>> = snapshot
+        .keys()
+        .into_iter()
+        .filter(|key| key.starts_with(prefix))
+        .collect();
+    keys.reverse();
+    Box::into_raw(Box::new(BitcaskIter {
+        snapshot,
+        keys,
+        store: db.store.clone(),
+    }))
+}
+
+/// 取下一个 key 和 value，两个缓冲区都要用 `bitcask_free` 释放；遍历完时返回 `BITCASK_NOT_FOUND`
+///
+/// # Safety
+///
+/// `iter` 必须是 `bitcask_iter_new` 返回的、还没有释放的迭代器，输出指针都可写。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_iter_next(
+    iter: *mut BitcaskIter,
+    key: *mut *mut u8,
+    key_len: *mut usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> c_int {
+    clear_last_error();
+    if iter.is_null()
+        || key.is_null()
+        || key_len.is_null()
+        || value.is_null()
+        || value_len.is_null()
+    {
+        set_last_error("iter or an output pointer is NULL");
+        return BITCASK_ERROR;
+    }
+    // SAFETY: 调用者保证 iter 有效且只在当前线程使用
+    let iter = unsafe { &mut *iter };
+    let Some(next_key) = iter.keys.pop() else {
+        return BITCASK_NOT_FOUND;
+    };
+    match iter.store.block_on(iter.snapshot.get(&next_key)) {
+        Ok(Some(found)) => {
+            // SAFETY: 调用者保证输出指针可写
+            unsafe {
+                write_buffer(next_key, key, key_len);
+                write_buffer(found, value, value_len);
+            }
+            BITCASK_OK
+        }
+        // 视图里的 key 都是存活的，不会走到这里
+        Ok(None) => BITCASK_NOT_FOUND,
+        Err(e) => {
+            set_last_error(e);
+            BITCASK_ERROR
+        }
+    }
+}
+
+/// # Safety
+///
+/// `iter` 必须是 `bitcask_iter_new` 返回的、还没有释放的迭代器，或者 NULL。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_iter_free(iter: *mut BitcaskIter) {
+    if !iter.is_null() {
+        // SAFETY: 调用者保证 iter 来自 bitcask_iter_new 且没有被释放
+        drop(unsafe { Box::from_raw(iter) });
+    }
+}
+
+/// 释放 bitcask 函数返回的缓冲区
+///
+/// # Safety
+///
+/// `buf` 和 `len` 必须是 bitcask 函数返回的、还没有释放的缓冲区和它的长度，或者 `buf` 为 NULL。
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bitcask_free(buf: *mut u8, len: usize) {
+    if !buf.is_null() {
+        // SAFETY: 调用者保证 buf 来自 write_buffer 且长度是 len
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(buf, len)) });
+    }
+}
+
+fn status(result: io::Result<()>) -> c_int {
+    match result {
+        Ok(()) => BITCASK_OK,
+        Err(e) => {
+            set_last_error(e);
+            BITCASK_ERROR
+        }
+    }
+}
+
+/// 长度为 0 时允许 `ptr` 是 NULL
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+    // SAFETY: 调用者保证 ptr 指向 len 个字节
+    unsafe { slice::from_raw_parts(ptr, len) }
+}
+
+unsafe fn write_buffer(buf: Vec<u8>, out: *mut *mut u8, out_len: *mut usize) {
+    let buf = buf.into_boxed_slice();
+    let len = buf.len();
+    // SAFETY: 调用者保证输出指针可写
+    unsafe {
+        *out_len = len;
+        *out = Box::into_raw(buf).cast::<u8>();
+    }
+}
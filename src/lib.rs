@@ -1,6 +1,26 @@
+pub mod blocking;
+pub mod client;
 mod config;
+pub mod ffi;
+mod protocol;
+pub mod server;
 mod storage;
 mod utils;
 
 pub use config::BitCaskConfig;
-pub use storage::{bitcask_impl::BitCaskHandle, config::StorageConfig};
+pub use storage::{
+    bitcask_impl::BitCaskHandle,
+    checkpoint::CheckpointReport,
+    config::StorageConfig,
+    merge::{CancelToken, MergeReport},
+    merge_scheduler::{MergePolicy, MergeScheduler, MergeWindow},
+    namespace::{Namespace, NamespaceStats},
+    options::{PutCondition, PutOptions, PutOutcome, ValueMeta},
+    read_only::ReadOnlyStore,
+    read_view::Snapshot,
+    rebuild::RebuildProgress,
+    sharded::{ShardedBitCask, ShardedStats},
+    stats::{FileStats, StoreStats},
+    verify::{VerifyIssue, VerifyReport},
+};
+pub use utils::time::{Clock, ManualClock, SystemClock};
@@ -1,38 +1,449 @@
-use std::io;
+use std::{
+    fs::Permissions,
+    io::{self, Read, Write},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+};
 
-mod config;
-mod storage;
-mod utils;
+use bitcask::{
+    BitCaskConfig, BitCaskHandle, MergePolicy, MergeScheduler, server, server::admin::LogControl,
+};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    sync::watch,
+    task::JoinSet,
+};
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt, reload, util::SubscriberInitExt};
+
+const USAGE: &str = "\
+Usage: bitcask <data-dir> <command> [args]
+
+Commands:
+  get <key>                 print the value of <key> to stdout
+  put <key> [--file <path>] store a value read from <path> or stdin
+  delete <key>              delete <key>
+  scan [--prefix <p>]       print key/value pairs, one per line
+  keys [--prefix <p>]       print keys, one per line
+  stats                     print store statistics
+  merge                     compact sealed data files
+  verify                    check checksums and file consistency
+  repair-manifest           rebuild the manifest from the files in <data-dir>
+  restore <backup>...       assemble a new store in <data-dir> from a full
+                            backup followed by its incremental backups
+  serve [options]           serve the store over the network until Ctrl-C
+    --addr <addr>             Redis protocol address (default 127.0.0.1:6379)
+    --memcached-addr <addr>   also serve the memcached text protocol
+    --binary-addr <addr>      also serve the binary protocol over TCP
+    --binary-socket <path>    also serve the binary protocol over a Unix socket
+    --http-addr <addr>        also serve the HTTP interface
+    --admin-socket <path>     accept admin commands (merge, rotate, sync, stats,
+                              verify, log-level, io-rate, checkpoint, backup)
+                              on a Unix socket
+    --auto-merge <ratio>      merge sealed files whose dead bytes reach <ratio>
+                              (0.0 to 1.0) in the background
+    --io-rate <bytes>         limit merge, snapshot and verify I/O to <bytes>
+                              per second
+
+Exit codes: 0 ok, 1 key or data directory not found, 2 usage error, 3 I/O error,
+            4 verify found problems";
+
+const EXIT_NOT_FOUND: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_IO_ERROR: u8 = 3;
+const EXIT_CORRUPTED: u8 = 4;
+
+const DEFAULT_RESP_ADDR: &str = "127.0.0.1:6379";
+
+enum Command {
+    Get { key: Vec<u8> },
+    Put { key: Vec<u8>, file: Option<PathBuf> },
+    Delete { key: Vec<u8> },
+    Scan { prefix: Vec<u8> },
+    Keys { prefix: Vec<u8> },
+    Stats,
+    Merge,
+    Verify,
+    RepairManifest,
+    Restore { backups: Vec<PathBuf> },
+    Serve(ServeOptions),
+}
+
+struct ServeOptions {
+    resp_addr: String,
+    memcached_addr: Option<String>,
+    binary_addr: Option<String>,
+    binary_socket: Option<PathBuf>,
+    http_addr: Option<String>,
+    admin_socket: Option<PathBuf>,
+    auto_merge: Option<f64>,
+    io_rate: Option<u64>,
+}
 
-use config::BitCaskConfig;
-use storage::bitcask_impl::BitCaskHandle;
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> io::Result<()> {
-    tracing_subscriber::fmt::init();
-    // info!("🚀 Tokio 运行时已启动");
-
-    // // 示例异步任务
-    // let task1 = tokio::spawn(async {
-    //     info!("  子任务 1 开始");
-    //     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-    //     info!("  子任务 1 结束");
-    // });
-    // let task2 = tokio::spawn(async {
-    //     info!("  子任务 2 开始");
-    //     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-    //     info!("  子任务 2 结束");
-    // });
-
-    // // 主任务
-    // info!("主任务执行中...");
-    // tokio::join!(task1, task2);
-    // tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-    // info!("主任务结束");
-
-    let mut handle = BitCaskHandle::<BitCaskConfig>::open("./data").await?;
-    handle.put(b"key", b"value").await?;
-    let value = handle.get(b"key").await;
-    println!("{value:?}");
-
-    Ok(())
+async fn main() -> ExitCode {
+    // 过滤规则可以通过 admin socket 在运行时修改
+    let (filter, log_control) = reload::Layer::new(EnvFilter::from_default_env());
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(io::stderr))
+        .init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (data_dir, command) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(msg) => {
+            eprintln!("error: {msg}\n\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    match run(data_dir, command, Arc::new(log_control)).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::from(EXIT_IO_ERROR)
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<(PathBuf, Command), String> {
+    let [data_dir, command, rest @ ..] = args else {
+        return Err("missing data directory or command".to_string());
+    };
+
+    let command = match (command.as_str(), rest) {
+        ("get", [key]) => Command::Get {
+            key: key.as_bytes().to_vec(),
+        },
+        ("put", [key]) => Command::Put {
+            key: key.as_bytes().to_vec(),
+            file: None,
+        },
+        ("put", [key, flag, path]) if flag == "--file" => Command::Put {
+            key: key.as_bytes().to_vec(),
+            file: Some(PathBuf::from(path)),
+        },
+        ("delete", [key]) => Command::Delete {
+            key: key.as_bytes().to_vec(),
+        },
+        ("scan", rest) => Command::Scan {
+            prefix: parse_prefix(rest)?,
+        },
+        ("keys", rest) => Command::Keys {
+            prefix: parse_prefix(rest)?,
+        },
+        ("stats", []) => Command::Stats,
+        ("merge", []) => Command::Merge,
+        ("verify", []) => Command::Verify,
+        ("repair-manifest", []) => Command::RepairManifest,
+        ("restore", backups) if !backups.is_empty() => Command::Restore {
+            backups: backups.iter().map(PathBuf::from).collect(),
+        },
+        ("serve", rest) => Command::Serve(parse_serve_options(rest)?),
+        (command, _) => return Err(format!("invalid arguments for command `{command}`")),
+    };
+
+    Ok((PathBuf::from(data_dir), command))
+}
+
+fn parse_prefix(args: &[String]) -> Result<Vec<u8>, String> {
+    match args {
+        [] => Ok(Vec::new()),
+        [flag, prefix] if flag == "--prefix" => Ok(prefix.as_bytes().to_vec()),
+        _ => Err("expected `--prefix <prefix>`".to_string()),
+    }
+}
+
+fn parse_serve_options(args: &[String]) -> Result<ServeOptions, String> {
+    let mut options = ServeOptions {
+        resp_addr: DEFAULT_RESP_ADDR.to_string(),
+        memcached_addr: None,
+        binary_addr: None,
+        binary_socket: None,
+        http_addr: None,
+        admin_socket: None,
+        auto_merge: None,
+        io_rate: None,
+    };
+    for pair in args.chunks(2) {
+        match pair {
+            [flag, addr] if flag == "--addr" => options.resp_addr = addr.clone(),
+            [flag, addr] if flag == "--memcached-addr" => {
+                options.memcached_addr = Some(addr.clone())
+            }
+            [flag, addr] if flag == "--binary-addr" => options.binary_addr = Some(addr.clone()),
+            [flag, path] if flag == "--binary-socket" => {
+                options.binary_socket = Some(PathBuf::from(path))
+            }
+            [flag, addr] if flag == "--http-addr" => options.http_addr = Some(addr.clone()),
+            [flag, path] if flag == "--admin-socket" => {
+                options.admin_socket = Some(PathBuf::from(path))
+            }
+            [flag, ratio] if flag == "--auto-merge" => match ratio.parse::<f64>() {
+                Ok(ratio) if (0.0..=1.0).contains(&ratio) => options.auto_merge = Some(ratio),
+                _ => return Err(format!("invalid dead ratio `{ratio}`")),
+            },
+            [flag, rate] if flag == "--io-rate" => match rate.parse::<u64>() {
+                Ok(rate) => options.io_rate = Some(rate),
+                Err(_) => return Err(format!("invalid I/O rate `{rate}`")),
+            },
+            _ => return Err(format!("invalid serve option `{}`", pair[0])),
+        }
+    }
+    Ok(options)
+}
+
+async fn run(
+    data_dir: PathBuf,
+    command: Command,
+    log_control: Arc<dyn LogControl>,
+) -> io::Result<ExitCode> {
+    if let Command::Serve(options) = &command {
+        serve(data_dir, options, log_control).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Command::RepairManifest = command {
+        let data_files = BitCaskHandle::<BitCaskConfig>::repair_manifest(&data_dir).await?;
+        println!("manifest rebuilt with {data_files} data files");
+        return Ok(ExitCode::SUCCESS);
+    }
+    // 写入的命令也不创建目录，打错路径时不会悄悄建出一个空的存储
+    if !matches!(command, Command::Put { .. } | Command::Restore { .. }) && !data_dir.is_dir() {
+        eprintln!("data directory not found: {}", data_dir.display());
+        return Ok(ExitCode::from(EXIT_NOT_FOUND));
+    }
+    if let Command::Restore { backups } = &command {
+        let report = BitCaskHandle::<BitCaskConfig>::restore(backups, &data_dir).await?;
+        println!(
+            "restored {} data files from {} backups",
+            report.data_files,
+            backups.len()
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if matches!(
+        command,
+        Command::Get { .. }
+            | Command::Scan { .. }
+            | Command::Keys { .. }
+            | Command::Stats
+            | Command::Verify
+    ) {
+        return run_read_only(data_dir, command).await;
+    }
+
+    // 先读完输入再打开存储，stdin 读失败时不会留下空的 active file
+    let value = match &command {
+        Command::Put {
+            file: Some(path), ..
+        } => Some(tokio::fs::read(path).await?),
+        Command::Put { file: None, .. } => {
+            let mut buf = Vec::new();
+            io::stdin().read_to_end(&mut buf)?;
+            Some(buf)
+        }
+        _ => None,
+    };
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(data_dir).await?;
+    let mut stdout = io::stdout().lock();
+    let mut code = ExitCode::SUCCESS;
+
+    match command {
+        Command::Put { key, .. } => {
+            handle.put(&key, &value.unwrap_or_default()).await?;
+        }
+        Command::Delete { key } => {
+            if !handle.delete(&key).await? {
+                eprintln!("key not found: {}", key.escape_ascii());
+                code = ExitCode::from(EXIT_NOT_FOUND);
+            }
+        }
+        Command::Merge => {
+            writeln!(stdout, "{}", handle.merge().await?)?;
+        }
+        _ => unreachable!("handled above"),
+    }
+    stdout.flush()?;
+    drop(stdout);
+
+    handle.close().await?;
+    Ok(code)
+}
+
+/// 只读的命令：不创建 active file，也不改写清单
+async fn run_read_only(data_dir: PathBuf, command: Command) -> io::Result<ExitCode> {
+    let store = BitCaskHandle::<BitCaskConfig>::open_read_only(data_dir).await?;
+    let mut stdout = io::stdout().lock();
+    let mut code = ExitCode::SUCCESS;
+
+    match command {
+        Command::Get { key } => match store.get(&key).await? {
+            Some(value) => stdout.write_all(&value)?,
+            None => {
+                eprintln!("key not found: {}", key.escape_ascii());
+                code = ExitCode::from(EXIT_NOT_FOUND);
+            }
+        },
+        Command::Scan { prefix } => {
+            for (key, value) in store.scan_prefix(&prefix).await? {
+                writeln!(stdout, "{}\t{}", key.escape_ascii(), value.escape_ascii())?;
+            }
+        }
+        Command::Keys { prefix } => {
+            for key in store.keys().iter().filter(|key| key.starts_with(&prefix)) {
+                writeln!(stdout, "{}", key.escape_ascii())?;
+            }
+        }
+        Command::Stats => writeln!(stdout, "{}", store.stats().await?)?,
+        Command::Verify => {
+            let report = store.verify().await?;
+            writeln!(stdout, "{report}")?;
+            if !report.is_ok() {
+                code = ExitCode::from(EXIT_CORRUPTED);
+            }
+        }
+        _ => unreachable!("not a read-only command"),
+    }
+    stdout.flush()?;
+    Ok(code)
+}
+
+/// 一直服务到 Ctrl-C，然后关闭存储
+async fn serve(
+    data_dir: PathBuf,
+    options: &ServeOptions,
+    log_control: Arc<dyn LogControl>,
+) -> io::Result<()> {
+    let config = BitCaskConfig {
+        merge_policy: options.auto_merge.map(|min_dead_ratio| MergePolicy {
+            min_dead_ratio,
+            ..MergePolicy::default()
+        }),
+        background_io_rate: options.io_rate,
+        ..BitCaskConfig::default()
+    };
+    let handle = Arc::new(BitCaskHandle::open_with_config(data_dir, config).await?);
+    let merge_scheduler = MergeScheduler::spawn(&handle);
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let shutdown = |mut rx: watch::Receiver<()>| async move {
+        let _ = rx.changed().await;
+    };
+
+    // 先绑定所有地址，任何一个失败都直接退出
+    let mut servers = JoinSet::new();
+    let listener = TcpListener::bind(&options.resp_addr).await?;
+    servers.spawn(server::resp::serve(
+        listener,
+        handle.clone(),
+        shutdown(shutdown_rx.clone()),
+    ));
+    if let Some(addr) = &options.memcached_addr {
+        let listener = TcpListener::bind(addr).await?;
+        servers.spawn(server::memcached::serve(
+            listener,
+            handle.clone(),
+            shutdown(shutdown_rx.clone()),
+        ));
+    }
+    if let Some(addr) = &options.binary_addr {
+        let listener = TcpListener::bind(addr).await?;
+        servers.spawn(server::binary::serve(
+            listener,
+            handle.clone(),
+            shutdown(shutdown_rx.clone()),
+        ));
+    }
+    if let Some(addr) = &options.http_addr {
+        let listener = TcpListener::bind(addr).await?;
+        servers.spawn(server::http::serve(
+            listener,
+            handle.clone(),
+            shutdown(shutdown_rx.clone()),
+        ));
+    }
+    if let Some(path) = &options.binary_socket {
+        // 上次没有正常退出时 socket 文件还在，bind 会失败
+        remove_socket_file(path)?;
+        let listener = UnixListener::bind(path)?;
+        servers.spawn(server::binary::serve_unix(
+            listener,
+            handle.clone(),
+            shutdown(shutdown_rx.clone()),
+        ));
+    }
+
+    if let Some(path) = &options.admin_socket {
+        remove_socket_file(path)?;
+        let listener = UnixListener::bind(path)?;
+        // 通过 admin socket 可以在服务器上任意写文件，只允许同一个用户连接
+        std::fs::set_permissions(path, Permissions::from_mode(0o600))?;
+        servers.spawn(server::admin::serve(
+            listener,
+            handle.clone(),
+            Some(log_control),
+            shutdown(shutdown_rx.clone()),
+        ));
+    }
+
+    // 某个服务出错退出时也要停掉其它服务，走下面的正常关闭流程
+    let mut result = tokio::select! {
+        signal = tokio::signal::ctrl_c() => {
+            if let Err(e) = signal {
+                eprintln!("error: failed to listen for Ctrl-C: {e}");
+            }
+            Ok(())
+        }
+        Some(joined) = servers.join_next() => joined.map_err(io::Error::other).and_then(|r| r),
+    };
+    let _ = shutdown_tx.send(());
+    // 服务返回时它的连接任务都已经结束，持有的 handle 也已经释放
+    while let Some(joined) = servers.join_next().await {
+        let joined = joined.map_err(io::Error::other).and_then(|r| r);
+        result = result.and(joined);
+    }
+    for path in [&options.binary_socket, &options.admin_socket]
+        .into_iter()
+        .flatten()
+    {
+        result = result.and(remove_socket_file(path));
+    }
+
+    if let Some(scheduler) = merge_scheduler {
+        scheduler.stop().await;
+    }
+    let closed = match Arc::try_unwrap(handle) {
+        Ok(handle) => handle.close().await,
+        Err(handle) => {
+            // 正常情况下走不到这里；至少把已经写入的数据刷到磁盘
+            tracing::warn!(
+                "Store is still shared by {} references at shutdown, syncing without closing",
+                Arc::strong_count(&handle) - 1
+            );
+            handle.sync().await
+        }
+    };
+    result.and(closed)
+}
+
+/// 删除上次留下的 socket 文件；路径上是别的文件时报错，不删除
+fn remove_socket_file(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
 }
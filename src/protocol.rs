@@ -0,0 +1,340 @@
+//! 二进制协议的编解码，服务端（[`crate::server::binary`]）和客户端（[`crate::client`]）共用
+//!
+//! 每个帧是 `[len u32][body]`，所有整数都是小端。请求的 body 是 `[opcode u8][参数]`，
+//! 响应的 body 是 `[status u8][内容]`；字节串编码为 `[len u32][bytes]`。
+//! 一个连接上的请求按顺序处理，响应的顺序和请求一致。
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// 帧长度的上限，超过的帧直接断开连接
+pub(crate) const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+
+const OP_GET: u8 = 1;
+const OP_PUT: u8 = 2;
+const OP_DELETE: u8 = 3;
+const OP_BATCH: u8 = 4;
+const OP_SCAN: u8 = 5;
+
+const STATUS_OK: u8 = 0;
+const STATUS_VALUE: u8 = 1;
+const STATUS_NOT_FOUND: u8 = 2;
+const STATUS_BATCH: u8 = 3;
+const STATUS_SCAN: u8 = 4;
+const STATUS_ERROR: u8 = 5;
+
+/// 批量请求中的一个操作
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Get(Vec<u8>),
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// 一页扫描结果，`next_cursor` 为 `None` 表示已经扫描完
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanPage {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    pub next_cursor: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Request {
+    Get(Vec<u8>),
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Batch(Vec<BatchOp>),
+    // cursor 是上一页最后一个 key（不包含），`None` 表示从头开始
+    Scan {
+        prefix: Vec<u8>,
+        cursor: Option<Vec<u8>>,
+        limit: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Response {
+    Ok,
+    Value(Vec<u8>),
+    NotFound,
+    Batch(Vec<Response>),
+    Scan(ScanPage),
+    Error(String),
+}
+
+impl Request {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Request::Get(key) => encode_op(&mut buf, OP_GET, key, None),
+            Request::Put(key, value) => encode_op(&mut buf, OP_PUT, key, Some(value)),
+            Request::Delete(key) => encode_op(&mut buf, OP_DELETE, key, None),
+            Request::Batch(ops) => {
+                buf.push(OP_BATCH);
+                buf.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+                for op in ops {
+                    match op {
+                        BatchOp::Get(key) => encode_op(&mut buf, OP_GET, key, None),
+                        BatchOp::Put(key, value) => encode_op(&mut buf, OP_PUT, key, Some(value)),
+                        BatchOp::Delete(key) => encode_op(&mut buf, OP_DELETE, key, None),
+                    }
+                }
+            }
+            Request::Scan {
+                prefix,
+                cursor,
+                limit,
+            } => {
+                buf.push(OP_SCAN);
+                put_bytes(&mut buf, prefix);
+                match cursor {
+                    Some(cursor) => {
+                        buf.push(1);
+                        put_bytes(&mut buf, cursor);
+                    }
+                    None => buf.push(0),
+                }
+                buf.extend_from_slice(&limit.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    pub(crate) fn decode(body: &[u8]) -> io::Result<Self> {
+        let mut cursor = Cursor { buf: body, pos: 0 };
+        let request = match cursor.read_u8()? {
+            OP_GET => Request::Get(cursor.read_bytes()?),
+            OP_PUT => Request::Put(cursor.read_bytes()?, cursor.read_bytes()?),
+            OP_DELETE => Request::Delete(cursor.read_bytes()?),
+            OP_BATCH => {
+                let count = cursor.read_u32()?;
+                let mut ops = Vec::new();
+                for _ in 0..count {
+                    let op = match cursor.read_u8()? {
+                        OP_GET => BatchOp::Get(cursor.read_bytes()?),
+                        OP_PUT => BatchOp::Put(cursor.read_bytes()?, cursor.read_bytes()?),
+                        OP_DELETE => BatchOp::Delete(cursor.read_bytes()?),
+                        op => return Err(invalid_data(format!("invalid batch opcode: {op}"))),
+                    };
+                    ops.push(op);
+                }
+                Request::Batch(ops)
+            }
+            OP_SCAN => Request::Scan {
+                prefix: cursor.read_bytes()?,
+                cursor: match cursor.read_u8()? {
+                    0 => None,
+                    _ => Some(cursor.read_bytes()?),
+                },
+                limit: cursor.read_u32()?,
+            },
+            op => return Err(invalid_data(format!("unknown opcode: {op}"))),
+        };
+        cursor.finish()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    /// 编码后 body 的长度，不用真的编码
+    pub(crate) fn encoded_len(&self) -> usize {
+        1 + match self {
+            Response::Ok | Response::NotFound => 0,
+            Response::Value(value) => 4 + value.len(),
+            Response::Batch(responses) => {
+                4 + responses.iter().map(Response::encoded_len).sum::<usize>()
+            }
+            Response::Scan(page) => {
+                1 + page.next_cursor.as_ref().map_or(0, |next| 4 + next.len())
+                    + 4
+                    + page
+                        .entries
+                        .iter()
+                        .map(|(key, value)| scan_entry_len(key, value))
+                        .sum::<usize>()
+            }
+            Response::Error(msg) => 4 + msg.len(),
+        }
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            Response::Ok => buf.push(STATUS_OK),
+            Response::Value(value) => {
+                buf.push(STATUS_VALUE);
+                put_bytes(buf, value);
+            }
+            Response::NotFound => buf.push(STATUS_NOT_FOUND),
+            Response::Batch(responses) => {
+                buf.push(STATUS_BATCH);
+                buf.extend_from_slice(&(responses.len() as u32).to_le_bytes());
+                for response in responses {
+                    response.encode_into(buf);
+                }
+            }
+            Response::Scan(page) => {
+                buf.push(STATUS_SCAN);
+                match &page.next_cursor {
+                    Some(next) => {
+                        buf.push(1);
+                        put_bytes(buf, next);
+                    }
+                    None => buf.push(0),
+                }
+                buf.extend_from_slice(&(page.entries.len() as u32).to_le_bytes());
+                for (key, value) in &page.entries {
+                    put_bytes(buf, key);
+                    put_bytes(buf, value);
+                }
+            }
+            Response::Error(msg) => {
+                buf.push(STATUS_ERROR);
+                put_bytes(buf, msg.as_bytes());
+            }
+        }
+    }
+
+    pub(crate) fn decode(body: &[u8]) -> io::Result<Self> {
+        let mut cursor = Cursor { buf: body, pos: 0 };
+        let response = Self::decode_from(&mut cursor, true)?;
+        cursor.finish()?;
+        Ok(response)
+    }
+
+    fn decode_from(cursor: &mut Cursor<'_>, allow_batch: bool) -> io::Result<Self> {
+        let response = match cursor.read_u8()? {
+            STATUS_OK => Response::Ok,
+            STATUS_VALUE => Response::Value(cursor.read_bytes()?),
+            STATUS_NOT_FOUND => Response::NotFound,
+            STATUS_BATCH if allow_batch => {
+                let count = cursor.read_u32()?;
+                let mut responses = Vec::new();
+                for _ in 0..count {
+                    responses.push(Self::decode_from(cursor, false)?);
+                }
+                Response::Batch(responses)
+            }
+            STATUS_SCAN => {
+                let next_cursor = match cursor.read_u8()? {
+                    0 => None,
+                    _ => Some(cursor.read_bytes()?),
+                };
+                let count = cursor.read_u32()?;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    entries.push((cursor.read_bytes()?, cursor.read_bytes()?));
+                }
+                Response::Scan(ScanPage {
+                    entries,
+                    next_cursor,
+                })
+            }
+            STATUS_ERROR => {
+                Response::Error(String::from_utf8_lossy(&cursor.read_bytes()?).into_owned())
+            }
+            status => return Err(invalid_data(format!("unknown response status: {status}"))),
+        };
+        Ok(response)
+    }
+}
+
+/// 读一个帧的 body；连接在帧之间关闭时返回 `None`
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data(format!("frame too large: {len} bytes")));
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(body))
+}
+
+pub(crate) async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    body: &[u8],
+) -> io::Result<()> {
+    if body.len() > MAX_FRAME_LEN {
+        return Err(invalid_data(format!(
+            "frame too large: {} bytes",
+            body.len()
+        )));
+    }
+    writer.write_all(&(body.len() as u32).to_le_bytes()).await?;
+    writer.write_all(body).await
+}
+
+/// 扫描结果中一条 entry 编码后的长度
+pub(crate) fn scan_entry_len(key: &[u8], value: &[u8]) -> usize {
+    8 + key.len() + value.len()
+}
+
+fn encode_op(buf: &mut Vec<u8>, op: u8, key: &[u8], value: Option<&Vec<u8>>) {
+    buf.push(op);
+    put_bytes(buf, key);
+    if let Some(value) = value {
+        put_bytes(buf, value);
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or_else(|| invalid_data("frame is truncated"))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if self.pos != self.buf.len() {
+            return Err(invalid_data("trailing bytes in frame"));
+        }
+        Ok(())
+    }
+}
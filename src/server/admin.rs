@@ -0,0 +1,220 @@
+//! 运维用的控制通道：Unix socket 上的行协议
+//!
+//! 每行一个命令，回复若干行输出，最后一行是 `OK` 或者 `ERR <原因>`：
+//!
+//! - `merge`、`rotate`、`sync`、`stats`、`verify`
+//! - `log-level`：查看当前的日志过滤规则；`log-level <directives>`：修改，语法和 `RUST_LOG` 一样
+//! - `io-rate`：查看 merge 等后台任务的读写限速；`io-rate <bytes/s>` 或 `io-rate off`：修改
+//! - `checkpoint <dir>`：把当前的数据备份到服务器上的 `<dir>`
+//! - `backup <manifest> <dir>`：以上一次备份的清单为基准，把增量备份到 `<dir>`
+//! - `help`、`quit`
+
+use std::{fmt::Write as _, future::Future, io, sync::Arc};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{UnixListener, UnixStream},
+};
+use tracing::info;
+use tracing_subscriber::{EnvFilter, reload};
+
+use crate::{BitCaskHandle, StorageConfig};
+
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const HELP: &str = "\
+merge                 compact sealed data files
+rotate                seal the active file and start a new one
+sync                  flush and fsync the active file
+stats                 print store statistics
+verify                check checksums and file consistency
+log-level [filter]    show or replace the tracing filter
+io-rate [bytes|off]   show or set the background I/O limit in bytes per second
+checkpoint <dir>      write a consistent copy of the store into an empty <dir>
+backup <manifest> <dir>
+                      write the files sealed since the backup described by
+                      <manifest> into an empty <dir>
+quit                  close this connection";
+
+/// 运行时查看和修改日志过滤规则
+pub trait LogControl: Send + Sync + 'static {
+    fn current(&self) -> String;
+
+    fn set(&self, directives: &str) -> Result<(), String>;
+}
+
+impl<S: 'static> LogControl for reload::Handle<EnvFilter, S> {
+    fn current(&self) -> String {
+        self.with_current(|filter| filter.to_string())
+            .unwrap_or_else(|e| format!("unavailable: {e}"))
+    }
+
+    fn set(&self, directives: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+        self.reload(filter).map_err(|e| e.to_string())
+    }
+}
+
+/// 在 `listener` 上接受控制命令，直到 `shutdown` 完成
+///
+/// 没有传 `log_control` 时 `log-level` 命令返回错误。socket 文件由调用者负责创建和清理，
+/// 权限也由调用者控制。
+pub async fn serve<C: StorageConfig>(
+    listener: UnixListener,
+    handle: Arc<BitCaskHandle<C>>,
+    log_control: Option<Arc<dyn LogControl>>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    super::accept_loop(listener, handle, shutdown, move |stream, handle| {
+        handle_connection(stream, handle, log_control.clone())
+    })
+    .await
+}
+
+async fn handle_connection<C: StorageConfig>(
+    stream: UnixStream,
+    handle: Arc<BitCaskHandle<C>>,
+    log_control: Option<Arc<dyn LogControl>>,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    while let Some(line) = read_line(&mut reader).await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+        if command == "quit" {
+            break;
+        }
+
+        info!("Admin command: {line}");
+        let reply = match execute(&handle, log_control.as_deref(), command, arg).await {
+            Ok(output) => format!("{output}OK\n"),
+            Err(e) => format!("ERR {}\n", e.to_string().replace('\n', " ")),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+        writer.flush().await?;
+    }
+    writer.flush().await
+}
+
+/// 返回 `OK` 之前的输出，每行以换行结尾
+async fn execute<C: StorageConfig>(
+    handle: &BitCaskHandle<C>,
+    log_control: Option<&dyn LogControl>,
+    command: &str,
+    arg: &str,
+) -> io::Result<String> {
+    let mut output = String::new();
+    match (command, arg) {
+        ("merge", "") => {
+            let _ = writeln!(output, "{}", handle.merge().await?);
+        }
+        ("rotate", "") => {
+            handle.rotate_now().await?;
+            let _ = writeln!(output, "active file id: {}", handle.active_file_id().await);
+        }
+        ("sync", "") => handle.sync().await?,
+        ("stats", "") => {
+            let _ = writeln!(output, "{}", handle.stats().await?);
+        }
+        ("verify", "") => {
+            let _ = writeln!(output, "{}", handle.verify().await?);
+        }
+        ("log-level", directives) => {
+            let log_control =
+                log_control.ok_or_else(|| io::Error::other("log level control is not enabled"))?;
+            if !directives.is_empty() {
+                log_control
+                    .set(directives)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            }
+            let _ = writeln!(output, "{}", log_control.current());
+        }
+        ("io-rate", rate) => {
+            if !rate.is_empty() {
+                let rate = match rate {
+                    "off" => None,
+                    rate => Some(rate.parse::<u64>().map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid I/O rate `{rate}`"),
+                        )
+                    })?),
+                };
+                handle.set_background_io_rate(rate);
+            }
+            match handle.background_io_rate() {
+                Some(rate) => {
+                    let _ = writeln!(output, "{rate} bytes/s");
+                }
+                None => output.push_str("off\n"),
+            }
+        }
+        ("checkpoint", dest) if !dest.is_empty() => {
+            let report = handle.checkpoint(dest).await?;
+            let _ = writeln!(
+                output,
+                "checkpoint of {} data files and {} hint files written to {dest} ({} copied, {} bytes)",
+                report.data_files, report.hint_files, report.copied_files, report.bytes
+            );
+        }
+        ("checkpoint", _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "`checkpoint` needs a destination directory",
+            ));
+        }
+        ("backup", args) => {
+            let Some((manifest, dest)) = args.split_once(char::is_whitespace) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "`backup` needs a previous manifest and a destination directory",
+                ));
+            };
+            let dest = dest.trim();
+            let report = handle.incremental_backup(manifest, dest).await?;
+            let _ = writeln!(
+                output,
+                "backup of {} new data files written to {dest}, {} files removed since {manifest}",
+                report.data_files, report.removed_files
+            );
+        }
+        ("help", "") => {
+            output.push_str(HELP);
+            output.push('\n');
+        }
+        ("merge" | "rotate" | "sync" | "stats" | "verify" | "help", _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{command}` takes no arguments"),
+            ));
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown command `{command}`, try `help`"),
+            ));
+        }
+    }
+    Ok(output)
+}
+
+/// 读一行并去掉结尾的换行；连接关闭时返回 `None`
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 || line.pop() != Some(b'\n') {
+        // 连接关闭，或者行太长
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
@@ -0,0 +1,177 @@
+//! 长度前缀的二进制协议（见 [`crate::protocol`]），可以跑在 TCP 或 Unix domain socket 上
+//!
+//! 对应的客户端是 [`crate::client::Client`]。
+
+use std::{future::Future, io, sync::Arc};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, UnixListener},
+};
+
+use crate::{
+    BitCaskHandle, StorageConfig,
+    protocol::{self, BatchOp, Request, Response, ScanPage},
+};
+
+// 一页扫描最多返回的条数，请求的 limit 为 0 时用默认值
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 10_000;
+
+/// 在 TCP `listener` 上提供二进制协议服务，直到 `shutdown` 完成
+pub async fn serve<C: StorageConfig>(
+    listener: TcpListener,
+    handle: Arc<BitCaskHandle<C>>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    super::accept_loop(listener, handle, shutdown, handle_connection).await
+}
+
+/// 在 Unix domain socket 上提供二进制协议服务，直到 `shutdown` 完成
+///
+/// socket 文件由调用者负责创建和清理。
+pub async fn serve_unix<C: StorageConfig>(
+    listener: UnixListener,
+    handle: Arc<BitCaskHandle<C>>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    super::accept_loop(listener, handle, shutdown, handle_connection).await
+}
+
+async fn handle_connection<C, S>(stream: S, handle: Arc<BitCaskHandle<C>>) -> io::Result<()>
+where
+    C: StorageConfig,
+    S: AsyncRead + AsyncWrite + Send,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    while let Some(body) = protocol::read_frame(&mut reader).await? {
+        // 帧的边界不受 body 内容影响，解析失败只回复错误，连接继续可用
+        let response = match Request::decode(&body) {
+            Ok(request) => execute(&handle, request).await,
+            Err(e) => Response::Error(e.to_string()),
+        };
+        // 超过帧长度上限的响应对端会拒收，换成错误回复，连接继续可用
+        let response = match response.encoded_len() {
+            len if len > protocol::MAX_FRAME_LEN => {
+                Response::Error(format!("response too large: {len} bytes"))
+            }
+            _ => response,
+        };
+        protocol::write_frame(&mut writer, &response.encode()).await?;
+
+        // 客户端 pipeline 发来的请求处理完再一起 flush
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await
+}
+
+async fn execute<C: StorageConfig>(handle: &BitCaskHandle<C>, request: Request) -> Response {
+    let result = match request {
+        Request::Get(key) => get(handle, &key).await,
+        Request::Put(key, value) => handle.put(&key, &value).await.map(|_| Response::Ok),
+        Request::Delete(key) => delete(handle, &key).await,
+        Request::Batch(ops) => batch(handle, ops).await,
+        Request::Scan {
+            prefix,
+            cursor,
+            limit,
+        } => scan(handle, &prefix, cursor.as_deref(), limit as usize).await,
+    };
+    result.unwrap_or_else(|e| Response::Error(e.to_string()))
+}
+
+async fn get<C: StorageConfig>(handle: &BitCaskHandle<C>, key: &[u8]) -> io::Result<Response> {
+    Ok(match handle.get(key).await? {
+        Some(value) => Response::Value(value),
+        None => Response::NotFound,
+    })
+}
+
+async fn delete<C: StorageConfig>(handle: &BitCaskHandle<C>, key: &[u8]) -> io::Result<Response> {
+    Ok(match handle.delete(key).await? {
+        true => Response::Ok,
+        false => Response::NotFound,
+    })
+}
+
+/// 按顺序执行，不是原子的：某个操作失败时前面的操作已经生效，后面的不再执行
+///
+/// 响应累计超过帧长度上限时也按失败处理，剩下的操作不再执行。
+async fn batch<C: StorageConfig>(
+    handle: &BitCaskHandle<C>,
+    ops: Vec<BatchOp>,
+) -> io::Result<Response> {
+    let op_count = ops.len();
+    let mut responses = Vec::with_capacity(op_count);
+    // status 和条数
+    let mut response_len = 5;
+    for (i, op) in ops.into_iter().enumerate() {
+        let response = match op {
+            BatchOp::Get(key) => get(handle, &key).await?,
+            BatchOp::Put(key, value) => {
+                handle.put(&key, &value).await?;
+                Response::Ok
+            }
+            BatchOp::Delete(key) => delete(handle, &key).await?,
+        };
+        response_len += response.encoded_len();
+        if response_len > protocol::MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "batch response exceeds {} bytes at op {i} of {op_count}",
+                    protocol::MAX_FRAME_LEN
+                ),
+            ));
+        }
+        responses.push(response);
+    }
+    Ok(Response::Batch(responses))
+}
+
+/// 按 key 的字节序返回 `cursor` 之后以 `prefix` 开头的 key
+///
+/// cursor 是上一页的最后一个 key，第一页为 `None`；迭代期间的写入不会导致跳过或重复已有的 key。
+/// 一页除了不超过 `limit` 条，编码后也不超过帧长度上限，放不下的留到下一页。
+async fn scan<C: StorageConfig>(
+    handle: &BitCaskHandle<C>,
+    prefix: &[u8],
+    cursor: Option<&[u8]>,
+    limit: usize,
+) -> io::Result<Response> {
+    let limit = match limit {
+        0 => DEFAULT_SCAN_LIMIT,
+        limit => limit.min(MAX_SCAN_LIMIT),
+    };
+    // 多取一个，用来判断后面还有没有
+    let mut keys = handle.keys_after(prefix, cursor, limit + 1);
+    let has_more = keys.len() > limit;
+    keys.truncate(limit);
+
+    let mut page = ScanPage::default();
+    let mut page_len = Response::Scan(ScanPage::default()).encoded_len();
+    let mut truncated = false;
+    for key in keys {
+        // 取 key 列表之后被删除的 key 直接跳过
+        if let Some(value) = handle.get(&key).await? {
+            // 除了这条 entry，还要给 next_cursor 留出位置
+            let len = protocol::scan_entry_len(&key, &value) + 4 + key.len();
+            if !page.entries.is_empty() && page_len + len > protocol::MAX_FRAME_LEN {
+                truncated = true;
+                break;
+            }
+            page_len += protocol::scan_entry_len(&key, &value);
+            page.entries.push((key.clone(), value));
+        }
+        page.next_cursor = Some(key);
+    }
+    if !has_more && !truncated {
+        page.next_cursor = None;
+    }
+    Ok(Response::Scan(page))
+}
@@ -0,0 +1,404 @@
+//! 给 curl 和脚本用的最小 HTTP/1.1 接口
+//!
+//! - `GET/PUT/DELETE /kv/{key}`：key 按百分号编码，可以是任意字节（包括 `/`）
+//! - `GET /keys?prefix={prefix}`：每行一个百分号编码的 key
+//! - `GET /stats`：JSON 格式的 [`StoreStats`](crate::StoreStats)
+//!
+//! 请求体只支持 `Content-Length`，支持 keep-alive 和 `Expect: 100-continue`。
+//!
+//! 请求体和响应体都整个放在内存里：存储按整条记录写入（校验和覆盖整个 value），
+//! 不能边收边写。请求体的上限就是存储对 value 的上限，其他协议能写入的 value
+//! 都能通过 HTTP 读出来再写回去。
+
+use std::{fmt::Write as _, future::Future, io, sync::Arc};
+
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+        BufWriter,
+    },
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{BitCaskHandle, StorageConfig, storage::MAX_VALUE_LEN};
+
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+// 除了 unreserved 字符都编码，编码后的 key 可以直接放进 URL
+const UNRESERVED: &[u8] = b"-._~";
+
+/// 在 `listener` 上提供 HTTP 服务，直到 `shutdown` 完成
+pub async fn serve<C: StorageConfig>(
+    listener: TcpListener,
+    handle: Arc<BitCaskHandle<C>>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    super::accept_loop(listener, handle, shutdown, handle_connection).await
+}
+
+struct Request {
+    method: String,
+    target: String,
+    content_length: Option<usize>,
+    expect_continue: bool,
+    keep_alive: bool,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    // 405 时列出允许的方法
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+            allow: None,
+        }
+    }
+
+    fn empty(status: u16) -> Self {
+        Self::new(status, "text/plain", Vec::new())
+    }
+
+    fn text(status: u16, msg: impl Into<String>) -> Self {
+        let mut body = msg.into().into_bytes();
+        body.push(b'\n');
+        Self::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Self {
+            allow: Some(allow),
+            ..Self::text(405, "method not allowed")
+        }
+    }
+
+    async fn write_to<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        keep_alive: bool,
+        head_only: bool,
+    ) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        // 204 不能带 Content-Length
+        if self.status != 204 {
+            let _ = write!(
+                head,
+                "Content-Type: {}\r\nContent-Length: {}\r\n",
+                self.content_type,
+                self.body.len()
+            );
+        }
+        if let Some(allow) = self.allow {
+            let _ = write!(head, "Allow: {allow}\r\n");
+        }
+        if !keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes()).await?;
+        if !head_only {
+            writer.write_all(&self.body).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn handle_connection<C: StorageConfig>(
+    stream: TcpStream,
+    handle: Arc<BitCaskHandle<C>>,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    loop {
+        let request = match read_request(&mut reader).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // 请求头解析失败后找不到下一个请求的边界，回复错误并断开
+                Response::text(400, e.to_string())
+                    .write_to(&mut writer, false, false)
+                    .await?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let body = match read_body(&mut reader, &mut writer, &request).await? {
+            Ok(body) => body,
+            Err(response) => {
+                response.write_to(&mut writer, false, false).await?;
+                break;
+            }
+        };
+
+        let response = match route(&handle, &request, body).await {
+            Ok(response) => response,
+            Err(e) => Response::text(500, e.to_string()),
+        };
+        response
+            .write_to(&mut writer, request.keep_alive, request.method == "HEAD")
+            .await?;
+        if !request.keep_alive {
+            break;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await
+}
+
+async fn route<C: StorageConfig>(
+    handle: &BitCaskHandle<C>,
+    request: &Request,
+    body: Vec<u8>,
+) -> io::Result<Response> {
+    let (path, query) = request
+        .target
+        .split_once('?')
+        .unwrap_or((&request.target, ""));
+    let method = request.method.as_str();
+
+    if let Some(encoded_key) = path.strip_prefix("/kv/") {
+        let Some(key) = percent_decode(encoded_key) else {
+            return Ok(Response::text(400, "invalid percent-encoding in key"));
+        };
+        return match method {
+            "GET" | "HEAD" => Ok(match handle.get(&key).await? {
+                Some(value) => Response::new(200, "application/octet-stream", value),
+                None => Response::text(404, "key not found"),
+            }),
+            "PUT" => {
+                handle.put(&key, &body).await?;
+                Ok(Response::empty(204))
+            }
+            "DELETE" => Ok(match handle.delete(&key).await? {
+                true => Response::empty(204),
+                false => Response::text(404, "key not found"),
+            }),
+            _ => Ok(Response::method_not_allowed("GET, HEAD, PUT, DELETE")),
+        };
+    }
+
+    match (path, method) {
+        ("/keys", "GET" | "HEAD") => {
+            let mut prefix = Vec::new();
+            for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+                if name == "prefix" {
+                    let Some(value) = percent_decode(value) else {
+                        return Ok(Response::text(400, "invalid percent-encoding in prefix"));
+                    };
+                    prefix = value;
+                }
+            }
+            let mut body = String::new();
+            for key in handle.keys().iter().filter(|key| key.starts_with(&prefix)) {
+                body.push_str(&percent_encode(key));
+                body.push('\n');
+            }
+            Ok(Response::new(
+                200,
+                "text/plain; charset=utf-8",
+                body.into_bytes(),
+            ))
+        }
+        ("/stats", "GET" | "HEAD") => {
+            let stats = handle.stats().await?;
+            let body = format!(
+                "{{\"key_count\":{},\"data_files\":{},\"hint_files\":{},\"data_bytes\":{},\"active_file_id\":{},\"live_bytes\":{},\"dead_bytes\":{}}}\n",
+                stats.key_count,
+                stats.data_files,
+                stats.hint_files,
+                stats.data_bytes,
+                stats.active_file_id,
+                stats.live_bytes,
+                stats.dead_bytes
+            );
+            Ok(Response::new(200, "application/json", body.into_bytes()))
+        }
+        ("/keys" | "/stats", _) => Ok(Response::method_not_allowed("GET, HEAD")),
+        _ => Ok(Response::text(404, "not found")),
+    }
+}
+
+/// 读请求行和请求头；连接在请求之间关闭时返回 `None`
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Request>> {
+    // 容忍请求之间多余的空行
+    let request_line = loop {
+        match read_line(reader).await? {
+            None => return Ok(None),
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid_data("malformed request line"));
+    };
+    let keep_alive_by_default = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(invalid_data(format!("unsupported HTTP version: {version}"))),
+    };
+
+    let mut request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        content_length: None,
+        expect_continue: false,
+        keep_alive: keep_alive_by_default,
+    };
+    for _ in 0..=MAX_HEADERS {
+        let line = read_line(reader)
+            .await?
+            .ok_or_else(|| invalid_data("connection closed in request headers"))?;
+        if line.is_empty() {
+            return Ok(Some(request));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_data("malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                let len = value
+                    .parse()
+                    .map_err(|_| invalid_data("invalid Content-Length"))?;
+                request.content_length = Some(len);
+            }
+            "transfer-encoding" => {
+                return Err(invalid_data("Transfer-Encoding is not supported"));
+            }
+            "expect" => request.expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            "connection" => {
+                if value.eq_ignore_ascii_case("close") {
+                    request.keep_alive = false;
+                } else if value.eq_ignore_ascii_case("keep-alive") {
+                    request.keep_alive = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(invalid_data("too many headers"))
+}
+
+/// 按 `Content-Length` 读请求体；请求体不可接受时返回要回复的错误
+async fn read_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    request: &Request,
+) -> io::Result<Result<Vec<u8>, Response>>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let len = match request.content_length {
+        Some(len) => len,
+        None if request.method == "PUT" => {
+            return Ok(Err(Response::text(411, "Content-Length required")));
+        }
+        None => 0,
+    };
+    if len > MAX_VALUE_LEN {
+        return Ok(Err(Response::text(413, "value too large")));
+    }
+    if len == 0 {
+        return Ok(Ok(Vec::new()));
+    }
+
+    if request.expect_continue {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        writer.flush().await?;
+    }
+    // 随读到的数据增长，不按客户端声明的长度一次分配
+    let mut body = Vec::new();
+    reader.take(len as u64).read_to_end(&mut body).await?;
+    if body.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Ok(body))
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid_data("line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid_data("request line or header is not valid UTF-8"))
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || UNRESERVED.contains(&b) {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{b:02X}");
+        }
+    }
+    encoded
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
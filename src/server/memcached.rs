@@ -0,0 +1,298 @@
+//! memcached 文本协议：get、gets、set、add、replace、cas、delete（以及 version、quit）
+//!
+//! flags 和过期时间随记录一起保存，cas 的版本号就是记录的序列号。
+
+use std::{future::Future, io, sync::Arc};
+
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+        BufWriter,
+    },
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{BitCaskHandle, PutCondition, PutOptions, PutOutcome, StorageConfig};
+
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_KEY_LEN: usize = 250;
+const MAX_VALUE_LEN: usize = 64 * 1024 * 1024;
+// exptime 不超过 30 天时是相对时间（秒），否则是 unix 时间戳
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// 在 `listener` 上提供 memcached 服务，直到 `shutdown` 完成
+pub async fn serve<C: StorageConfig>(
+    listener: TcpListener,
+    handle: Arc<BitCaskHandle<C>>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    super::accept_loop(listener, handle, shutdown, handle_connection).await
+}
+
+async fn handle_connection<C: StorageConfig>(
+    stream: TcpStream,
+    handle: Arc<BitCaskHandle<C>>,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    while let Some(line) = read_line(&mut reader).await? {
+        let Ok(line) = std::str::from_utf8(&line) else {
+            writer
+                .write_all(b"CLIENT_ERROR invalid command\r\n")
+                .await?;
+            writer.flush().await?;
+            continue;
+        };
+        let args: Vec<&str> = line.split_ascii_whitespace().collect();
+        let Some((&command, args)) = args.split_first() else {
+            writer.write_all(b"ERROR\r\n").await?;
+            writer.flush().await?;
+            continue;
+        };
+
+        let result = match command {
+            "get" => get(&handle, args, false, &mut writer).await,
+            "gets" => get(&handle, args, true, &mut writer).await,
+            "set" | "add" | "replace" | "cas" => {
+                store(&handle, command, args, &mut reader, &mut writer).await
+            }
+            "delete" => delete(&handle, args, &mut writer).await,
+            "version" => {
+                let version = format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"));
+                writer.write_all(version.as_bytes()).await
+            }
+            "quit" => break,
+            _ => writer.write_all(b"ERROR\r\n").await,
+        };
+        match result {
+            Ok(()) => {}
+            // 读写连接失败直接断开，存储层的错误回复给客户端
+            Err(e) if is_connection_error(&e) => return Err(e),
+            Err(e) => {
+                let msg = format!("SERVER_ERROR {e}\r\n").replace(['\r', '\n'], " ");
+                writer.write_all(msg.trim_end().as_bytes()).await?;
+                writer.write_all(b"\r\n").await?;
+            }
+        }
+
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await
+}
+
+async fn get<C: StorageConfig, W: AsyncWrite + Unpin>(
+    handle: &BitCaskHandle<C>,
+    keys: &[&str],
+    with_cas: bool,
+    writer: &mut W,
+) -> io::Result<()> {
+    if keys.is_empty() {
+        return writer.write_all(b"ERROR\r\n").await;
+    }
+    if let Some(key) = keys.iter().find(|key| !is_valid_key(key)) {
+        return client_error(writer, &format!("invalid key: {key}")).await;
+    }
+
+    for key in keys {
+        let Some((value, meta)) = handle.get_with_meta(key.as_bytes()).await? else {
+            continue;
+        };
+        let header = if with_cas {
+            format!(
+                "VALUE {key} {} {} {}\r\n",
+                meta.flags,
+                value.len(),
+                meta.version
+            )
+        } else {
+            format!("VALUE {key} {} {}\r\n", meta.flags, value.len())
+        };
+        writer.write_all(header.as_bytes()).await?;
+        writer.write_all(&value).await?;
+        writer.write_all(b"\r\n").await?;
+    }
+    writer.write_all(b"END\r\n").await
+}
+
+/// `<command> <key> <flags> <exptime> <bytes> [<cas unique>] [noreply]`，后面跟着一行数据
+async fn store<C, R, W>(
+    handle: &BitCaskHandle<C>,
+    command: &str,
+    args: &[&str],
+    reader: &mut R,
+    writer: &mut W,
+) -> io::Result<()>
+where
+    C: StorageConfig,
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let is_cas = command == "cas";
+    let required = if is_cas { 5 } else { 4 };
+    let noreply = args.len() == required + 1 && args[required] == "noreply";
+    if args.len() != required && !noreply {
+        return writer.write_all(b"ERROR\r\n").await;
+    }
+
+    let Ok(len) = args[3].parse::<usize>() else {
+        return client_error(writer, "bad command line format").await;
+    };
+    // 数据块连同结尾的 `\r\n` 的长度溢出时没法把它读掉，回复错误后断开连接
+    let Some(chunk_len) = (len as u64).checked_add(2) else {
+        client_error(writer, "bad data chunk").await?;
+        writer.flush().await?;
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "data chunk length overflows",
+        ));
+    };
+    // 知道数据长度之后，出错也要把数据块读掉，否则它会被当成下一条命令
+    let cas_unique = match is_cas.then(|| args[4].parse::<u64>()).transpose() {
+        Ok(cas_unique) => cas_unique,
+        Err(_) => {
+            discard(reader, chunk_len).await?;
+            return client_error(writer, "bad command line format").await;
+        }
+    };
+    let (Ok(flags), Ok(exptime)) = (args[1].parse::<u32>(), args[2].parse::<i64>()) else {
+        discard(reader, chunk_len).await?;
+        return client_error(writer, "bad command line format").await;
+    };
+
+    if len > MAX_VALUE_LEN {
+        // 丢掉这条命令的数据，保持连接可用
+        discard(reader, chunk_len).await?;
+        return writer
+            .write_all(b"SERVER_ERROR object too large for cache\r\n")
+            .await;
+    }
+    let mut value = vec![0u8; len + 2];
+    reader.read_exact(&mut value).await?;
+    if !value.ends_with(b"\r\n") {
+        return client_error(writer, "bad data chunk").await;
+    }
+    value.truncate(len);
+
+    let key = args[0];
+    if !is_valid_key(key) {
+        return client_error(writer, &format!("invalid key: {key}")).await;
+    }
+
+    let condition = match (command, cas_unique) {
+        (_, Some(version)) => PutCondition::Version(version),
+        ("add", _) => PutCondition::Absent,
+        ("replace", _) => PutCondition::Present,
+        _ => PutCondition::Always,
+    };
+    let options = PutOptions {
+        flags,
+        expire_at_ms: expire_at_ms(exptime, handle.clock().now_ms()),
+        condition,
+    };
+
+    let reply: &[u8] = match handle.put_with(key.as_bytes(), &value, options).await? {
+        PutOutcome::Stored { .. } => b"STORED\r\n",
+        PutOutcome::NotStored if is_cas => b"EXISTS\r\n",
+        PutOutcome::NotStored => b"NOT_STORED\r\n",
+        PutOutcome::NotFound => b"NOT_FOUND\r\n",
+    };
+    if noreply {
+        return Ok(());
+    }
+    writer.write_all(reply).await
+}
+
+/// `delete <key> [0] [noreply]`
+async fn delete<C: StorageConfig, W: AsyncWrite + Unpin>(
+    handle: &BitCaskHandle<C>,
+    args: &[&str],
+    writer: &mut W,
+) -> io::Result<()> {
+    let (key, noreply) = match args {
+        [key] | [key, "0"] => (*key, false),
+        [key, "noreply"] | [key, "0", "noreply"] => (*key, true),
+        _ => {
+            return client_error(
+                writer,
+                "bad command line format. Usage: delete <key> [noreply]",
+            )
+            .await;
+        }
+    };
+    if !is_valid_key(key) {
+        return client_error(writer, &format!("invalid key: {key}")).await;
+    }
+
+    let reply: &[u8] = if handle.delete(key.as_bytes()).await? {
+        b"DELETED\r\n"
+    } else {
+        b"NOT_FOUND\r\n"
+    };
+    if noreply {
+        return Ok(());
+    }
+    writer.write_all(reply).await
+}
+
+/// 把 memcached 的 exptime 换算成毫秒时间戳，0 表示永不过期；太大的 exptime 截到 `u64::MAX`
+fn expire_at_ms(exptime: i64, now_ms: u64) -> u64 {
+    match exptime {
+        0 => 0,
+        // 负数表示立即过期；过期时间为 0 会被当成永不过期，所以至少是 1
+        ..0 => now_ms.max(1),
+        1..=MAX_RELATIVE_EXPTIME => now_ms.saturating_add(exptime as u64 * 1000),
+        _ => (exptime as u64).saturating_mul(1000),
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && !key.bytes().any(|b| b.is_ascii_control())
+}
+
+async fn client_error<W: AsyncWrite + Unpin>(writer: &mut W, msg: &str) -> io::Result<()> {
+    writer
+        .write_all(format!("CLIENT_ERROR {msg}\r\n").as_bytes())
+        .await
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+async fn discard<R: AsyncBufRead + Unpin>(reader: &mut R, len: u64) -> io::Result<()> {
+    let skipped = tokio::io::copy(&mut reader.take(len), &mut tokio::io::sink()).await?;
+    if skipped < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// 读一行并去掉结尾的 `\r\n`；连接关闭时返回 `None`
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        // 行太长或者连接在行中间关闭
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
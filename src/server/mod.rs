@@ -0,0 +1,109 @@
+//! 把 [`BitCaskHandle`](crate::BitCaskHandle) 通过网络协议暴露出去
+//!
+//! 所有连接共享同一个 handle（`Arc<BitCaskHandle<C>>`）。
+
+pub mod admin;
+pub mod binary;
+pub mod http;
+pub mod memcached;
+pub mod resp;
+
+use std::{future::Future, io, sync::Arc};
+
+use tokio::{
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    task::JoinSet,
+};
+use tracing::{debug, error, info};
+
+use crate::{BitCaskHandle, StorageConfig};
+
+/// 可以接受连接的监听者，TCP 和 Unix domain socket 共用一套 accept 循环
+pub(crate) trait Listener {
+    type Stream: Send + 'static;
+
+    // 返回连接和用于日志的对端地址
+    fn accept_connection(&self) -> impl Future<Output = io::Result<(Self::Stream, String)>> + Send;
+
+    fn local_addr_string(&self) -> io::Result<String>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept_connection(&self) -> io::Result<(TcpStream, String)> {
+        let (stream, peer) = self.accept().await?;
+        Ok((stream, peer.to_string()))
+    }
+
+    fn local_addr_string(&self) -> io::Result<String> {
+        Ok(self.local_addr()?.to_string())
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept_connection(&self) -> io::Result<(UnixStream, String)> {
+        // 客户端的 Unix socket 通常没有绑定路径
+        let (stream, _) = self.accept().await?;
+        Ok((stream, "unix socket client".to_string()))
+    }
+
+    fn local_addr_string(&self) -> io::Result<String> {
+        let addr = self.local_addr()?;
+        Ok(match addr.as_pathname() {
+            Some(path) => path.display().to_string(),
+            None => "unnamed unix socket".to_string(),
+        })
+    }
+}
+
+/// 接受连接并为每个连接起一个任务，`shutdown` 完成后停止接受新连接并结束所有连接
+///
+/// 返回时所有连接任务都已经结束，它们持有的 handle 引用也已经释放。
+pub(crate) async fn accept_loop<C, L, F, Fut>(
+    listener: L,
+    handle: Arc<BitCaskHandle<C>>,
+    shutdown: impl Future<Output = ()>,
+    serve_connection: F,
+) -> io::Result<()>
+where
+    C: StorageConfig,
+    L: Listener,
+    F: Fn(L::Stream, Arc<BitCaskHandle<C>>) -> Fut,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    let local_addr = listener.local_addr_string()?;
+    info!("Listening on {local_addr}");
+
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = listener.accept_connection() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Failed to accept connection on {local_addr}: {e}");
+                        continue;
+                    }
+                };
+                debug!("Accepted connection from {peer}");
+                let connection = serve_connection(stream, handle.clone());
+                connections.spawn(async move {
+                    if let Err(e) = connection.await {
+                        debug!("Connection from {peer} closed with error: {e}");
+                    }
+                });
+            }
+            // 顺便回收已经结束的连接
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+
+    connections.shutdown().await;
+    info!("Stopped listening on {local_addr}");
+    Ok(())
+}
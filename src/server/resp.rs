@@ -0,0 +1,499 @@
+//! RESP2（Redis 协议）的一个子集：GET、SET、DEL、EXISTS、KEYS、SCAN、DBSIZE、PING、INFO
+//!
+//! 既支持客户端库发送的数组格式，也支持 `redis-cli` / telnet 的 inline 命令。
+//!
+//! SCAN 的 cursor 是服务端分配的编号，对应上一页的最后一个 key，下一页从这个 key 之后开始。
+//! 编号在所有连接间共享，只保留最近的 [`MAX_SCAN_CURSORS`] 个，太旧的 cursor 会返回错误。
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+        BufWriter,
+    },
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{BitCaskHandle, StorageConfig};
+
+// 和 Redis 的默认限制保持一致
+const MAX_INLINE_LEN: u64 = 64 * 1024;
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+const MAX_SCAN_CURSORS: usize = 4096;
+
+/// 在 `listener` 上提供 RESP 服务，直到 `shutdown` 完成
+pub async fn serve<C: StorageConfig>(
+    listener: TcpListener,
+    handle: Arc<BitCaskHandle<C>>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let cursors = Arc::new(Mutex::new(ScanCursors::default()));
+    super::accept_loop(listener, handle, shutdown, move |stream, handle| {
+        handle_connection(stream, handle, cursors.clone())
+    })
+    .await
+}
+
+/// SCAN 分配出去的 cursor 到上一页最后一个 key 的映射
+#[derive(Default)]
+struct ScanCursors {
+    last_id: u64,
+    last_keys: BTreeMap<u64, Vec<u8>>,
+}
+
+impl ScanCursors {
+    fn insert(&mut self, last_key: Vec<u8>) -> u64 {
+        self.last_id += 1;
+        self.last_keys.insert(self.last_id, last_key);
+        // 编号递增，最小的就是最早的
+        if self.last_keys.len() > MAX_SCAN_CURSORS {
+            self.last_keys.pop_first();
+        }
+        self.last_id
+    }
+
+    fn get(&self, cursor: u64) -> Option<Vec<u8>> {
+        self.last_keys.get(&cursor).cloned()
+    }
+}
+
+async fn handle_connection<C: StorageConfig>(
+    stream: TcpStream,
+    handle: Arc<BitCaskHandle<C>>,
+    cursors: Arc<Mutex<ScanCursors>>,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    loop {
+        let args = match read_command(&mut reader).await {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // 协议错误后无法再找到下一条命令的边界，回复错误并断开
+                Reply::Error(format!("ERR Protocol error: {e}"))
+                    .write_to(&mut writer)
+                    .await?;
+                writer.flush().await?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = if quit {
+            Reply::Simple("OK")
+        } else {
+            execute(&handle, &cursors, args).await
+        };
+        reply.write_to(&mut writer).await?;
+
+        // pipeline 的请求都处理完再一起发出去
+        if quit || reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn bulk(value: impl Into<Vec<u8>>) -> Self {
+        Reply::Bulk(Some(value.into()))
+    }
+
+    fn wrong_args(command: &[u8]) -> Self {
+        Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command.escape_ascii().to_string().to_lowercase()
+        ))
+    }
+
+    async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        // 嵌套的数组用显式的栈展开，避免 async 递归
+        let mut stack = vec![self];
+        while let Some(reply) = stack.pop() {
+            match reply {
+                Reply::Simple(s) => writer.write_all(format!("+{s}\r\n").as_bytes()).await?,
+                Reply::Error(e) => {
+                    let e = e.replace(['\r', '\n'], " ");
+                    writer.write_all(format!("-{e}\r\n").as_bytes()).await?
+                }
+                Reply::Integer(n) => writer.write_all(format!(":{n}\r\n").as_bytes()).await?,
+                Reply::Bulk(None) => writer.write_all(b"$-1\r\n").await?,
+                Reply::Bulk(Some(bytes)) => {
+                    writer
+                        .write_all(format!("${}\r\n", bytes.len()).as_bytes())
+                        .await?;
+                    writer.write_all(bytes).await?;
+                    writer.write_all(b"\r\n").await?;
+                }
+                Reply::Array(items) => {
+                    writer
+                        .write_all(format!("*{}\r\n", items.len()).as_bytes())
+                        .await?;
+                    stack.extend(items.iter().rev());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn execute<C: StorageConfig>(
+    handle: &BitCaskHandle<C>,
+    cursors: &Mutex<ScanCursors>,
+    args: Vec<Vec<u8>>,
+) -> Reply {
+    match run_command(handle, cursors, args).await {
+        Ok(reply) => reply,
+        Err(e) => Reply::Error(format!("ERR {e}")),
+    }
+}
+
+async fn run_command<C: StorageConfig>(
+    handle: &BitCaskHandle<C>,
+    cursors: &Mutex<ScanCursors>,
+    mut args: Vec<Vec<u8>>,
+) -> io::Result<Reply> {
+    let command = args[0].to_ascii_uppercase();
+    let argc = args.len();
+
+    let reply = match (command.as_slice(), argc) {
+        (b"PING", 1) => Reply::Simple("PONG"),
+        (b"PING", 2) => Reply::Bulk(args.pop()),
+        (b"ECHO", 2) => Reply::Bulk(args.pop()),
+        (b"GET", 2) => Reply::Bulk(handle.get(&args[1]).await?),
+        (b"SET", 3) => {
+            handle.put(&args[1], &args[2]).await?;
+            Reply::Simple("OK")
+        }
+        (b"SET", _) if argc > 3 => Reply::Error("ERR syntax error".to_string()),
+        (b"DEL", 2..) => {
+            let mut deleted = 0;
+            for key in &args[1..] {
+                if handle.delete(key).await? {
+                    deleted += 1;
+                }
+            }
+            Reply::Integer(deleted)
+        }
+        (b"EXISTS", 2..) => {
+            let found = args[1..]
+                .iter()
+                .filter(|key| handle.contains_key(key))
+                .count();
+            Reply::Integer(found as i64)
+        }
+        (b"KEYS", 2) => {
+            let prefix = glob_literal_prefix(&args[1]);
+            let keys = handle
+                .keys_after(prefix, None, usize::MAX)
+                .into_iter()
+                .filter(|key| glob_match(&args[1], key))
+                .map(Reply::bulk)
+                .collect();
+            Reply::Array(keys)
+        }
+        (b"SCAN", 2..) => scan(handle, cursors, &args[1..]),
+        (b"DBSIZE", 1) => Reply::Integer(handle.len() as i64),
+        (b"INFO", 1 | 2) => Reply::bulk(info(handle).await?),
+        // 客户端连接时常发的命令
+        (b"SELECT", 2) if args[1] == b"0" => Reply::Simple("OK"),
+        (b"SELECT", 2) => Reply::Error("ERR DB index is out of range".to_string()),
+        (b"COMMAND", _) => Reply::Array(Vec::new()),
+        (
+            b"PING" | b"ECHO" | b"GET" | b"SET" | b"DEL" | b"EXISTS" | b"KEYS" | b"SCAN"
+            | b"DBSIZE" | b"INFO" | b"SELECT",
+            _,
+        ) => Reply::wrong_args(&args[0]),
+        _ => Reply::Error(format!("ERR unknown command '{}'", args[0].escape_ascii())),
+    };
+    Ok(reply)
+}
+
+/// 按字节序每页查看 COUNT 个 key，返回其中匹配 MATCH 的；迭代期间的写入不会导致跳过或重复已有的 key
+///
+/// MATCH 开头不含通配符的部分当作前缀，只在这个范围里查找。
+fn scan<C: StorageConfig>(
+    handle: &BitCaskHandle<C>,
+    cursors: &Mutex<ScanCursors>,
+    args: &[Vec<u8>],
+) -> Reply {
+    let last_key = match parse_number::<u64>(&args[0]) {
+        Some(0) => None,
+        Some(cursor) => match cursors.lock().unwrap().get(cursor) {
+            Some(last_key) => Some(last_key),
+            None => return Reply::Error("ERR invalid cursor".to_string()),
+        },
+        None => return Reply::Error("ERR invalid cursor".to_string()),
+    };
+
+    let mut pattern: Option<&[u8]> = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in args[1..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = Some(value),
+            [name, value] if name.eq_ignore_ascii_case(b"COUNT") => {
+                match parse_number::<usize>(value) {
+                    Some(n) if n > 0 => count = n,
+                    _ => return Reply::Error("ERR syntax error".to_string()),
+                }
+            }
+            _ => return Reply::Error("ERR syntax error".to_string()),
+        }
+    }
+
+    let prefix = pattern.map_or(&[][..], glob_literal_prefix);
+    // 多取一个，用来判断后面还有没有
+    let mut keys = handle.keys_after(prefix, last_key.as_deref(), count.saturating_add(1));
+    let next_cursor = if keys.len() > count {
+        keys.truncate(count);
+        cursors.lock().unwrap().insert(keys[count - 1].clone())
+    } else {
+        0
+    };
+
+    let batch = keys
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .map(Reply::bulk)
+        .collect();
+    Reply::Array(vec![
+        Reply::bulk(next_cursor.to_string()),
+        Reply::Array(batch),
+    ])
+}
+
+async fn info<C: StorageConfig>(handle: &BitCaskHandle<C>) -> io::Result<String> {
+    let stats = handle.stats().await?;
+    Ok(format!(
+        "# Server\r\n\
+         bitcask_version:{}\r\n\
+         \r\n\
+         # Keyspace\r\n\
+         db0:keys={}\r\n\
+         \r\n\
+         # Bitcask\r\n\
+         data_files:{}\r\n\
+         hint_files:{}\r\n\
+         data_bytes:{}\r\n\
+         live_bytes:{}\r\n\
+         dead_bytes:{}\r\n\
+         active_file_id:{}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        stats.key_count,
+        stats.data_files,
+        stats.hint_files,
+        stats.data_bytes,
+        stats.live_bytes,
+        stats.dead_bytes,
+        stats.active_file_id,
+    ))
+}
+
+/// 读取一条命令；连接正常关闭时返回 `None`，格式错误时返回 `InvalidData`
+async fn read_command<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader).await? else {
+        return Ok(None);
+    };
+
+    let Some(count) = line.strip_prefix(b"*") else {
+        // inline 命令
+        return Ok(Some(
+            line.split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    };
+
+    let count =
+        parse_number::<i64>(count).ok_or_else(|| invalid_data("invalid multibulk length"))?;
+    if count <= 0 {
+        return Ok(Some(Vec::new()));
+    }
+    if count as usize > MAX_ARGS {
+        return Err(invalid_data("invalid multibulk length"));
+    }
+
+    let mut args = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let line = read_line(reader).await?.ok_or_else(unexpected_eof)?;
+        let len = line
+            .strip_prefix(b"$")
+            .and_then(parse_number::<usize>)
+            .filter(|len| *len <= MAX_BULK_LEN)
+            .ok_or_else(|| invalid_data("invalid bulk length"))?;
+
+        let mut arg = vec![0u8; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid_data("expected CRLF after bulk string"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// 读一行并去掉结尾的 `\r\n`；连接在行首关闭时返回 `None`
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_INLINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(if n as u64 == MAX_INLINE_LEN {
+            invalid_data("too big inline request")
+        } else {
+            unexpected_eof()
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_number<T: std::str::FromStr>(bytes: &[u8]) -> Option<T> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn unexpected_eof() -> io::Error {
+    io::ErrorKind::UnexpectedEof.into()
+}
+
+/// Redis 风格的 glob：`*`、`?`、`[abc]`、`[^a]`、`[a-z]` 和 `\` 转义
+/// glob 开头不含通配符和转义的部分，匹配的 key 一定以它开头
+fn glob_literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|b| matches!(b, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // 最近一个 `*` 的位置以及它当时对应的 key 位置，用于回溯
+    let mut star: Option<(usize, usize)> = None;
+
+    while k < key.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p, k));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    k += 1;
+                    continue;
+                }
+                b'[' => match match_class(pattern, p, key[k]) {
+                    Some((true, next)) => {
+                        p = next;
+                        k += 1;
+                        continue;
+                    }
+                    Some((false, _)) => {}
+                    None if key[k] == b'[' => {
+                        p += 1;
+                        k += 1;
+                        continue;
+                    }
+                    None => {}
+                },
+                b'\\' if p + 1 < pattern.len() && pattern[p + 1] == key[k] => {
+                    p += 2;
+                    k += 1;
+                    continue;
+                }
+                b'\\' if p + 1 < pattern.len() => {}
+                c if c == key[k] => {
+                    p += 1;
+                    k += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        // 当前字符不匹配，让上一个 `*` 多吃一个字符
+        let Some((star_p, star_k)) = star else {
+            return false;
+        };
+        star = Some((star_p, star_k + 1));
+        p = star_p + 1;
+        k = star_k + 1;
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// 匹配从 `pattern[start]`（`[`）开始的字符集合，返回是否匹配以及集合之后的位置；
+/// 没有闭合的 `]` 时返回 `None`，`[` 按普通字符处理
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    loop {
+        match *pattern.get(i)? {
+            b']' => break,
+            b'\\' => {
+                i += 1;
+                matched |= *pattern.get(i)? == c;
+                i += 1;
+            }
+            lo if pattern.get(i + 1) == Some(&b'-')
+                && pattern.get(i + 2).is_some_and(|b| *b != b']') =>
+            {
+                let hi = pattern[i + 2];
+                let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                matched |= (lo..=hi).contains(&c);
+                i += 3;
+            }
+            b => {
+                matched |= b == c;
+                i += 1;
+            }
+        }
+    }
+    Some((matched != negate, i + 1))
+}
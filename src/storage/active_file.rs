@@ -1,32 +1,55 @@
 use std::{
-    io::{self, IoSlice},
+    io,
     path::PathBuf,
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
-use tokio::{
-    fs::File,
-    io::{AsyncWriteExt, BufWriter},
-};
+use tokio::{fs::File, io::AsyncWriteExt};
 use tracing::{debug, error};
 
-use super::{constants::*, file_util::new_data_writer};
-use crate::{storage::config::StorageConfig, utils::time::current_timestamp_ms};
+use super::{
+    constants::*,
+    file_util::{encode_record_header, new_data_file, set_readonly},
+    manifest::Manifest,
+};
+use crate::{storage::config::StorageConfig, utils::time::Clock};
+
+/// 和 value 一起写进记录的属性
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordAttrs {
+    pub flags: u32,
+    // 过期时间（毫秒），0 表示永不过期
+    pub expire_at: u64,
+    pub namespace: u32,
+}
 
 pub struct WriteRecordResult {
     pub(crate) value_size: usize,
     pub(crate) value_pos: u64,
     pub(crate) file_id: u64,
-    pub(crate) timestamp: u64,
+    pub(crate) seq: u64,
 }
 
 pub struct ActiveFile {
     id: u64,
-    next_id: u64,
+    // 和 merge 共享，merge 不持有 active file 的锁也能给输出分配 id
+    next_id: Arc<AtomicU64>,
     current_pos: u64,
+    // 当前文件写入第一条记录的时间（毫秒），用于按时间轮转
+    first_write_ms: u64,
+    // 下一条记录的序列号，跨重启单调递增，决定同一个 key 的记录的新旧
+    next_seq: u64,
     base_dir: PathBuf,
     config: Arc<dyn StorageConfig>, // 这里为什么要 dyn
-    writer: BufWriter<File>,
+    clock: Arc<dyn Clock>,
+    // 不加缓冲：每条记录写完就交给 OS，随后的读能直接从文件读到，
+    // 进程崩溃或 handle 没有 close 就被丢掉时已经返回的写入也不会丢
+    file: File,
+    // 新文件在创建之前登记到清单里；merge 提交时持有 active file 的锁，也通过这里提交
+    manifest: Manifest,
     // 如果外层处理并发的方式不是 Actor 模型，而是单线程多任务，那么锁是必须的
     write_lock: tokio::sync::Mutex<()>,
 }
@@ -35,18 +58,28 @@ impl ActiveFile {
     pub async fn new(
         base_dir: PathBuf,
         initial_id: u64,
+        next_seq: u64,
         config: Arc<dyn StorageConfig>,
+        mut manifest: Manifest,
     ) -> io::Result<Self> {
-        let writer = new_data_writer(&base_dir, initial_id, FILE_WRITER_BUFFER_SIZE).await?;
-        let current_pos = writer.get_ref().metadata().await?.len();
+        if !manifest.contains(initial_id) {
+            manifest.add_data_file(initial_id, next_seq).await?;
+        }
+        let file = new_data_file(&base_dir, initial_id).await?;
+        let current_pos = file.metadata().await?.len();
+        let clock = config.clock();
 
         Ok(Self {
-            writer,
+            file,
             base_dir,
+            first_write_ms: clock.now_ms(),
+            clock,
             config,
             current_pos,
+            next_seq,
             id: initial_id,
-            next_id: initial_id + 1,
+            next_id: Arc::new(AtomicU64::new(initial_id + 1)),
+            manifest,
             write_lock: tokio::sync::Mutex::new(()),
         })
     }
@@ -55,29 +88,76 @@ impl ActiveFile {
         &mut self,
         key: &[u8],
         value: &[u8],
+        attrs: RecordAttrs,
+    ) -> io::Result<WriteRecordResult> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("value too large: {} bytes", value.len()),
+            ));
+        }
+        self.append_record(key, value, value.len() as u32, attrs)
+            .await
+    }
+
+    /// 写入删除标记，恢复 keydir 时会把这个 key 删掉
+    pub async fn write_tombstone(
+        &mut self,
+        namespace: u32,
+        key: &[u8],
+    ) -> io::Result<WriteRecordResult> {
+        let attrs = RecordAttrs {
+            namespace,
+            ..Default::default()
+        };
+        self.append_record(key, &[], TOMBSTONE_VALUE_SIZE, attrs)
+            .await
+    }
+
+    async fn append_record(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        value_size: u32,
+        attrs: RecordAttrs,
     ) -> io::Result<WriteRecordResult> {
+        if key.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key too large: {} bytes", key.len()),
+            ));
+        }
         let record_size = RECORD_HEADER_SIZE + key.len() + value.len();
 
-        let timestamp = current_timestamp_ms();
-        let mut header_bytes = [0u8; 16];
-        let record = Self::record_to_io_slices(timestamp, key, value, &mut header_bytes);
+        let seq = self.next_seq;
+        let timestamp = self.clock.now_ms();
+        let header_bytes = encode_record_header(seq, timestamp, attrs, key, value_size, value);
+        // 拼成一块一次写入：tokio 的 File 不支持 write_vectored，分开写每段都要等一次后台线程
+        let mut record = Vec::with_capacity(record_size);
+        record.extend_from_slice(&header_bytes);
+        record.extend_from_slice(key);
+        record.extend_from_slice(value);
 
-        if self.should_rotate(record_size) {
+        if self.should_rotate(record_size) || self.is_expired() {
             self.rotate().await?;
         }
+        if !self.has_records() {
+            self.first_write_ms = timestamp;
+        }
 
         let _guard = self.write_lock.lock().await;
-        let bytes_written = self.writer.write_vectored(&record).await?;
+        self.file.write_all(&record).await?;
+        // tokio 的 File 在后台线程上写，flush 等它真正写进 OS
+        self.file.flush().await?;
 
-        // 下面两行会有数据竞争的并发问题吗，在这个异步的write_record函数内部？
-        // - 会
         let start_pos = self.current_pos;
-        self.current_pos += bytes_written as u64;
+        self.current_pos += record_size as u64;
+        self.next_seq += 1;
 
         Ok(WriteRecordResult {
-            timestamp,
+            seq,
             file_id: self.id,
-            value_size: value.len(),
+            value_size: value_size as usize,
             value_pos: start_pos + RECORD_HEADER_SIZE as u64 + key.len() as u64,
         })
     }
@@ -86,14 +166,42 @@ impl ActiveFile {
         self.id
     }
 
-    async fn rotate(&mut self) -> io::Result<()> {
-        self.writer.flush().await?;
+    pub fn current_pos(&self) -> u64 {
+        self.current_pos
+    }
+
+    /// 文件头之后有没有记录
+    pub fn has_records(&self) -> bool {
+        self.current_pos > FILE_HEADER_SIZE
+    }
+
+    /// 已分配出去的最大序列号
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    pub async fn sync(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        self.file.sync_all().await
+    }
+
+    /// 非空且写入第一条记录后已经超过 `max_active_file_age`
+    pub fn is_expired(&self) -> bool {
+        let Some(max_age) = self.config.max_active_file_age() else {
+            return false;
+        };
+        self.has_records()
+            && self.clock.now_ms().saturating_sub(self.first_write_ms) >= max_age.as_millis() as u64
+    }
+
+    pub async fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
 
         let file_id = self.allocate_id();
-        let new_writer = new_data_writer(&self.base_dir, file_id, FILE_WRITER_BUFFER_SIZE).await?;
+        self.manifest.add_data_file(file_id, self.next_seq).await?;
+        let new_file = new_data_file(&self.base_dir, file_id).await?;
 
-        let old_writer = std::mem::replace(&mut self.writer, new_writer);
-        let file_to_sync = old_writer.into_inner();
+        let file_to_sync = std::mem::replace(&mut self.file, new_file);
         let old_file_id = self.id;
 
         tokio::spawn(async move {
@@ -103,48 +211,40 @@ impl ActiveFile {
         });
 
         self.id = file_id;
+        self.current_pos = FILE_HEADER_SIZE;
 
         Ok(())
     }
 
-    fn allocate_id(&mut self) -> u64 {
-        let new_id = self.next_id;
-        self.next_id += 1;
-        new_id
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
     }
 
-    #[inline]
-    fn record_to_io_slices<'a>(
-        timestamp: u64,
-        key: &'a [u8],
-        value: &'a [u8],
-        header_bytes: &'a mut [u8; 16],
-    ) -> [IoSlice<'a>; 5] {
-        header_bytes[0..8].copy_from_slice(&timestamp.to_le_bytes());
-        header_bytes[8..12].copy_from_slice(&(key.len() as u32).to_le_bytes());
-        header_bytes[12..16].copy_from_slice(&(value.len() as u32).to_le_bytes());
+    pub fn manifest_mut(&mut self) -> &mut Manifest {
+        &mut self.manifest
+    }
+
+    pub fn allocate_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
 
-        [
-            IoSlice::new(&header_bytes[0..8]),
-            IoSlice::new(&header_bytes[8..12]),
-            IoSlice::new(&header_bytes[12..16]),
-            IoSlice::new(key),
-            IoSlice::new(value),
-        ]
+    /// merge 的输出文件也从这里分配 id，保证和数据文件的 id 不冲突
+    pub fn id_allocator(&self) -> Arc<AtomicU64> {
+        self.next_id.clone()
     }
 
     #[inline(always)]
     fn should_rotate(&self, new_record_size: usize) -> bool {
-        self.current_pos + new_record_size as u64 >= self.config.max_active_file_size()
+        // 空文件不轮转，否则单条超大记录会不停地产生空文件
+        self.has_records()
+            && self.current_pos + new_record_size as u64 >= self.config.max_active_file_size()
     }
 
     async fn process_old_file(file: File, id: u64) -> io::Result<()> {
         file.sync_all().await?;
         debug!("File {id} synced successfully");
 
-        let mut perms = file.metadata().await?.permissions();
-        perms.set_readonly(true);
-        file.set_permissions(perms).await?;
+        set_readonly(&file).await?;
         debug!("File {id} set to readonly successfully");
 
         // TODO: 是否要关闭 file 句柄
@@ -1,65 +1,64 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     io,
+    ops::Bound,
     path::{Path, PathBuf},
-    pin::Pin,
-    sync::Arc,
+    sync::{
+        Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak, atomic::Ordering,
+    },
+    time::Duration,
 };
 
-use tokio::{
-    fs::{self, File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, BufReader},
-    sync::{Semaphore, mpsc as tk_mpsc},
-    task::JoinSet,
-};
+use tokio::{fs, sync::Mutex as AsyncMutex, task::JoinHandle};
+use tracing::{debug, error, info, warn};
+
+use crate::utils::time::Clock;
 
 use super::{
-    WriteRecordResult, active_file::ActiveFile, config::StorageConfig, constants::*,
-    file_util::FileCache,
+    RecordAttrs, WriteRecordResult,
+    active_file::ActiveFile,
+    checkpoint::{self, CheckpointReport},
+    config::StorageConfig,
+    constants::*,
+    file_util::{self, data_file_path, hint_file_path},
+    io_limiter::IoLimiter,
+    keydir::{Entry, KeyDir},
+    manifest::{FileMeta, Manifest},
+    merge::{self, CancelToken, MergeReport},
+    namespace::{self, NAMESPACE_DROPPED, Namespace, NamespaceRegistry},
+    options::{PutCondition, PutOptions, PutOutcome, ValueMeta},
+    read_files::{ReadFiles, ReadGuard},
+    read_only::ReadOnlyStore,
+    read_view::Snapshot,
+    rebuild::{self, DataDirScanResult, FileInfo, FileType, RebuildProgress},
+    snapshot::{self, KeydirSnapshot},
+    stats::{self, FileStats, StoreStats},
+    usage::{self, UsageTracker},
+    verify::{self, VerifyReport},
 };
 
-struct Entry {
-    value_size: usize,
-    value_pos: u64,
-    file_id: u64,
-    timestamp: u64,
-}
-
-impl Entry {
-    fn new(file_id: u64, value_pos: u64, value_size: usize, timestamp: u64) -> Self {
-        Entry {
-            file_id,
-            value_pos,
-            value_size,
-            timestamp,
-        }
-    }
-}
-
-#[derive(PartialEq)]
-enum FileType {
-    Hint,
-    Data,
-}
-
-struct FileInfo {
-    file_type: FileType,
-    id: u64,
-    path: PathBuf,
-}
-
-struct DataDirScanResult {
-    hint_files: Vec<FileInfo>,
-    data_files: Vec<FileInfo>,
-}
-
 pub struct BitCaskHandle<C: StorageConfig> {
     base_dir: PathBuf,
     config: Arc<C>,
-    read_files: FileCache,
-    active_file: ActiveFile,
-    hint_files: Option<Vec<File>>,
-    keydir: HashMap<Vec<u8>, Entry>,
+    clock: Arc<dyn Clock>,
+    // 读者登记 epoch，merge 删除的文件等读者都结束后才删
+    read_files: Arc<ReadFiles>,
+    // 后台的按时间轮转任务也要访问 active file
+    active_file: Arc<AsyncMutex<ActiveFile>>,
+    // 只在持有 active file 锁时修改，保证写文件和更新 keydir 是一个原子操作；后台快照任务也要读
+    keydir: Arc<RwLock<KeyDir>>,
+    // 同样只在持有 active file 锁时修改
+    namespaces: RwLock<NamespaceRegistry>,
+    // 每个数据文件的存活 / 垃圾字节，和 keydir 一起更新
+    usage: Mutex<UsageTracker>,
+    // merge、快照和校验共用的磁盘读写限速
+    io_limiter: Arc<IoLimiter>,
+    // 正在进行的 merge 的取消标记，见 `cancel_merge`
+    running_merge: Mutex<Option<CancelToken>>,
+    // 整个 merge 期间持有，保证同一时刻最多一个 merge
+    merge_lock: AsyncMutex<()>,
+    rotation_task: Option<JoinHandle<()>>,
+    snapshot_task: Option<JoinHandle<()>>,
 }
 
 impl<C> BitCaskHandle<C>
@@ -69,336 +68,1038 @@ where
     pub async fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
         Self::open_with_config(dir, C::default()).await
     }
+
+    pub async fn open_read_only(dir: impl Into<PathBuf>) -> io::Result<ReadOnlyStore> {
+        Self::open_read_only_with_config(dir, C::default()).await
+    }
 }
 
 impl<C: StorageConfig> BitCaskHandle<C> {
     pub async fn open_with_config(dir: impl Into<PathBuf>, config: C) -> io::Result<Self> {
         let base_dir = dir.into();
-        let scan_result = Self::scan_data_dir(&base_dir).await?;
+        fs::create_dir_all(&base_dir).await?;
+        let manifest = Manifest::open(&base_dir).await?;
+        merge::recover(&base_dir).await?;
+        let scan_result = Self::scan_data_dir(&base_dir, manifest.files()).await?;
 
-        let mut max_id = scan_result
-            .data_files
-            .iter()
-            .map(|f| f.id)
-            .max()
-            .unwrap_or(0u64);
-        max_id = max_id.max(
-            scan_result
-                .hint_files
-                .iter()
-                .map(|f| f.id)
-                .max()
-                .unwrap_or(0u64),
-        );
-        let initial_id = if max_id == 0 { 0 } else { max_id + 1 };
+        // 不在清单里的文件的 id 也不再使用
+        let max_id = manifest.max_file_id().unwrap_or(0);
+        // 最新的数据文件是空的（上次打开后没有写入）就接着用，不再新建一个空文件
+        let last_is_empty = manifest.contains(max_id)
+            && !manifest.has_hint(max_id)
+            && file_util::data_file_lens(&base_dir, &[max_id])
+                .await?
+                .get(&max_id)
+                .is_none_or(|&len| len <= FILE_HEADER_SIZE);
+        let initial_id = if max_id == 0 || last_is_empty {
+            max_id
+        } else {
+            max_id + 1
+        };
 
         let config = Arc::new(config);
 
-        let active_file =
-            ActiveFile::new(PathBuf::from(&base_dir), initial_id, config.clone()).await?;
+        let (mut keydir, max_seq) = Self::load_keydir(&base_dir, &config, scan_result).await?;
+        let namespaces = namespace::load_registry(&base_dir, &mut keydir).await?;
+        let file_lens = file_util::data_file_lens(&base_dir, &manifest.data_file_ids()).await?;
+        let usage = UsageTracker::rebuild(&keydir, &file_lens, &manifest.kept_bytes());
 
-        let keydir = Self::build_keydir(scan_result).await?;
+        let active_file = ActiveFile::new(
+            PathBuf::from(&base_dir),
+            initial_id,
+            max_seq + 1,
+            config.clone(),
+            manifest,
+        )
+        .await?;
+        let active_file = Arc::new(AsyncMutex::new(active_file));
+        let keydir = Arc::new(RwLock::new(keydir));
+        let io_limiter = Arc::new(IoLimiter::new(config.background_io_rate()));
+
+        let rotation_task = config.max_active_file_age().map(|_| {
+            Self::spawn_rotation_task(
+                Arc::downgrade(&active_file),
+                config.rotation_check_interval(),
+            )
+        });
+        let snapshot_task = config.keydir_snapshot_interval().map(|interval| {
+            Self::spawn_snapshot_task(
+                base_dir.clone(),
+                Arc::downgrade(&active_file),
+                Arc::downgrade(&keydir),
+                io_limiter.clone(),
+                config.clock(),
+                interval,
+            )
+        });
 
         Ok(BitCaskHandle {
             keydir,
+            namespaces: RwLock::new(namespaces),
+            usage: Mutex::new(usage),
+            io_limiter,
+            running_merge: Mutex::new(None),
+            merge_lock: AsyncMutex::new(()),
+            read_files: Arc::new(ReadFiles::new(base_dir.clone(), READ_FILES_CACHE_SIZE)),
             base_dir,
+            clock: config.clock(),
             config,
             active_file,
-            hint_files: None,
-            read_files: FileCache::new(READ_FILES_CACHE_SIZE),
+            rotation_task,
+            snapshot_task,
         })
     }
 
-    pub async fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        None
+    /// 只读地打开 `dir`，不在目录里创建或修改任何文件，见 [`ReadOnlyStore`]
+    ///
+    /// 目录不存在时返回 `NotFound`；上次有提交了但还没改完名的 merge 时返回错误，
+    /// 需要先正常打开一次把它补完。
+    pub async fn open_read_only_with_config(
+        dir: impl Into<PathBuf>,
+        config: C,
+    ) -> io::Result<ReadOnlyStore> {
+        let base_dir = dir.into();
+        let files = Manifest::load_files(&base_dir).await?;
+        for (&file_id, meta) in &files {
+            let mut paths = vec![data_file_path(&base_dir, file_id)];
+            if meta.has_hint {
+                paths.push(hint_file_path(&base_dir, file_id));
+            }
+            for path in paths {
+                if !fs::try_exists(&path).await?
+                    && fs::try_exists(file_util::merge_temp_path(&path)).await?
+                {
+                    return Err(io::Error::other(format!(
+                        "{} has an unfinished merge, open it for writing once to complete it",
+                        base_dir.display()
+                    )));
+                }
+            }
+        }
+
+        let scan_result = Self::scan_data_dir(&base_dir, &files).await?;
+        let (mut keydir, _) = Self::load_keydir(&base_dir, &config, scan_result).await?;
+        // 只用来去掉已删除的 namespace 的分区
+        namespace::load_registry(&base_dir, &mut keydir).await?;
+        let data_file_ids: Vec<u64> = files.keys().copied().collect();
+        let file_lens = file_util::data_file_lens(&base_dir, &data_file_ids).await?;
+        let kept_bytes = files
+            .iter()
+            .map(|(&file_id, meta)| (file_id, meta.kept_bytes))
+            .collect();
+        let usage = UsageTracker::rebuild(&keydir, &file_lens, &kept_bytes);
+
+        Ok(ReadOnlyStore::new(
+            base_dir,
+            config.clock(),
+            keydir,
+            files,
+            usage,
+            IoLimiter::new(config.background_io_rate()),
+        ))
     }
 
-    pub async fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
-        // TODO: 写文件 和 更新 keydir 需要是一整个原子操作
-        // 如果 多线程多任务，RwLock，那么写锁要锁整个handle吗？
-        // 这样，put内部调用栈上的各处await又有何意义？并发体现在何处？
-        // 同理，如果是（单线程多任务）单线程异步，这些await点的意义又何在？是多个put的IO可以交替并发？
-        let WriteRecordResult {
-            file_id,
-            value_pos,
-            value_size,
-            timestamp,
-        } = self.active_file.write_record(key, value).await?;
+    /// 丢弃 `MANIFEST`，按目录里的数据文件和 hint 文件重建，返回登记的数据文件数
+    ///
+    /// 用于清单丢失或者手动放入了文件的情况，存储不能处于打开状态。
+    pub async fn repair_manifest(dir: impl AsRef<Path>) -> io::Result<usize> {
+        // 快照不知道新登记的文件，下次打开时完整重建
+        snapshot::remove(dir.as_ref()).await?;
+        Manifest::rebuild(dir.as_ref()).await
+    }
 
-        self.update_keydir(key, file_id, value_pos, value_size, timestamp);
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.get_in(DEFAULT_NAMESPACE, key).await
+    }
 
+    /// 同 [`get`](Self::get)，同时返回写入时附带的 flags、版本号等元信息
+    pub async fn get_with_meta(&self, key: &[u8]) -> io::Result<Option<(Vec<u8>, ValueMeta)>> {
+        self.get_with_meta_in(DEFAULT_NAMESPACE, key).await
+    }
+
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.put_with(key, value, PutOptions::default()).await?;
         Ok(())
     }
 
-    pub fn active_file_id(&self) -> u64 {
-        self.active_file.id()
-    }
-
-    async fn scan_data_dir(base_dir: &Path) -> io::Result<DataDirScanResult> {
-        #[allow(unused_doc_comments)]
-        /// for file in base_dir:
-        ///     file_type  = get_type(file)
-        ///     max_id = 0
-        ///     build file info map:
-        ///         put file_id in file_map if not exists:
-        ///             <file_id, (file_handle, [file_type_list])>
-        ///             max_id = max(max_id, file_id)
-        ///         or
-        ///         put file_id in hint_file_map if not exists:
-        ///             <file_id, file_handle>
-        ///             max_id = max(max_id, file_id)
-        ///         put file_id in data_file_map if not exists:
-        ///             <file_id, file_handle>
-        ///             max_id = max(max_id, file_id)
-        ///         data_file_map.zip(hint_file_map).filter(fild_id in data_file_map && not in hint_file_map)
-        ///
-        ///     
-        /// for (file_id, file_handle) in hint_file_map:
-        ///     async_process_hint_file(file_handle, keydir)
-        ///     
-        /// for (file_id, file_handle) in data_file_map:
-        ///     async_process_data_file(file_handle, keydir)
-        ///
-        /// async_process_file(file_handle, keydir):
-        ///     for content in batch_read(file_handle):
-        ///         (key, timestamp) = decode(content)
-        ///         if timestamp > keydir(key).timestamp:
-        ///             update keydir
-        ///     
-        //
-        let mut hint_file_map = HashMap::new();
-        let mut data_file_map = HashMap::new();
-
-        let mut entries = fs::read_dir(base_dir).await?;
-        while let Some(entry) = entries.next_entry().await? {
-            let path = entry.path();
-            if !path.is_file() {
-                continue;
-            }
+    /// 带 flags、过期时间和写入条件的 put；条件的检查和写入是原子的
+    pub async fn put_with(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        options: PutOptions,
+    ) -> io::Result<PutOutcome> {
+        self.put_with_in(DEFAULT_NAMESPACE, key, value, options)
+            .await
+    }
 
-            let (Some(file_name), Some(ext)) = (
-                path.file_name().and_then(|x| x.to_str()),
-                path.extension().and_then(|x| x.to_str()),
-            ) else {
-                continue;
-            };
+    /// 写入删除标记并从 keydir 中移除；key 不存在（或已过期）时返回 `false`，不写任何数据
+    pub async fn delete(&self, key: &[u8]) -> io::Result<bool> {
+        self.delete_in(DEFAULT_NAMESPACE, key).await
+    }
 
-            if let Ok(id) = file_name.parse::<u64>() {
-                match ext {
-                    "log" => {
-                        data_file_map.entry(id).or_insert(path.clone());
-                    }
-                    "hint" => {
-                        hint_file_map.entry(id).or_insert(path.clone());
-                    }
-                    _ => continue,
-                }
+    /// 所有存活（未删除、未过期）的 key，按字节序排列
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.keys_in(DEFAULT_NAMESPACE)
+    }
+
+    /// 默认 namespace 中 `cursor` 之后以 `prefix` 开头的 key，用于分页扫描
+    pub(crate) fn keys_after(
+        &self,
+        prefix: &[u8],
+        cursor: Option<&[u8]>,
+        limit: usize,
+    ) -> Vec<Vec<u8>> {
+        self.keys_after_in(DEFAULT_NAMESPACE, prefix, cursor, limit)
+    }
+
+    /// 以 `prefix` 开头的所有 key 和对应的 value，按 key 的字节序排列
+    pub async fn scan_prefix(&self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_prefix_in(DEFAULT_NAMESPACE, prefix).await
+    }
+
+    /// 默认 namespace 在此刻的只读视图，之后的写入和删除在视图里都看不到
+    ///
+    /// 视图存活期间，它引用的文件即使被 merge 取代也不会被删除。
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot_in(DEFAULT_NAMESPACE)
+    }
+
+    pub(crate) fn snapshot_in(&self, namespace: u32) -> Snapshot {
+        // 和读一样，先登记再复制分区
+        let read = self.read_files.pin();
+        let partition = self
+            .read_keydir()
+            .partition(namespace)
+            .cloned()
+            .unwrap_or_default();
+        Snapshot::new(partition, self.clock.now_ms(), read)
+    }
+
+    /// 名为 `name` 的 namespace，不存在时创建
+    ///
+    /// handle 自身的读写方法操作的是默认 namespace，和所有命名的 namespace 互不可见。
+    pub async fn namespace(&self, name: &str) -> io::Result<Namespace<'_, C>> {
+        if let Some(id) = self.read_namespaces().id(name) {
+            return Ok(Namespace::new(self, id, name));
+        }
+        namespace::validate_name(name)?;
+
+        let mut active_file = self.active_file.lock().await;
+        // 等锁期间可能已经被别的任务创建了
+        if let Some(id) = self.read_namespaces().id(name) {
+            return Ok(Namespace::new(self, id, name));
+        }
+        let id = self.read_namespaces().next_id()?;
+        self.write_registry_record(&mut active_file, id, name, 0)
+            .await?;
+        self.write_namespaces().register(name, id);
+        drop(active_file);
+
+        info!("Namespace {name} created with id {id}");
+        Ok(Namespace::new(self, id, name))
+    }
+
+    /// 删除 namespace 和其中所有的 key；不存在时返回 `false`
+    ///
+    /// 只写一条注册表记录并丢掉 keydir 分区，数据文件里的记录由之后的 merge 回收。
+    /// 之后再创建同名的 namespace 会分配新的 id，是空的。
+    pub async fn drop_namespace(&self, name: &str) -> io::Result<bool> {
+        let mut active_file = self.active_file.lock().await;
+        let Some(id) = self.read_namespaces().id(name) else {
+            return Ok(false);
+        };
+        self.write_registry_record(&mut active_file, id, name, NAMESPACE_DROPPED)
+            .await?;
+        self.write_namespaces().unregister(name);
+        let partition = self.write_keydir().remove_partition(id).unwrap_or_default();
+        {
+            let mut usage = self.lock_usage();
+            for (key, entry) in &partition {
+                usage.mark_dead(
+                    entry.file_id,
+                    usage::record_size(key.len(), entry.value_size),
+                );
             }
         }
+        drop(active_file);
 
-        data_file_map.retain(|file_id, _| !hint_file_map.contains_key(file_id));
+        let key_count = partition.len();
+        info!("Namespace {name} (id {id}) dropped, {key_count} keys left for merge");
+        Ok(true)
+    }
 
-        let hint_file_list: Vec<FileInfo> = hint_file_map
-            .into_iter()
-            .map(|(file_id, path)| FileInfo {
-                id: file_id,
-                file_type: FileType::Hint,
-                path,
-            })
-            .collect();
+    /// 所有命名的 namespace，按名字排序
+    pub fn namespaces(&self) -> Vec<String> {
+        self.read_namespaces().names()
+    }
 
-        let data_file_list: Vec<FileInfo> = data_file_map
-            .into_iter()
-            .map(|(file_id, path)| FileInfo {
-                id: file_id,
-                file_type: FileType::Data,
-                path,
-            })
-            .collect();
+    pub(crate) async fn get_in(&self, namespace: u32, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        // 先登记再查 keydir，读完之前 entry 指向的文件不会被删除
+        let read = self.read_files.pin();
+        let Some(entry) = self.live_entry(namespace, key) else {
+            return Ok(None);
+        };
 
-        Ok(DataDirScanResult {
-            hint_files: hint_file_list,
-            data_files: data_file_list,
-        })
+        read.read_value(&entry).await.map(Some)
     }
 
-    async fn build_keydir(scan_res: DataDirScanResult) -> io::Result<HashMap<Vec<u8>, Entry>> {
-        let (tx, mut rx) = tk_mpsc::channel(100);
-        let semaphore = Arc::new(Semaphore::new(num_cpus::get() * 2));
-        let mut tasks = JoinSet::<io::Result<()>>::new();
+    pub(crate) async fn get_with_meta_in(
+        &self,
+        namespace: u32,
+        key: &[u8],
+    ) -> io::Result<Option<(Vec<u8>, ValueMeta)>> {
+        // 先登记再查 keydir，读完之前 entry 指向的文件不会被删除
+        let read = self.read_files.pin();
+        let Some(entry) = self.live_entry(namespace, key) else {
+            return Ok(None);
+        };
 
-        type FileProcessorFn = fn(
-            PathBuf,
-            u64,
-        ) -> Pin<
-            Box<dyn Future<Output = io::Result<HashMap<Vec<u8>, Entry>>> + Send>,
-        >;
+        read.read_value_with_meta(key, &entry).await.map(Some)
+    }
 
-        let spawn_file_task = |tasks: &mut JoinSet<io::Result<()>>,
-                               tx: &tk_mpsc::Sender<HashMap<Vec<u8>, Entry>>,
-                               semaphore: &Arc<Semaphore>,
-                               file: FileInfo,
-                               processor: FileProcessorFn| {
-            let tx = tx.clone();
-            let semaphore = semaphore.clone();
+    pub(crate) async fn put_with_in(
+        &self,
+        namespace: u32,
+        key: &[u8],
+        value: &[u8],
+        options: PutOptions,
+    ) -> io::Result<PutOutcome> {
+        // 写文件和更新 keydir 都在 active file 的锁内完成，
+        // 并发的 put / delete 按 seq 的顺序生效，读者只会看到完整写入的记录
+        let mut active_file = self.active_file.lock().await;
+        if !self.read_namespaces().is_live(namespace) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "namespace has been dropped",
+            ));
+        }
 
-            tasks.spawn(async move {
-                let _permit = semaphore.acquire().await.map_err(io::Error::other)?;
-                // let _permit = semaphore.acquire_owned().await.map_err(io::Error::other)?;
+        let current = self.live_entry(namespace, key);
+        let outcome = match (options.condition, current) {
+            (PutCondition::Always, _) => None,
+            (PutCondition::Absent, None) | (PutCondition::Present, Some(_)) => None,
+            (PutCondition::Absent, Some(_)) | (PutCondition::Present, None) => {
+                Some(PutOutcome::NotStored)
+            }
+            (PutCondition::Version(_), None) => Some(PutOutcome::NotFound),
+            (PutCondition::Version(version), Some(entry)) if entry.seq == version => None,
+            (PutCondition::Version(_), Some(_)) => Some(PutOutcome::NotStored),
+        };
+        if let Some(outcome) = outcome {
+            return Ok(outcome);
+        }
+
+        let attrs = RecordAttrs {
+            flags: options.flags,
+            expire_at: options.expire_at_ms,
+            namespace,
+        };
+        let written = active_file.write_record(key, value, attrs).await?;
+        let seq = written.seq;
+        self.update_keydir(namespace, key, written, attrs.expire_at);
+        drop(active_file);
 
-                let entries = processor(file.path, file.id).await?;
+        Ok(PutOutcome::Stored { version: seq })
+    }
+
+    pub(crate) async fn delete_in(&self, namespace: u32, key: &[u8]) -> io::Result<bool> {
+        let mut active_file = self.active_file.lock().await;
+        if self.live_entry(namespace, key).is_none() {
+            return Ok(false);
+        }
 
-                tx.send(entries)
-                    .await
-                    .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+        let written = active_file.write_tombstone(namespace, key).await?;
+        self.update_keydir(namespace, key, written, 0);
+        drop(active_file);
 
-                Ok(())
-            });
+        Ok(true)
+    }
+
+    pub(crate) fn keys_in(&self, namespace: u32) -> Vec<Vec<u8>> {
+        let now = self.clock.now_ms();
+        let keydir = self.read_keydir();
+        let Some(partition) = keydir.partition(namespace) else {
+            return Vec::new();
         };
+        partition
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// 按字节序取 `cursor` 之后（不包含）以 `prefix` 开头的存活 key，最多 `limit` 个
+    ///
+    /// 从分区的有序表里做范围查询，代价只和跳过的过期 key 以及返回的 key 有关。
+    pub(crate) fn keys_after_in(
+        &self,
+        namespace: u32,
+        prefix: &[u8],
+        cursor: Option<&[u8]>,
+        limit: usize,
+    ) -> Vec<Vec<u8>> {
+        let now = self.clock.now_ms();
+        let keydir = self.read_keydir();
+        let Some(partition) = keydir.partition(namespace) else {
+            return Vec::new();
+        };
+        let start = match cursor {
+            Some(cursor) if cursor >= prefix => Bound::Excluded(cursor),
+            _ => Bound::Included(prefix),
+        };
+        partition
+            .range::<_, [u8]>((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
 
-        let mut keydir: HashMap<Vec<u8>, Entry> = HashMap::new();
+    pub(crate) async fn scan_prefix_in(
+        &self,
+        namespace: u32,
+        prefix: &[u8],
+    ) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let keys = self.keys_after_in(namespace, prefix, None, usize::MAX);
 
-        for hint_file in scan_res.hint_files {
-            spawn_file_task(&mut tasks, &tx, &semaphore, hint_file, |path, id| {
-                Box::pin(Self::process_hint_file(path, id))
-            });
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            // 取 key 列表之后被删除的 key 直接跳过
+            if let Some(value) = self.get_in(namespace, &key).await? {
+                pairs.push((key, value));
+            }
         }
-        drop(tx);
+        Ok(pairs)
+    }
+
+    /// namespace 中存活的 key 的数量和 value 的总字节数
+    pub(crate) fn partition_stats(&self, namespace: u32) -> (usize, u64) {
+        let now = self.clock.now_ms();
+        let keydir = self.read_keydir();
+        let Some(partition) = keydir.partition(namespace) else {
+            return (0, 0);
+        };
+        partition
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .fold((0, 0), |(count, bytes), entry| {
+                (count + 1, bytes + entry.value_size as u64)
+            })
+    }
+
+    /// `key_count` 是所有 namespace（包括默认 namespace）中存活的 key 的总数
+    pub async fn stats(&self) -> io::Result<StoreStats> {
+        let (active_file_id, data_file_ids, hint_files) = {
+            let active_file = self.active_file.lock().await;
+            let manifest = active_file.manifest();
+            (
+                active_file.id(),
+                manifest.data_file_ids(),
+                manifest.hint_file_ids().len(),
+            )
+        };
+        let key_count = {
+            let now = self.clock.now_ms();
+            self.read_keydir()
+                .iter()
+                .filter(|(namespace, _, entry)| {
+                    *namespace != SYSTEM_NAMESPACE && !entry.is_expired(now)
+                })
+                .count()
+        };
+        let files = self.file_stats();
+        stats::collect(
+            &self.base_dir,
+            key_count,
+            active_file_id,
+            &data_file_ids,
+            hint_files,
+            files,
+        )
+        .await
+    }
 
-        while let Some(entries) = rx.recv().await {
-            keydir.extend(entries);
+    /// 每个数据文件（包括 active file）的存活和垃圾字节，按文件 id 升序
+    pub fn file_stats(&self) -> Vec<FileStats> {
+        self.lock_usage().file_stats()
+    }
+
+    /// 把所有已封存的数据文件中存活的记录重写到新文件（带 hint 文件），然后删除旧文件
+    pub async fn merge(&self) -> io::Result<MergeReport> {
+        self.merge_with_cancel(None, &CancelToken::new()).await
+    }
+
+    /// 只 merge `file_ids` 中已封存的数据文件，active file 和不存在的文件被忽略
+    ///
+    /// 其他文件里可能还有被删除或过期的 key 的旧版本，所以输入文件里的 tombstone
+    /// 和过期的记录会被保留下来。
+    pub async fn merge_files(&self, file_ids: &[u64]) -> io::Result<MergeReport> {
+        self.merge_with_cancel(Some(file_ids), &CancelToken::new())
+            .await
+    }
+
+    /// 取消正在进行的 merge，没有 merge 在进行时返回 `false`
+    pub fn cancel_merge(&self) -> bool {
+        match &*self.running_merge.lock().expect("merge lock poisoned") {
+            Some(cancel) => {
+                cancel.cancel();
+                true
+            }
+            None => false,
         }
+    }
 
-        let (tx, mut rx) = tk_mpsc::channel(100);
+    /// `file_ids` 为 `None` 时同 [`merge`](Self::merge)，否则同 [`merge_files`](Self::merge_files)；
+    /// `cancel` 被取消时 merge 放弃已写出的输出并返回 `Interrupted`，存储保持原样
+    pub async fn merge_with_cancel(
+        &self,
+        file_ids: Option<&[u64]>,
+        cancel: &CancelToken,
+    ) -> io::Result<MergeReport> {
+        // 同一时刻最多一个 merge；active file 的锁只在挑选输入和提交时持有，搬运记录期间照常写入
+        let _merging = self.merge_lock.lock().await;
+        let active_file = self.active_file.lock().await;
+        let active_id = active_file.id();
 
-        for data_file in scan_res.data_files {
-            spawn_file_task(&mut tasks, &tx, &semaphore, data_file, |path, id| {
-                Box::pin(Self::process_data_file(path, id))
-            });
+        let sealed_ids: Vec<u64> = active_file
+            .manifest()
+            .data_file_ids()
+            .into_iter()
+            .filter(|&id| id != active_id)
+            .collect();
+        let input_ids: Vec<u64> = match file_ids {
+            Some(selected) => sealed_ids
+                .iter()
+                .copied()
+                .filter(|id| selected.contains(id))
+                .collect(),
+            None => sealed_ids.clone(),
+        };
+        if input_ids.is_empty() {
+            return Ok(MergeReport::default());
         }
-        drop(tx);
-
-        while let Some(entries) = rx.recv().await {
-            for (key, new_entry) in entries {
-                if let Some(entry) = keydir.get(&key) {
-                    if new_entry.timestamp <= entry.timestamp {
-                        continue;
-                    }
-                    keydir.insert(key, new_entry);
-                }
+        // 之后轮转出来的文件里的记录都更新，不影响 tombstone 的取舍
+        let oldest_outside = active_file.manifest().oldest_seq_excluding(&input_ids);
+        let next_id = active_file.id_allocator();
+        drop(active_file);
+
+        // 持有 merge 锁时才登记，同一时刻最多一个；future 被中途丢弃时也要清掉
+        struct Registered<'a>(&'a Mutex<Option<CancelToken>>);
+        impl Drop for Registered<'_> {
+            fn drop(&mut self) {
+                *self.0.lock().expect("merge lock poisoned") = None;
             }
         }
+        *self.running_merge.lock().expect("merge lock poisoned") = Some(cancel.clone());
+        let registered = Registered(&self.running_merge);
+        // 期间被覆盖或删除的 key 在 install 时按旧位置比较，不会被改回去
+        let result = merge::merge_files(
+            &self.base_dir,
+            &input_ids,
+            oldest_outside,
+            &self.keydir,
+            self.config.max_active_file_size(),
+            self.clock.now_ms(),
+            &self.io_limiter,
+            cancel,
+            || next_id.fetch_add(1, Ordering::SeqCst),
+        )
+        .await;
+        drop(registered);
+        let prepared = match result {
+            Ok(prepared) => prepared,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                info!("Merge cancelled");
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
 
-        // while tasks.join_next().await.is_some() {}
-        while let Some(res) = tasks.join_next().await {
-            res??;
+        // 提交、更新 keydir 和重新统计用量时不能有写入穿插进来
+        let mut active_file = self.active_file.lock().await;
+        // 清单里的这条记录落盘就是提交点，之后崩溃的话打开时按清单补完
+        let committed = active_file
+            .manifest_mut()
+            .commit_merge(prepared.outputs(), &input_ids)
+            .await;
+        if let Err(e) = committed {
+            prepared.abort().await;
+            return Err(e);
         }
+        let report = prepared.install(&self.keydir).await?;
+
+        // 输入文件马上要被删除，按剩下的文件重新统计用量
+        let live_ids = active_file.manifest().data_file_ids();
+        let file_lens = file_util::data_file_lens(&self.base_dir, &live_ids).await?;
+        let kept_bytes = active_file.manifest().kept_bytes();
+        *self.lock_usage() = UsageTracker::rebuild(&self.read_keydir(), &file_lens, &kept_bytes);
+        drop(active_file);
+
+        // 快照引用了即将删除的文件，先删快照
+        snapshot::remove(&self.base_dir).await?;
+        self.read_files.retire(&input_ids).await;
 
-        Ok(keydir)
+        info!(
+            "Merged {} files into {}: {} live records, {} bytes reclaimed",
+            report.input_files, report.output_files, report.live_records, report.reclaimed_bytes
+        );
+        Ok(report)
     }
 
-    async fn process_data_file(path: PathBuf, file_id: u64) -> io::Result<HashMap<Vec<u8>, Entry>> {
-        let mut res_map: HashMap<Vec<u8>, Entry> = HashMap::new();
-        let file = OpenOptions::new().read(true).open(&path).await?;
-        let mut reader = BufReader::with_capacity(FILE_READER_BUFFER_SIZE, file);
+    /// 生成一个一致的备份：轮转 active file，把所有已封存的数据文件和 hint 文件硬链接（跨文件系统时复制）
+    /// 到 `dest`，连同清单一起组成一个可以独立打开的存储。`dest` 必须不存在或者为空。
+    ///
+    /// 只在轮转时短暂持有 active file 的锁，备份期间可以继续写入。
+    pub async fn checkpoint(&self, dest: impl AsRef<Path>) -> io::Result<CheckpointReport> {
+        let (files, _read) = self.seal_for_backup().await?;
+        checkpoint::write(&self.base_dir, dest.as_ref(), &files).await
+    }
 
-        // TODO cap as follow:
-        // while let Some(entry) = read_data_record(&mut reader).await? {
-        // }
-        let mut offset = 0u64;
-        let mut header_bytes = [0u8; RECORD_HEADER_SIZE];
-        while (reader.read_exact(&mut header_bytes).await).is_ok() {
-            let tstamp_bytes = <[u8; 8]>::try_from(&header_bytes[0..8]).unwrap();
-            let timestamp = u64::from_be_bytes(tstamp_bytes);
+    /// 增量备份：`previous_manifest` 是上一次备份（全量或增量）的 `MANIFEST`，只把之后新封存的文件
+    /// 放进 `dest`，清单里记录当前完整的文件集合和期间被 merge 移除的文件
+    ///
+    /// 用 [`restore`](Self::restore) 把全量备份和之后的增量备份组装回一个存储。
+    pub async fn incremental_backup(
+        &self,
+        previous_manifest: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+    ) -> io::Result<CheckpointReport> {
+        let (previous, _) = Manifest::read(previous_manifest.as_ref()).await?;
+        let (files, _read) = self.seal_for_backup().await?;
+        // merge 的输出和新的 active file 的 id 都更大，上一次备份里的文件不可能比现在的都新
+        let newest = files.iter().map(|&(file_id, _)| file_id).max();
+        if previous.keys().next_back().copied() > newest {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is not a backup of this store",
+                    previous_manifest.as_ref().display()
+                ),
+            ));
+        }
+        checkpoint::write_incremental(&self.base_dir, dest.as_ref(), &previous, &files).await
+    }
 
-            let key_size_bytes = <[u8; 4]>::try_from(&header_bytes[8..12]).unwrap();
-            let key_size = u32::from_be_bytes(key_size_bytes);
-            let mut key = vec![0u8; key_size as usize];
+    /// 从 `backups`（一个全量备份，后面跟着按时间顺序的增量备份）组装出一个新的存储放进 `dest`
+    ///
+    /// `dest` 必须不存在或者为空，之后可以直接打开。
+    pub async fn restore(
+        backups: &[impl AsRef<Path>],
+        dest: impl AsRef<Path>,
+    ) -> io::Result<CheckpointReport> {
+        let backups: Vec<PathBuf> = backups
+            .iter()
+            .map(|dir| dir.as_ref().to_path_buf())
+            .collect();
+        checkpoint::restore(&backups, dest.as_ref()).await
+    }
 
-            if reader.read_exact(&mut key).await.is_err() {
-                break;
+    /// 轮转 active file，返回所有已封存的文件；返回的登记释放之前，merge 不会删除这些文件
+    async fn seal_for_backup(&self) -> io::Result<(Vec<(u64, FileMeta)>, ReadGuard)> {
+        let mut active_file = self.active_file.lock().await;
+        if active_file.has_records() {
+            active_file.rotate().await?;
+        }
+        let active_id = active_file.id();
+        let manifest = active_file.manifest();
+        let files = manifest
+            .data_file_ids()
+            .into_iter()
+            .filter(|&id| id != active_id)
+            .filter_map(|id| Some((id, manifest.file_meta(id)?)))
+            .collect();
+        Ok((files, self.read_files.pin()))
+    }
+
+    /// 检查数据文件、hint 文件和 keydir 的一致性，只读不修复
+    pub async fn verify(&self) -> io::Result<VerifyReport> {
+        // 校验期间不删除被 merge 取代的文件，它们还在目录里
+        let _read = self.read_files.pin();
+        let keydir = self.read_keydir().clone();
+        verify::verify(&self.base_dir, &keydir, &self.io_limiter).await
+    }
+
+    /// 把 active file 刷盘并写 keydir 快照，下次打开时可以跳过重建
+    pub async fn close(self) -> io::Result<()> {
+        self.write_keydir_snapshot().await
+    }
+
+    /// 默认 namespace 中存活的 key 的数量
+    pub fn len(&self) -> usize {
+        self.partition_stats(DEFAULT_NAMESPACE).0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.live_entry(DEFAULT_NAMESPACE, key).is_some()
+    }
+
+    /// 当前的后台读写限速（每秒字节数），`None` 表示不限速
+    pub fn background_io_rate(&self) -> Option<u64> {
+        self.io_limiter.rate()
+    }
+
+    /// 运行时调整后台读写限速，对正在进行的 merge 也立即生效；`None` 或 0 表示不限速
+    pub fn set_background_io_rate(&self, bytes_per_sec: Option<u64>) {
+        self.io_limiter.set_rate(bytes_per_sec);
+        info!("Background I/O rate set to {bytes_per_sec:?} bytes/s");
+    }
+
+    pub fn config(&self) -> &C {
+        &self.config
+    }
+
+    /// 过期时间使用的时钟，即 [`StorageConfig::clock`]
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    pub async fn active_file_id(&self) -> u64 {
+        self.active_file.lock().await.id()
+    }
+
+    /// 立即封存当前的 active file 并切换到新文件；active file 为空时什么都不做
+    pub async fn rotate_now(&self) -> io::Result<()> {
+        let mut active_file = self.active_file.lock().await;
+        if !active_file.has_records() {
+            return Ok(());
+        }
+        active_file.rotate().await
+    }
+
+    /// 把 active file 的缓冲写到磁盘并 fsync
+    pub async fn sync(&self) -> io::Result<()> {
+        self.active_file.lock().await.sync().await
+    }
+
+    fn spawn_rotation_task(
+        active_file: Weak<AsyncMutex<ActiveFile>>,
+        check_interval: Duration,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(check_interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                // handle 已经被 drop
+                let Some(active_file) = active_file.upgrade() else {
+                    break;
+                };
+
+                let mut active_file = active_file.lock().await;
+                if !active_file.is_expired() {
+                    continue;
+                }
+                let file_id = active_file.id();
+                match active_file.rotate().await {
+                    Ok(()) => debug!("File {file_id} rotated by age"),
+                    Err(e) => error!("Failed to rotate file {file_id} by age: {e}"),
+                }
             }
+        })
+    }
 
-            let entry = res_map.entry(key).or_insert_with(|| Entry::new(0, 0, 0, 0));
-            if timestamp <= entry.timestamp {
-                continue;
+    /// 每隔 `interval` 写一次 keydir 快照，期间没有新的写入时跳过
+    fn spawn_snapshot_task(
+        base_dir: PathBuf,
+        active_file: Weak<AsyncMutex<ActiveFile>>,
+        keydir: Weak<RwLock<KeyDir>>,
+        io_limiter: Arc<IoLimiter>,
+        clock: Arc<dyn Clock>,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval.max(MIN_SNAPSHOT_INTERVAL));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            // 第一次 tick 立即完成；打开之后还没有写入时不用写快照
+            ticker.tick().await;
+            let mut last_seq = match active_file.upgrade() {
+                Some(active_file) => Some(active_file.lock().await.last_seq()),
+                None => return,
+            };
+            loop {
+                ticker.tick().await;
+                // handle 已经被 drop
+                let (Some(active_file), Some(keydir)) = (active_file.upgrade(), keydir.upgrade())
+                else {
+                    break;
+                };
+                if last_seq == Some(active_file.lock().await.last_seq()) {
+                    continue;
+                }
+                let written = Self::write_keydir_snapshot_to(
+                    &base_dir,
+                    &active_file,
+                    &keydir,
+                    &io_limiter,
+                    clock.now_sec(),
+                )
+                .await;
+                match written {
+                    Ok(seq) => last_seq = Some(seq),
+                    Err(e) => error!("Failed to write keydir snapshot: {e}"),
+                }
             }
+        })
+    }
 
-            let value_pos = offset + RECORD_HEADER_SIZE as u64 + key_size as u64;
-            let value_size_bytes = <[u8; 4]>::try_from(&header_bytes[12..16]).unwrap();
-            let value_size = u32::from_be_bytes(value_size_bytes);
+    async fn write_keydir_snapshot(&self) -> io::Result<()> {
+        Self::write_keydir_snapshot_to(
+            &self.base_dir,
+            &self.active_file,
+            &self.keydir,
+            &self.io_limiter,
+            self.clock.now_sec(),
+        )
+        .await?;
+        Ok(())
+    }
 
-            entry.file_id = file_id;
-            entry.value_pos = value_pos;
-            entry.value_size = value_size as usize;
-            entry.timestamp = timestamp;
+    /// 写 keydir 快照，返回它覆盖到的最大 seq
+    ///
+    /// 只在刷盘和复制 keydir（分区是共享的，复制很便宜）时持有 active file 的锁，编码和写文件都在锁外。
+    async fn write_keydir_snapshot_to(
+        base_dir: &Path,
+        active_file: &AsyncMutex<ActiveFile>,
+        keydir: &RwLock<KeyDir>,
+        io_limiter: &IoLimiter,
+        created_at: u64,
+    ) -> io::Result<u64> {
+        let mut active_file = active_file.lock().await;
+        // 快照只能覆盖已经落盘的记录；持有 active file 的锁，keydir 和偏移是一致的
+        active_file.sync().await?;
+        let file_id = active_file.id();
+        let offset = active_file.current_pos();
+        let max_seq = active_file.last_seq();
+        let keydir = keydir.read().expect("keydir lock poisoned").clone();
+        drop(active_file);
 
-            // seek to end of value, update offset
-            reader
-                .seek(io::SeekFrom::Current(value_size as i64))
-                .await?;
-            offset += value_pos + value_size as u64;
+        let bytes = snapshot::encode(&keydir, file_id, offset, max_seq, created_at);
+        snapshot::write(base_dir, &bytes, io_limiter).await?;
+
+        debug!(
+            "Keydir snapshot written: {} keys, up to file {file_id} offset {offset}",
+            keydir.len()
+        );
+        Ok(max_seq)
+    }
+
+    async fn load_keydir(
+        base_dir: &Path,
+        config: &C,
+        scan_res: DataDirScanResult,
+    ) -> io::Result<(KeyDir, u64)> {
+        let on_progress = |progress: &RebuildProgress| config.on_rebuild_progress(progress);
+
+        match snapshot::load(base_dir).await {
+            Ok(Some(snapshot)) => {
+                if let Some(pending) =
+                    Self::files_after_snapshot(base_dir, &snapshot, &scan_res).await?
+                {
+                    info!(
+                        "Loaded keydir snapshot taken at {}: {} keys, replaying {} files",
+                        snapshot.created_at,
+                        snapshot.keydir.len(),
+                        pending.hint_files.len() + pending.data_files.len()
+                    );
+                    return rebuild::build_keydir(
+                        snapshot.keydir,
+                        snapshot.max_seq,
+                        pending,
+                        on_progress,
+                    )
+                    .await;
+                }
+                info!("Keydir snapshot is stale, rebuilding keydir from data files");
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to load keydir snapshot, rebuilding keydir: {e}"),
         }
-        Ok(res_map)
+
+        rebuild::build_keydir(KeyDir::new(), 0, scan_res, on_progress).await
     }
 
-    async fn process_hint_file(path: PathBuf, file_id: u64) -> io::Result<HashMap<Vec<u8>, Entry>> {
-        let mut res_map: HashMap<Vec<u8>, Entry> = HashMap::new();
-        let file = OpenOptions::new().read(true).open(&path).await?;
-        let mut reader = BufReader::with_capacity(FILE_READER_BUFFER_SIZE, file);
+    /// 返回快照之后需要重放的文件；快照引用的文件已经不存在或被截断时返回 `None`
+    async fn files_after_snapshot(
+        base_dir: &Path,
+        snapshot: &KeydirSnapshot,
+        scan_res: &DataDirScanResult,
+    ) -> io::Result<Option<DataDirScanResult>> {
+        let covered_path = data_file_path(base_dir, snapshot.file_id);
+        match fs::metadata(&covered_path).await {
+            Ok(meta) if meta.len() >= snapshot.offset => {}
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        }
 
-        let mut header_bytes = [0u8; HINT_HEADER_SIZE];
-        while (reader.read_exact(&mut header_bytes).await).is_ok() {
-            let tstamp_bytes = <[u8; 8]>::try_from(&header_bytes[0..8]).unwrap();
-            let timestamp = u64::from_be_bytes(tstamp_bytes);
+        for file_id in snapshot.referenced_file_ids() {
+            if !fs::try_exists(data_file_path(base_dir, file_id)).await? {
+                return Ok(None);
+            }
+        }
 
-            let key_size_bytes = <[u8; 4]>::try_from(&header_bytes[8..12]).unwrap();
-            let key_size = u32::from_be_bytes(key_size_bytes);
+        let newer = |files: &[FileInfo]| -> Vec<FileInfo> {
+            files
+                .iter()
+                .filter(|f| f.id > snapshot.file_id)
+                .cloned()
+                .collect()
+        };
 
-            let value_size_bytes = <[u8; 4]>::try_from(&header_bytes[12..16]).unwrap();
-            let value_size = u32::from_be_bytes(value_size_bytes);
+        let hint_files = newer(&scan_res.hint_files);
+        let mut data_files = newer(&scan_res.data_files);
+        data_files.push(FileInfo {
+            file_type: FileType::Data,
+            id: snapshot.file_id,
+            path: covered_path,
+            start_offset: snapshot.offset,
+        });
 
-            let value_pos_bytes = <[u8; 8]>::try_from(&header_bytes[16..24]).unwrap();
-            let value_pos = u64::from_be_bytes(value_pos_bytes);
+        Ok(Some(DataDirScanResult {
+            hint_files,
+            data_files,
+        }))
+    }
 
-            let mut key = vec![0u8; key_size as usize];
-            if reader.read_exact(&mut key).await.is_err() {
-                break;
+    /// 清单里的文件：有 hint 文件的读 hint 文件，其余读数据文件；清单里有但磁盘上没有的文件被跳过
+    async fn scan_data_dir(
+        base_dir: &Path,
+        files: &BTreeMap<u64, FileMeta>,
+    ) -> io::Result<DataDirScanResult> {
+        let mut hint_files = Vec::new();
+        let mut data_files = Vec::new();
+        for (&file_id, meta) in files {
+            let (file_type, path) = if meta.has_hint {
+                (FileType::Hint, hint_file_path(base_dir, file_id))
+            } else {
+                (FileType::Data, data_file_path(base_dir, file_id))
+            };
+            if !fs::try_exists(&path).await? {
+                warn!("File listed in the manifest is missing: {}", path.display());
+                continue;
+            }
+            let info = FileInfo {
+                id: file_id,
+                file_type,
+                path,
+                start_offset: 0,
+            };
+            match info.file_type {
+                FileType::Hint => hint_files.push(info),
+                FileType::Data => data_files.push(info),
             }
-
-            res_map.insert(
-                key,
-                Entry {
-                    timestamp,
-                    value_size: value_size as usize,
-                    value_pos,
-                    file_id,
-                },
-            );
         }
 
-        Ok(res_map)
+        Ok(DataDirScanResult {
+            hint_files,
+            data_files,
+        })
+    }
+
+    fn read_keydir(&self) -> RwLockReadGuard<'_, KeyDir> {
+        self.keydir.read().expect("keydir lock poisoned")
+    }
+
+    fn write_keydir(&self) -> RwLockWriteGuard<'_, KeyDir> {
+        self.keydir.write().expect("keydir lock poisoned")
+    }
+
+    fn read_namespaces(&self) -> RwLockReadGuard<'_, NamespaceRegistry> {
+        self.namespaces
+            .read()
+            .expect("namespace registry lock poisoned")
     }
 
+    fn write_namespaces(&self) -> RwLockWriteGuard<'_, NamespaceRegistry> {
+        self.namespaces
+            .write()
+            .expect("namespace registry lock poisoned")
+    }
+
+    /// 在系统 namespace 里写一条注册表记录，调用方持有 active file 的锁
+    async fn write_registry_record(
+        &self,
+        active_file: &mut ActiveFile,
+        id: u32,
+        name: &str,
+        flags: u32,
+    ) -> io::Result<()> {
+        let key = id.to_le_bytes();
+        let attrs = RecordAttrs {
+            flags,
+            expire_at: 0,
+            namespace: SYSTEM_NAMESPACE,
+        };
+        let written = active_file
+            .write_record(&key, name.as_bytes(), attrs)
+            .await?;
+        self.update_keydir(SYSTEM_NAMESPACE, &key, written, 0);
+        Ok(())
+    }
+
+    /// 写入一条记录之后更新 keydir 和文件用量，调用方持有 active file 的锁
+    ///
+    /// tombstone 从 keydir 中删除 key；被覆盖或删除的旧记录算作垃圾。
     fn update_keydir(
-        &mut self,
+        &self,
+        namespace: u32,
         key: &[u8],
-        file_id: u64,
-        value_pos: u64,
-        value_size: usize,
-        timestamp: u64,
+        written: WriteRecordResult,
+        expire_at: u64,
     ) {
-        let entry = self
-            .keydir
-            .entry(key.to_vec())
-            .or_insert_with(|| Entry::new(0, 0, 0, 0));
-
-        // 比较 timestamp
-        if entry.timestamp < timestamp {
-            entry.file_id = file_id;
-            entry.value_pos = value_pos;
-            entry.value_size = value_size;
-            entry.timestamp = timestamp;
+        let WriteRecordResult {
+            file_id,
+            value_pos,
+            value_size,
+            seq,
+        } = written;
+        let size = usage::record_size(key.len(), value_size);
+
+        let mut keydir = self.write_keydir();
+        let mut usage = self.lock_usage();
+        let previous = if value_size == TOMBSTONE_VALUE_SIZE as usize {
+            usage.add_dead(file_id, size);
+            keydir.remove(namespace, key)
+        } else {
+            usage.add_live(file_id, size);
+            let new_entry = Entry::new(file_id, value_pos, value_size, seq, expire_at);
+            keydir.insert(namespace, key.to_vec(), new_entry)
+        };
+        if let Some(previous) = previous {
+            usage.mark_dead(
+                previous.file_id,
+                usage::record_size(key.len(), previous.value_size),
+            );
+        }
+    }
+
+    fn lock_usage(&self) -> MutexGuard<'_, UsageTracker> {
+        self.usage.lock().expect("usage lock poisoned")
+    }
+
+    /// 未过期的 entry
+    pub(crate) fn live_entry(&self, namespace: u32, key: &[u8]) -> Option<Entry> {
+        let now = self.clock.now_ms();
+        self.read_keydir()
+            .get(namespace, key)
+            .filter(|entry| !entry.is_expired(now))
+            .copied()
+    }
+}
+
+impl<C: StorageConfig> Drop for BitCaskHandle<C> {
+    fn drop(&mut self) {
+        for task in [self.rotation_task.take(), self.snapshot_task.take()]
+            .into_iter()
+            .flatten()
+        {
+            task.abort();
         }
     }
 }
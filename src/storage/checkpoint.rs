@@ -0,0 +1,199 @@
+//! 在线备份：把已封存的文件硬链接（跨文件系统时复制）到另一个目录，再写一份只包含这些文件的清单，
+//! 得到的目录可以作为独立的存储打开。已封存的文件不会再被修改，源存储之后的写入和 merge 都不影响备份。
+//!
+//! 增量备份只放上一次备份之后新封存的文件，清单里记录完整的文件集合和被 merge 移除的文件，
+//! 可以作为下一次增量备份的基准。恢复时把全量备份和之后的增量备份按顺序叠起来。
+//! 文件 id 不会重用，已封存的文件内容也不会变，所以按 id 比较就够了。
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::fs::{self, File};
+use tracing::{debug, info};
+
+use super::{
+    constants::*,
+    file_util::{self, data_file_path, hint_file_path},
+    manifest::{FileMeta, Manifest},
+};
+
+/// 一次备份或恢复的结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckpointReport {
+    // 放进目标目录的文件数，增量备份时只算新文件
+    pub data_files: usize,
+    pub hint_files: usize,
+    // 增量备份时，上一次备份之后被 merge 移除的数据文件数
+    pub removed_files: usize,
+    // 不能硬链接、复制过去的文件数
+    pub copied_files: usize,
+    pub bytes: u64,
+}
+
+/// 把 `files`（数据文件 id 和它在清单里的信息）放进 `dest`，`dest` 必须不存在或者为空
+///
+/// 调用方保证这些文件在返回之前不会被删除。
+pub(crate) async fn write(
+    base_dir: &Path,
+    dest: &Path,
+    files: &[(u64, FileMeta)],
+) -> io::Result<CheckpointReport> {
+    create_empty_dir(dest).await?;
+    let mut report = CheckpointReport::default();
+    for &(file_id, meta) in files {
+        place_file(base_dir, dest, file_id, meta.has_hint, &mut report).await?;
+    }
+
+    Manifest::create(dest, files).await?;
+    file_util::sync_dir(dest).await?;
+    info!(
+        "Checkpoint of {} data files written to {}",
+        report.data_files,
+        dest.display()
+    );
+    Ok(report)
+}
+
+/// 同 [`write`]，但只放 `previous`（上一次备份的清单）里没有的文件
+pub(crate) async fn write_incremental(
+    base_dir: &Path,
+    dest: &Path,
+    previous: &BTreeMap<u64, FileMeta>,
+    files: &[(u64, FileMeta)],
+) -> io::Result<CheckpointReport> {
+    let added: Vec<(u64, FileMeta)> = files
+        .iter()
+        .copied()
+        .filter(|(file_id, _)| !previous.contains_key(file_id))
+        .collect();
+    let removed: Vec<u64> = previous
+        .keys()
+        .copied()
+        .filter(|file_id| !files.iter().any(|(id, _)| id == file_id))
+        .collect();
+
+    create_empty_dir(dest).await?;
+    let mut report = CheckpointReport {
+        removed_files: removed.len(),
+        ..Default::default()
+    };
+    for &(file_id, meta) in &added {
+        place_file(base_dir, dest, file_id, meta.has_hint, &mut report).await?;
+    }
+
+    Manifest::create_incremental(dest, previous, &added, &removed).await?;
+    file_util::sync_dir(dest).await?;
+    info!(
+        "Incremental backup written to {}: {} new data files, {} removed",
+        dest.display(),
+        report.data_files,
+        report.removed_files
+    );
+    Ok(report)
+}
+
+/// 从一个全量备份和之后按顺序的增量备份组装出一个存储，放进 `dest`
+///
+/// 最后一个备份的清单决定存储里有哪些文件，每个文件从包含它的最新的备份里取。
+pub(crate) async fn restore(backups: &[PathBuf], dest: &Path) -> io::Result<CheckpointReport> {
+    let Some(last) = backups.last() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no backups to restore from",
+        ));
+    };
+    let (files, _) = Manifest::read(&last.join(MANIFEST_FILE_NAME)).await?;
+    // 先确认每个文件都找得到，避免留下一个残缺的存储
+    let mut sources = Vec::with_capacity(files.len());
+    for (&file_id, meta) in &files {
+        let mut found = None;
+        for backup in backups.iter().rev() {
+            if fs::try_exists(data_file_path(backup, file_id)).await? {
+                found = Some(backup);
+                break;
+            }
+        }
+        let Some(backup) = found else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("data file {file_id} is missing from the backup chain"),
+            ));
+        };
+        sources.push((backup, file_id, meta.has_hint));
+    }
+
+    create_empty_dir(dest).await?;
+    let mut report = CheckpointReport::default();
+    for (backup, file_id, has_hint) in sources {
+        place_file(backup, dest, file_id, has_hint, &mut report).await?;
+    }
+
+    let files: Vec<(u64, FileMeta)> = files.into_iter().collect();
+    Manifest::create(dest, &files).await?;
+    file_util::sync_dir(dest).await?;
+    info!(
+        "Restored {} data files from {} backups into {}",
+        report.data_files,
+        backups.len(),
+        dest.display()
+    );
+    Ok(report)
+}
+
+async fn create_empty_dir(dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest).await?;
+    if fs::read_dir(dest).await?.next_entry().await?.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("directory {} is not empty", dest.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// 把 `src_dir` 里的数据文件和它的 hint 文件放进 `dest`
+async fn place_file(
+    src_dir: &Path,
+    dest: &Path,
+    file_id: u64,
+    has_hint: bool,
+    report: &mut CheckpointReport,
+) -> io::Result<()> {
+    let mut paths = vec![(
+        data_file_path(src_dir, file_id),
+        data_file_path(dest, file_id),
+    )];
+    if has_hint {
+        paths.push((
+            hint_file_path(src_dir, file_id),
+            hint_file_path(dest, file_id),
+        ));
+        report.hint_files += 1;
+    }
+    report.data_files += 1;
+    for (src, dst) in paths {
+        if link_or_copy(&src, &dst).await? {
+            report.copied_files += 1;
+        }
+        report.bytes += fs::metadata(&dst).await?.len();
+    }
+    Ok(())
+}
+
+/// 优先硬链接，失败时（比如跨文件系统）复制；返回是否复制了。两种情况都把内容刷盘
+async fn link_or_copy(src: &Path, dst: &Path) -> io::Result<bool> {
+    let copied = match fs::hard_link(src, dst).await {
+        Ok(()) => false,
+        Err(e) => {
+            debug!("Cannot link {} ({e}), copying it", src.display());
+            fs::copy(src, dst).await?;
+            true
+        }
+    };
+    // 刚轮转掉的文件可能还没有被后台任务 fsync
+    File::open(dst).await?.sync_all().await?;
+    Ok(copied)
+}
@@ -1,3 +1,41 @@
+use std::{sync::Arc, time::Duration};
+
+use super::{merge_scheduler::MergePolicy, rebuild::RebuildProgress};
+use crate::utils::time::{Clock, SystemClock};
+
 pub trait StorageConfig: Send + Sync + 'static {
     fn max_active_file_size(&self) -> u64;
+
+    /// active file 写入第一条记录后最多存活多久就要轮转，`None` 表示只按大小轮转
+    fn max_active_file_age(&self) -> Option<Duration> {
+        None
+    }
+
+    /// 后台检查 active file 是否超过 `max_active_file_age` 的间隔
+    fn rotation_check_interval(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    /// 后台任务周期性写 keydir 快照的间隔，`None` 表示只在 `close()` 时写
+    fn keydir_snapshot_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// 启动时重建 keydir 的进度回调，每处理完一个文件调用一次
+    fn on_rebuild_progress(&self, _progress: &RebuildProgress) {}
+
+    /// 自动 merge 的策略，`None` 表示只手动 merge；由 [`MergeScheduler`](crate::MergeScheduler) 执行
+    fn merge_policy(&self) -> Option<MergePolicy> {
+        None
+    }
+
+    /// merge、hint 文件、快照和校验读写磁盘的限速（每秒字节数），`None` 表示不限速
+    fn background_io_rate(&self) -> Option<u64> {
+        None
+    }
+
+    /// 给记录打时间戳的时钟
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
 }
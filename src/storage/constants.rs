@@ -1,5 +1,29 @@
-pub const RECORD_HEADER_SIZE: usize = 8 + 4 + 4;
-pub const HINT_HEADER_SIZE: usize = 8 + 4 + 4 + 8;
+// 数据文件和 hint 文件开头的文件头：magic + 格式版本，记录从文件头之后开始
+pub const DATA_FILE_MAGIC: &[u8; 4] = b"BCKD";
+pub const HINT_FILE_MAGIC: &[u8; 4] = b"BCKH";
+pub const FILE_FORMAT_VERSION: u32 = 1;
+pub const FILE_HEADER_SIZE: u64 = 4 + 4;
+// crc + seq + timestamp + expire_at + flags + namespace + key_size + value_size
+pub const RECORD_HEADER_SIZE: usize = 4 + 8 + 8 + 8 + 4 + 4 + 4 + 4;
+// seq + timestamp + expire_at + namespace + key_size + value_size + value_pos
+pub const HINT_HEADER_SIZE: usize = 8 + 8 + 8 + 4 + 4 + 4 + 8;
+// value_size 为该值的记录是删除标记 (tombstone)，后面没有 value
+pub const TOMBSTONE_VALUE_SIZE: u32 = u32::MAX;
+// 单个 value 的最大长度，再长就和 tombstone 的标记冲突
+pub const MAX_VALUE_LEN: usize = TOMBSTONE_VALUE_SIZE as usize - 1;
+// BitCaskHandle 自身的方法读写的 namespace
+pub const DEFAULT_NAMESPACE: u32 = 0;
+// 保存 namespace 注册表的内部 namespace，不对外暴露
+pub const SYSTEM_NAMESPACE: u32 = u32::MAX;
 pub const FILE_WRITER_BUFFER_SIZE: usize = 8 * 1024;
 pub const FILE_READER_BUFFER_SIZE: usize = 8 * 1024;
 pub const READ_FILES_CACHE_SIZE: usize = 50;
+pub const DATA_FILE_EXTENSION: &str = "data";
+pub const HINT_FILE_EXTENSION: &str = "hint";
+pub const KEYDIR_SNAPSHOT_FILE_NAME: &str = "keydir.snapshot";
+// 后台写 keydir 快照的最小间隔
+pub const MIN_SNAPSHOT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+// merge 的输出在提交之前的后缀，打开时残留的这种文件直接删除
+pub const MERGE_TEMP_EXTENSION: &str = "merging";
+// 记录属于存储的数据文件和 hint 文件
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST";
@@ -1,45 +1,455 @@
 use std::{
+    collections::HashMap,
+    fs::File as StdFile,
     io::{self, Error},
     num::NonZeroUsize,
-    path::Path,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
 use lru::LruCache;
 use tokio::{
     fs::{File, OpenOptions},
-    io::BufWriter,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter},
 };
 use tracing::warn;
 
-pub async fn new_data_writer(
-    base_dir: &Path,
-    file_id: u64,
+use super::{RecordAttrs, constants::*};
+
+/// 数据文件 / hint 文件中一条记录的元信息（不含 value 本身）
+pub struct RecordMeta {
+    pub seq: u64,
+    pub timestamp: u64,
+    // 过期时间（毫秒），0 表示永不过期
+    pub expire_at: u64,
+    // 调用方附带的不透明标志位（memcached 的 flags），hint 记录里没有，为 0
+    pub flags: u32,
+    pub namespace: u32,
+    pub key: Vec<u8>,
+    pub value_pos: u64,
+    pub value_size: u32,
+    // hint 记录没有校验和，为 0
+    pub crc: u32,
+}
+
+impl RecordMeta {
+    pub fn is_tombstone(&self) -> bool {
+        self.value_size == TOMBSTONE_VALUE_SIZE
+    }
+
+    /// 记录在文件中实际占用的 value 字节数
+    pub fn value_len(&self) -> u64 {
+        if self.is_tombstone() {
+            0
+        } else {
+            self.value_size as u64
+        }
+    }
+
+    pub fn end_pos(&self) -> u64 {
+        self.value_pos + self.value_len()
+    }
+
+    pub fn checksum_matches(&self, value: &[u8]) -> bool {
+        let checksum = record_checksum(
+            self.seq,
+            self.timestamp,
+            self.attrs(),
+            &self.key,
+            self.value_size,
+            value,
+        );
+        checksum == self.crc
+    }
+
+    pub fn attrs(&self) -> RecordAttrs {
+        RecordAttrs {
+            flags: self.flags,
+            expire_at: self.expire_at,
+            namespace: self.namespace,
+        }
+    }
+}
+
+/// 校验和覆盖 header 中 crc 之后的部分以及 key、value
+pub fn record_checksum(
+    seq: u64,
+    timestamp: u64,
+    attrs: RecordAttrs,
+    key: &[u8],
+    value_size: u32,
+    value: &[u8],
+) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&seq.to_le_bytes());
+    hasher.update(&timestamp.to_le_bytes());
+    hasher.update(&attrs.expire_at.to_le_bytes());
+    hasher.update(&attrs.flags.to_le_bytes());
+    hasher.update(&attrs.namespace.to_le_bytes());
+    hasher.update(&(key.len() as u32).to_le_bytes());
+    hasher.update(&value_size.to_le_bytes());
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize()
+}
+
+pub fn encode_record_header(
+    seq: u64,
+    timestamp: u64,
+    attrs: RecordAttrs,
+    key: &[u8],
+    value_size: u32,
+    value: &[u8],
+) -> [u8; RECORD_HEADER_SIZE] {
+    let crc = record_checksum(seq, timestamp, attrs, key, value_size, value);
+    let mut header_bytes = [0u8; RECORD_HEADER_SIZE];
+    header_bytes[0..4].copy_from_slice(&crc.to_le_bytes());
+    header_bytes[4..12].copy_from_slice(&seq.to_le_bytes());
+    header_bytes[12..20].copy_from_slice(&timestamp.to_le_bytes());
+    header_bytes[20..28].copy_from_slice(&attrs.expire_at.to_le_bytes());
+    header_bytes[28..32].copy_from_slice(&attrs.flags.to_le_bytes());
+    header_bytes[32..36].copy_from_slice(&attrs.namespace.to_le_bytes());
+    header_bytes[36..40].copy_from_slice(&(key.len() as u32).to_le_bytes());
+    header_bytes[40..44].copy_from_slice(&value_size.to_le_bytes());
+    header_bytes
+}
+
+/// 解析数据记录的 header，返回的 `RecordMeta` 还没有 key，同时返回 key 的长度
+fn decode_record_header(header_bytes: &[u8; RECORD_HEADER_SIZE], offset: u64) -> (RecordMeta, u32) {
+    // timestamp 是写入时的墙上时间，只作为元信息保存，不参与新旧判断
+    let crc = u32::from_le_bytes(header_bytes[0..4].try_into().unwrap());
+    let seq = u64::from_le_bytes(header_bytes[4..12].try_into().unwrap());
+    let timestamp = u64::from_le_bytes(header_bytes[12..20].try_into().unwrap());
+    let expire_at = u64::from_le_bytes(header_bytes[20..28].try_into().unwrap());
+    let flags = u32::from_le_bytes(header_bytes[28..32].try_into().unwrap());
+    let namespace = u32::from_le_bytes(header_bytes[32..36].try_into().unwrap());
+    let key_size = u32::from_le_bytes(header_bytes[36..40].try_into().unwrap());
+    let value_size = u32::from_le_bytes(header_bytes[40..44].try_into().unwrap());
+
+    let record = RecordMeta {
+        seq,
+        timestamp,
+        expire_at,
+        flags,
+        namespace,
+        key: Vec::new(),
+        value_pos: offset + RECORD_HEADER_SIZE as u64 + key_size as u64,
+        value_size,
+        crc,
+    };
+    (record, key_size)
+}
+
+pub fn encode_hint_header(
+    seq: u64,
+    timestamp: u64,
+    expire_at: u64,
+    namespace: u32,
+    key: &[u8],
+    value_size: u32,
+    value_pos: u64,
+) -> [u8; HINT_HEADER_SIZE] {
+    let mut header_bytes = [0u8; HINT_HEADER_SIZE];
+    header_bytes[0..8].copy_from_slice(&seq.to_le_bytes());
+    header_bytes[8..16].copy_from_slice(&timestamp.to_le_bytes());
+    header_bytes[16..24].copy_from_slice(&expire_at.to_le_bytes());
+    header_bytes[24..28].copy_from_slice(&namespace.to_le_bytes());
+    header_bytes[28..32].copy_from_slice(&(key.len() as u32).to_le_bytes());
+    header_bytes[32..36].copy_from_slice(&value_size.to_le_bytes());
+    header_bytes[36..44].copy_from_slice(&value_pos.to_le_bytes());
+    header_bytes
+}
+
+pub fn data_file_path(base_dir: &Path, file_id: u64) -> PathBuf {
+    base_dir.join(format!("{file_id:08}.{DATA_FILE_EXTENSION}"))
+}
+
+pub fn hint_file_path(base_dir: &Path, file_id: u64) -> PathBuf {
+    base_dir.join(format!("{file_id:08}.{HINT_FILE_EXTENSION}"))
+}
+
+/// merge 输出文件在提交（rename 成 `path`）之前使用的名字
+pub fn merge_temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".");
+    temp.push(MERGE_TEMP_EXTENSION);
+    PathBuf::from(temp)
+}
+
+/// 目录的 fsync，让 rename 和删除落盘
+pub async fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir).await?.sync_all().await
+}
+
+/// 列出目录下指定扩展名的文件 id，按 id 升序
+pub async fn list_file_ids(base_dir: &Path, extension: &str) -> io::Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    let mut entries = tokio::fs::read_dir(base_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|x| x.to_str()) != Some(extension) {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.parse::<u64>().ok())
+        {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// `file_ids` 中各个数据文件的长度，不存在的文件被跳过
+pub async fn data_file_lens(base_dir: &Path, file_ids: &[u64]) -> io::Result<HashMap<u64, u64>> {
+    let mut lens = HashMap::new();
+    for &file_id in file_ids {
+        match tokio::fs::metadata(data_file_path(base_dir, file_id)).await {
+            Ok(meta) => {
+                lens.insert(file_id, meta.len());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(lens)
+}
+
+/// 打开（不存在时创建）数据文件用于追加，active file 不经过缓冲直接写
+pub async fn new_data_file(base_dir: &Path, file_id: u64) -> io::Result<File> {
+    let path = data_file_path(base_dir, file_id);
+    open_for_append(&path, DATA_FILE_MAGIC).await
+}
+
+/// 写到 `path` 对应的临时文件，见 [`merge_temp_path`]；`magic` 是数据文件或 hint 文件的文件头
+pub async fn new_merge_temp_writer(
+    path: &Path,
+    magic: &[u8; 4],
     buffer_size: usize,
 ) -> io::Result<BufWriter<File>> {
-    let path = base_dir.join(format!("{file_id:08}.data"));
-    new_file_writer(&path, buffer_size).await
+    new_file_writer(&merge_temp_path(path), magic, buffer_size).await
+}
+
+/// 把文件设为只读，封存的数据文件和 hint 文件都不会再被修改
+pub async fn set_readonly(file: &File) -> io::Result<()> {
+    let mut perms = file.metadata().await?.permissions();
+    perms.set_readonly(true);
+    file.set_permissions(perms).await
 }
 
-async fn new_file_writer(path: &Path, buffer_size: usize) -> io::Result<BufWriter<File>> {
+async fn new_file_writer(
+    path: &Path,
+    magic: &[u8; 4],
+    buffer_size: usize,
+) -> io::Result<BufWriter<File>> {
+    let file = open_for_append(path, magic).await?;
+    Ok(BufWriter::with_capacity(buffer_size, file))
+}
+
+async fn open_for_append(path: &Path, magic: &[u8; 4]) -> io::Result<File> {
     // let read_fd = OpenOptions::new().read(true).open(path).await?;
     // let mut reader = BufReader::with_capacity(constants::FILE_READER_BUFFER_SIZE, read_fd);
     // let mut buffer = [0u8, 10];
     // reader.read_exact(&mut buffer).await.unwrap();
 
-    let write_fd = OpenOptions::new()
+    let mut write_fd = OpenOptions::new()
         .read(true)
         .create(true)
         .append(true)
         .open(path)
         .await?;
+    // 新文件，或者上次创建之后没来得及写完文件头
+    if write_fd.metadata().await?.len() < FILE_HEADER_SIZE {
+        write_fd.set_len(0).await?;
+        write_fd.write_all(&encode_file_header(magic)).await?;
+        write_fd.flush().await?;
+    }
+
+    Ok(write_fd)
+}
+
+fn encode_file_header(magic: &[u8; 4]) -> [u8; FILE_HEADER_SIZE as usize] {
+    let mut header = [0u8; FILE_HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(magic);
+    header[4..8].copy_from_slice(&FILE_FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// 读取并检查文件头，之后 `reader` 停在第一条记录处
+///
+/// 文件比文件头还短（刚创建、还没写入）时返回 `false`。没有文件头（旧版本写的文件）
+/// 或者格式版本不支持时返回 `InvalidData`，不去猜测记录的布局。
+pub async fn read_file_header<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    magic: &[u8; 4],
+    path: &Path,
+) -> io::Result<bool> {
+    let mut header = [0u8; FILE_HEADER_SIZE as usize];
+    if !read_exact_or_eof(reader, &mut header).await? {
+        return Ok(false);
+    }
+    if &header[0..4] != magic {
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} has no file header, it was written by an older version or is not a bitcask file",
+                path.display()
+            ),
+        ));
+    }
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if version != FILE_FORMAT_VERSION {
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} has unsupported format version {version} (expected {FILE_FORMAT_VERSION})",
+                path.display()
+            ),
+        ));
+    }
+    Ok(true)
+}
+
+/// 从 `offset` 处读取一条数据记录，读到文件尾（包括尾部不完整的记录）时返回 `None`
+pub async fn read_data_record<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    offset: u64,
+) -> io::Result<Option<RecordMeta>> {
+    let Some(record) = read_data_record_header(reader, offset).await? else {
+        return Ok(None);
+    };
+
+    // 只需要 value 的位置，跳过 value 本身
+    let skipped =
+        tokio::io::copy(&mut reader.take(record.value_len()), &mut tokio::io::sink()).await?;
+    if skipped != record.value_len() {
+        return Ok(None);
+    }
+
+    Ok(Some(record))
+}
+
+/// 同 [`read_data_record`]，但同时读出 value（tombstone 的 value 为空）
+pub async fn read_data_record_with_value<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    offset: u64,
+) -> io::Result<Option<(RecordMeta, Vec<u8>)>> {
+    let Some(record) = read_data_record_header(reader, offset).await? else {
+        return Ok(None);
+    };
+
+    let mut value = vec![0u8; record.value_len() as usize];
+    if !read_exact_or_eof(reader, &mut value).await? {
+        return Ok(None);
+    }
+
+    Ok(Some((record, value)))
+}
+
+async fn read_data_record_header<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    offset: u64,
+) -> io::Result<Option<RecordMeta>> {
+    let mut header_bytes = [0u8; RECORD_HEADER_SIZE];
+    if !read_exact_or_eof(reader, &mut header_bytes).await? {
+        return Ok(None);
+    }
+    let (mut record, key_size) = decode_record_header(&header_bytes, offset);
+
+    record.key = vec![0u8; key_size as usize];
+    if !read_exact_or_eof(reader, &mut record.key).await? {
+        return Ok(None);
+    }
+
+    Ok(Some(record))
+}
+
+pub async fn read_hint_record<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<RecordMeta>> {
+    let mut header_bytes = [0u8; HINT_HEADER_SIZE];
+    if !read_exact_or_eof(reader, &mut header_bytes).await? {
+        return Ok(None);
+    }
+
+    let seq = u64::from_le_bytes(header_bytes[0..8].try_into().unwrap());
+    let timestamp = u64::from_le_bytes(header_bytes[8..16].try_into().unwrap());
+    let expire_at = u64::from_le_bytes(header_bytes[16..24].try_into().unwrap());
+    let namespace = u32::from_le_bytes(header_bytes[24..28].try_into().unwrap());
+    let key_size = u32::from_le_bytes(header_bytes[28..32].try_into().unwrap());
+    let value_size = u32::from_le_bytes(header_bytes[32..36].try_into().unwrap());
+    let value_pos = u64::from_le_bytes(header_bytes[36..44].try_into().unwrap());
+
+    let mut key = vec![0u8; key_size as usize];
+    if !read_exact_or_eof(reader, &mut key).await? {
+        return Ok(None);
+    }
 
-    let writer = BufWriter::with_capacity(buffer_size, write_fd);
-    Ok(writer)
+    Ok(Some(RecordMeta {
+        seq,
+        timestamp,
+        expire_at,
+        flags: 0,
+        namespace,
+        key,
+        value_pos,
+        value_size,
+        crc: 0,
+    }))
+}
+
+async fn read_exact_or_eof<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> io::Result<bool> {
+    match reader.read_exact(buf).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn read_value_at(file: Arc<StdFile>, pos: u64, size: usize) -> io::Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || {
+        let mut buf = vec![0u8; size];
+        file.read_exact_at(&mut buf, pos)?;
+        Ok(buf)
+    })
+    .await
+    .map_err(Error::other)?
+}
+
+/// 读取 value 在 `value_pos` 处的整条记录（header、key 和 value），并校验 key 和校验和
+pub async fn read_record_at(
+    file: Arc<StdFile>,
+    key: &[u8],
+    value_pos: u64,
+    value_size: usize,
+) -> io::Result<(RecordMeta, Vec<u8>)> {
+    let header_len = RECORD_HEADER_SIZE as u64 + key.len() as u64;
+    let offset = value_pos
+        .checked_sub(header_len)
+        .ok_or_else(|| Error::new(io::ErrorKind::InvalidData, "invalid value position"))?;
+    let mut bytes = read_value_at(file, offset, header_len as usize + value_size).await?;
+
+    let header_bytes: &[u8; RECORD_HEADER_SIZE] = bytes[..RECORD_HEADER_SIZE].try_into().unwrap();
+    let (mut record, key_size) = decode_record_header(header_bytes, offset);
+    let value = bytes.split_off(header_len as usize);
+    record.key = bytes.split_off(RECORD_HEADER_SIZE);
+    if key_size as usize != key.len() || record.key != key || !record.checksum_matches(&value) {
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!("corrupted record at offset {offset}"),
+        ));
+    }
+    Ok((record, value))
 }
 
 pub struct FileCache {
-    inner: LruCache<u64, Arc<File>>,
+    inner: LruCache<u64, Arc<StdFile>>,
 }
 
 impl FileCache {
@@ -59,14 +469,20 @@ impl FileCache {
         }
     }
 
-    pub fn get(&mut self, file_id: u64) -> io::Result<Arc<File>> {
+    pub fn get(&mut self, file_id: u64) -> io::Result<Arc<StdFile>> {
         match self.inner.get(&file_id) {
             Some(file) => Ok(Arc::clone(file)),
             _ => Err(Error::other(format!("file not in cache: {file_id}"))),
         }
     }
 
-    pub fn insert(&mut self, file_id: u64, file: File) {
-        self.inner.put(file_id, Arc::new(file));
+    pub fn insert(&mut self, file_id: u64, file: StdFile) -> Arc<StdFile> {
+        let file = Arc::new(file);
+        self.inner.put(file_id, Arc::clone(&file));
+        file
+    }
+
+    pub fn remove(&mut self, file_id: u64) {
+        self.inner.pop(&file_id);
     }
 }
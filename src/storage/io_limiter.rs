@@ -0,0 +1,112 @@
+use std::sync::Mutex;
+
+use tokio::{
+    sync::Notify,
+    time::{Duration, Instant},
+};
+
+use super::merge::CancelToken;
+
+// 等待时最多睡这么久就重新检查一次取消标记
+const WAIT_SLICE: Duration = Duration::from_millis(50);
+
+/// 后台任务（merge、写 hint 文件、快照、校验）读写磁盘的令牌桶限速
+///
+/// 桶的容量是一秒的额度。一次申请超过余额时先记账再等待，所以大于容量的记录也能通过，
+/// 长期的平均速度不超过设定值。
+#[derive(Debug)]
+pub(crate) struct IoLimiter {
+    bucket: Mutex<Bucket>,
+    // 修改速度时唤醒正在等待的任务，让它们按新的速度重新计算
+    rate_changed: Notify,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // 每秒字节数，`None` 表示不限速
+    rate: Option<u64>,
+    // 可能为负，表示已经透支的字节数
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl IoLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.filter(|&rate| rate > 0);
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                last_refill: Instant::now(),
+            }),
+            rate_changed: Notify::new(),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.lock().rate
+    }
+
+    /// 修改速度立即生效，正在等待的任务也按新的速度计算；`None` 或 0 表示不限速
+    pub fn set_rate(&self, rate: Option<u64>) {
+        {
+            let mut bucket = self.lock();
+            bucket.refill();
+            bucket.rate = rate.filter(|&rate| rate > 0);
+            bucket.tokens = match bucket.rate {
+                Some(rate) => bucket.tokens.min(rate as f64),
+                None => 0.0,
+            };
+        }
+        self.rate_changed.notify_waiters();
+    }
+
+    /// 申请读写 `bytes` 字节，额度不够时等待；`cancel` 被取消时不再等待，直接返回
+    pub async fn acquire(&self, bytes: u64, cancel: Option<&CancelToken>) {
+        {
+            let mut bucket = self.lock();
+            if bucket.rate.is_none() {
+                return;
+            }
+            bucket.refill();
+            bucket.tokens -= bytes as f64;
+        }
+        loop {
+            // 先登记再检查余额，检查之后才改的速度也能唤醒这次等待
+            let rate_changed = self.rate_changed.notified();
+            let wait = {
+                let mut bucket = self.lock();
+                let Some(rate) = bucket.rate else {
+                    return;
+                };
+                bucket.refill();
+                if bucket.tokens >= 0.0 {
+                    return;
+                }
+                Duration::from_secs_f64(-bucket.tokens / rate as f64)
+            };
+            if cancel.is_some_and(CancelToken::is_cancelled) {
+                return;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(wait.min(WAIT_SLICE)) => {}
+                _ = rate_changed => {}
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        self.bucket.lock().expect("io limiter lock poisoned")
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last_refill = now;
+    }
+}
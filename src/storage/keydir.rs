@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use super::constants::TOMBSTONE_VALUE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub(crate) value_size: usize,
+    pub(crate) value_pos: u64,
+    pub(crate) file_id: u64,
+    pub(crate) seq: u64,
+    // 过期时间（毫秒），0 表示永不过期；过期的 entry 留在 keydir 里直到 merge
+    pub(crate) expire_at: u64,
+}
+
+impl Entry {
+    pub fn new(file_id: u64, value_pos: u64, value_size: usize, seq: u64, expire_at: u64) -> Self {
+        Entry {
+            file_id,
+            value_pos,
+            value_size,
+            seq,
+            expire_at,
+        }
+    }
+
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.expire_at != 0 && now_ms >= self.expire_at
+    }
+
+    /// 重建过程中 tombstone 也会暂存在 keydir 里，用来压过更旧的记录，重建结束后被清掉
+    pub fn is_tombstone(&self) -> bool {
+        self.value_size == TOMBSTONE_VALUE_SIZE as usize
+    }
+
+    /// 记录的新旧由单调递增的 seq 决定；
+    /// seq 相同（理论上不会出现）时再按 (file id, offset) 比较，保证结果确定
+    pub fn is_newer_than(&self, other: &Entry) -> bool {
+        (self.seq, self.file_id, self.value_pos) > (other.seq, other.file_id, other.value_pos)
+    }
+}
+
+/// 按 key 的字节序排列的持久化 B 树：clone 只复制根节点，之后的修改只复制改动路径上的节点
+///
+/// 有序是为了分页扫描：下一页从上一页的最后一个 key 开始做范围查询，不用每页都把 key 全取出来排序。
+pub type Partition = imbl::OrdMap<Vec<u8>, Entry>;
+
+/// 按 namespace 分区的 keydir，每个 namespace 一个独立的有序表
+///
+/// 删除一个 namespace 只需要移除它的分区。分区和未修改的部分在 clone 之间共享，
+/// 只读快照靠这个廉价地固定某一时刻的 keydir，之后的写入也不需要复制整个分区。
+#[derive(Debug, Clone, Default)]
+pub struct KeyDir {
+    partitions: HashMap<u32, Partition>,
+}
+
+impl KeyDir {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, namespace: u32, key: &[u8]) -> Option<&Entry> {
+        self.partitions.get(&namespace)?.get(key)
+    }
+
+    pub fn get_mut(&mut self, namespace: u32, key: &[u8]) -> Option<&mut Entry> {
+        self.partitions.get_mut(&namespace)?.get_mut(key)
+    }
+
+    pub fn insert(&mut self, namespace: u32, key: Vec<u8>, entry: Entry) -> Option<Entry> {
+        self.partitions
+            .entry(namespace)
+            .or_default()
+            .insert(key, entry)
+    }
+
+    pub fn remove(&mut self, namespace: u32, key: &[u8]) -> Option<Entry> {
+        let partition = self.partitions.get_mut(&namespace)?;
+        let entry = partition.remove(key);
+        if partition.is_empty() {
+            self.partitions.remove(&namespace);
+        }
+        entry
+    }
+
+    pub fn partition(&self, namespace: u32) -> Option<&Partition> {
+        self.partitions.get(&namespace)
+    }
+
+    pub fn remove_partition(&mut self, namespace: u32) -> Option<Partition> {
+        self.partitions.remove(&namespace)
+    }
+
+    /// 有 entry 的 namespace
+    pub fn namespaces(&self) -> impl Iterator<Item = u32> + '_ {
+        self.partitions.keys().copied()
+    }
+
+    /// 所有分区的 entry 总数
+    pub fn len(&self) -> usize {
+        self.partitions
+            .values()
+            .map(|partition| partition.len())
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Vec<u8>, &Entry)> {
+        self.partitions.iter().flat_map(|(&namespace, partition)| {
+            partition
+                .iter()
+                .map(move |(key, entry)| (namespace, key, entry))
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Entry> {
+        self.partitions
+            .values()
+            .flat_map(|partition| partition.values())
+    }
+
+    pub fn retain(&mut self, f: impl Fn(u32, &[u8], &Entry) -> bool) {
+        self.partitions.retain(|&namespace, partition| {
+            // OrdMap 没有 retain，先找出要删的 key 再逐个删除
+            let removed: Vec<Vec<u8>> = partition
+                .iter()
+                .filter(|(key, entry)| !f(namespace, key, entry))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in removed {
+                partition.remove(&key);
+            }
+            !partition.is_empty()
+        });
+    }
+}
+
+pub fn merge_entry(keydir: &mut KeyDir, namespace: u32, key: Vec<u8>, new_entry: Entry) {
+    match keydir.get_mut(namespace, &key) {
+        Some(entry) if !new_entry.is_newer_than(entry) => {}
+        Some(entry) => *entry = new_entry,
+        None => {
+            keydir.insert(namespace, key, new_entry);
+        }
+    }
+}
+
+/// 把 `entries` 合并进 `keydir`，结果与合并顺序无关
+pub fn merge_entries(keydir: &mut KeyDir, entries: KeyDir) {
+    for (namespace, partition) in entries.partitions {
+        for (key, new_entry) in partition {
+            merge_entry(keydir, namespace, key, new_entry);
+        }
+    }
+}
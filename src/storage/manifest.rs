@@ -0,0 +1,466 @@
+//! 记录哪些数据文件和 hint 文件属于存储
+//!
+//! `MANIFEST` 是追加写的日志，每条记录是一批文件的增删：active file 轮转时加入新的数据文件，
+//! merge 提交时在同一条记录里加入输出文件、移除输入文件，这条记录落盘就是 merge 的提交点。
+//! 打开时以清单为准：目录里不在清单中的文件被忽略，已经移除的文件被删掉。
+//! 没有清单（旧版本创建的存储）时从目录扫描重建；清单损坏时打开失败，
+//! 要用 `repair-manifest` 手动重建，避免悄悄丢掉已经提交的修改。
+//!
+//! 清单的读写都走 tokio::fs，调用方可能持有 active file 的锁，不能阻塞执行器。
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tracing::{info, warn};
+
+use super::{
+    constants::*,
+    file_util::{data_file_path, hint_file_path, merge_temp_path},
+};
+
+const MANIFEST_MAGIC: &[u8; 4] = b"BCKM";
+// 版本 2 的每个操作多了文件里最小的 seq 和 merge 必须保留的字节数；版本 1 的清单仍然可以读，
+// 这两项按 0 处理
+const MANIFEST_VERSION: u32 = 2;
+// magic + version
+const MANIFEST_HEADER_SIZE: usize = 4 + 4;
+// crc + payload_len
+const EDIT_HEADER_SIZE: usize = 4 + 4;
+// op + file_id + min_seq + kept_bytes
+const OP_SIZE: usize = 1 + 8 + 8 + 8;
+// 版本 1：op + file_id
+const OP_SIZE_V1: usize = 1 + 8;
+
+// 加入一个没有 hint 文件的数据文件（active file）
+const OP_ADD_DATA: u8 = 1;
+// 加入一个带 hint 文件的数据文件（merge 的输出）
+const OP_ADD_WITH_HINT: u8 = 2;
+// 移除数据文件和它的 hint 文件
+const OP_REMOVE: u8 = 3;
+
+/// 清单里记录的一个数据文件
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct FileMeta {
+    pub has_hint: bool,
+    // 文件里的记录的 seq 都不小于它；不知道的时候（旧清单、扫描目录得到的文件）是 0
+    pub min_seq: u64,
+    // merge 输出里因为可能遮住更老的版本而保留下来的 tombstone 的字节数，单独 merge 这个文件回收不了
+    pub kept_bytes: u64,
+}
+
+pub(crate) struct Manifest {
+    path: PathBuf,
+    file: File,
+    // 存活的数据文件 id -> 文件信息
+    files: BTreeMap<u64, FileMeta>,
+    // 清单里和目录里出现过的最大 id，新文件的 id 从它之后分配
+    max_id: Option<u64>,
+}
+
+impl Manifest {
+    /// 读取清单并整理目录：补完已提交的 merge 的改名，删除已移除的文件，最后把清单压缩成一条记录
+    pub async fn open(base_dir: &Path) -> io::Result<Self> {
+        let path = base_dir.join(MANIFEST_FILE_NAME);
+        let (files, removed) = match fs::read(&path).await {
+            Ok(bytes) => replay(&bytes).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "manifest {} is corrupted ({e}), run repair-manifest to rebuild it",
+                        path.display()
+                    ),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!(
+                    "No manifest in {}, building it from the directory",
+                    base_dir.display()
+                );
+                (scan_dir(base_dir).await?, BTreeSet::new())
+            }
+            Err(e) => return Err(e),
+        };
+
+        for (&file_id, meta) in &files {
+            let mut paths = vec![data_file_path(base_dir, file_id)];
+            if meta.has_hint {
+                paths.push(hint_file_path(base_dir, file_id));
+            }
+            // merge 提交之后、输出改名之前崩溃
+            for path in paths {
+                let temp = merge_temp_path(&path);
+                if !fs::try_exists(&path).await? && fs::try_exists(&temp).await? {
+                    info!("Completing committed merge output {}", path.display());
+                    fs::rename(&temp, &path).await?;
+                }
+            }
+        }
+        for &file_id in &removed {
+            for path in [
+                data_file_path(base_dir, file_id),
+                hint_file_path(base_dir, file_id),
+            ] {
+                match fs::remove_file(&path).await {
+                    Ok(()) => info!("Removed obsolete file {}", path.display()),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        let mut max_id = files.keys().chain(&removed).copied().max();
+        for (file_id, path) in list_store_files(base_dir).await? {
+            max_id = max_id.max(Some(file_id));
+            if !files.contains_key(&file_id) {
+                warn!(
+                    "Ignoring file not listed in the manifest: {}",
+                    path.display()
+                );
+            }
+        }
+
+        let file = write_compacted(&path, &files).await?;
+        Ok(Self {
+            path,
+            file,
+            files,
+            max_id,
+        })
+    }
+
+    /// 丢弃现有的清单，把目录里的数据文件和 hint 文件都登记进去，返回登记的数据文件数
+    pub async fn rebuild(base_dir: &Path) -> io::Result<usize> {
+        let files = scan_dir(base_dir).await?;
+        write_compacted(&base_dir.join(MANIFEST_FILE_NAME), &files).await?;
+        info!("Rebuilt manifest with {} data files", files.len());
+        Ok(files.len())
+    }
+
+    /// 在 `base_dir` 里写一个只包含 `files` 的新清单，用于备份
+    pub async fn create(base_dir: &Path, files: &[(u64, FileMeta)]) -> io::Result<()> {
+        let files = files.iter().copied().collect();
+        write_compacted(&base_dir.join(MANIFEST_FILE_NAME), &files).await?;
+        Ok(())
+    }
+
+    /// 增量备份的清单：先是上一次备份时的文件，再追加一条记录加入新文件、移除被 merge 掉的文件
+    pub async fn create_incremental(
+        base_dir: &Path,
+        previous: &BTreeMap<u64, FileMeta>,
+        added: &[(u64, FileMeta)],
+        removed: &[u64],
+    ) -> io::Result<()> {
+        let mut file = write_compacted(&base_dir.join(MANIFEST_FILE_NAME), previous).await?;
+        let ops: Vec<(u8, u64, FileMeta)> = added
+            .iter()
+            .map(|&(file_id, meta)| add_op(file_id, meta))
+            .chain(removed.iter().map(|&file_id| remove_op(file_id)))
+            .collect();
+        file.write_all(&encode_edit(&ops)).await?;
+        file.sync_data().await
+    }
+
+    /// 只读地解析 `path` 处的清单，返回存活的文件和被移除的文件
+    pub async fn read(path: &Path) -> io::Result<(BTreeMap<u64, FileMeta>, BTreeSet<u64>)> {
+        replay(&fs::read(path).await?).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("{} is not a valid manifest: {e}", path.display()),
+            )
+        })
+    }
+
+    /// 只读地取出 `base_dir` 里存活的文件：有清单时按清单，没有时扫描目录；不改动目录
+    pub async fn load_files(base_dir: &Path) -> io::Result<BTreeMap<u64, FileMeta>> {
+        let path = base_dir.join(MANIFEST_FILE_NAME);
+        match Self::read(&path).await {
+            Ok((files, _)) => Ok(files),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => scan_dir(base_dir).await,
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn files(&self) -> &BTreeMap<u64, FileMeta> {
+        &self.files
+    }
+
+    /// 存活的数据文件，按 id 升序
+    pub fn data_file_ids(&self) -> Vec<u64> {
+        self.files.keys().copied().collect()
+    }
+
+    pub fn hint_file_ids(&self) -> Vec<u64> {
+        self.files
+            .iter()
+            .filter(|(_, meta)| meta.has_hint)
+            .map(|(&file_id, _)| file_id)
+            .collect()
+    }
+
+    pub fn contains(&self, file_id: u64) -> bool {
+        self.files.contains_key(&file_id)
+    }
+
+    pub fn has_hint(&self, file_id: u64) -> bool {
+        self.files.get(&file_id).is_some_and(|meta| meta.has_hint)
+    }
+
+    pub fn file_meta(&self, file_id: u64) -> Option<FileMeta> {
+        self.files.get(&file_id).copied()
+    }
+
+    /// `excluded` 之外的文件里最老的记录的 seq 的下界，没有其他文件时返回 `None`
+    pub fn oldest_seq_excluding(&self, excluded: &[u64]) -> Option<u64> {
+        self.files
+            .iter()
+            .filter(|(file_id, _)| !excluded.contains(file_id))
+            .map(|(_, meta)| meta.min_seq)
+            .min()
+    }
+
+    /// 每个文件里 merge 必须保留的字节数，见 [`FileMeta::kept_bytes`]
+    pub fn kept_bytes(&self) -> HashMap<u64, u64> {
+        self.files
+            .iter()
+            .map(|(&file_id, meta)| (file_id, meta.kept_bytes))
+            .collect()
+    }
+
+    pub fn max_file_id(&self) -> Option<u64> {
+        self.max_id
+    }
+
+    /// 新的 active file 在创建之前登记，`min_seq` 是它的第一条记录将会用到的 seq
+    pub async fn add_data_file(&mut self, file_id: u64, min_seq: u64) -> io::Result<()> {
+        let meta = FileMeta {
+            min_seq,
+            ..Default::default()
+        };
+        self.append(&[add_op(file_id, meta)]).await?;
+        self.files.insert(file_id, meta);
+        self.max_id = self.max_id.max(Some(file_id));
+        Ok(())
+    }
+
+    /// 原子地加入 merge 的输出、移除输入
+    pub async fn commit_merge(
+        &mut self,
+        added: &[(u64, FileMeta)],
+        removed: &[u64],
+    ) -> io::Result<()> {
+        let ops: Vec<(u8, u64, FileMeta)> = added
+            .iter()
+            .map(|&(file_id, meta)| add_op(file_id, meta))
+            .chain(removed.iter().map(|&file_id| remove_op(file_id)))
+            .collect();
+        self.append(&ops).await?;
+        for &(file_id, meta) in added {
+            self.files.insert(file_id, meta);
+            self.max_id = self.max_id.max(Some(file_id));
+        }
+        for file_id in removed {
+            self.files.remove(file_id);
+        }
+        Ok(())
+    }
+
+    async fn append(&mut self, ops: &[(u8, u64, FileMeta)]) -> io::Result<()> {
+        self.file.write_all(&encode_edit(ops)).await?;
+        self.file.sync_data().await.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to sync {}: {e}", self.path.display()),
+            )
+        })
+    }
+}
+
+fn add_op(file_id: u64, meta: FileMeta) -> (u8, u64, FileMeta) {
+    let op = if meta.has_hint {
+        OP_ADD_WITH_HINT
+    } else {
+        OP_ADD_DATA
+    };
+    (op, file_id, meta)
+}
+
+fn remove_op(file_id: u64) -> (u8, u64, FileMeta) {
+    (OP_REMOVE, file_id, FileMeta::default())
+}
+
+fn encode_edit(ops: &[(u8, u64, FileMeta)]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(ops.len() * OP_SIZE);
+    for &(op, file_id, meta) in ops {
+        payload.push(op);
+        payload.extend_from_slice(&file_id.to_le_bytes());
+        payload.extend_from_slice(&meta.min_seq.to_le_bytes());
+        payload.extend_from_slice(&meta.kept_bytes.to_le_bytes());
+    }
+    let mut bytes = Vec::with_capacity(EDIT_HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+/// 返回存活的文件和被移除的文件
+///
+/// 只容忍最后一条记录不完整或校验失败：那是写到一半时崩溃留下的，它对应的文件还没有被创建，
+/// merge 也还没有提交。其他位置的损坏说明之后的修改也读不出来，返回 `InvalidData`。
+fn replay(bytes: &[u8]) -> io::Result<(BTreeMap<u64, FileMeta>, BTreeSet<u64>)> {
+    let header = bytes
+        .get(..MANIFEST_HEADER_SIZE)
+        .ok_or_else(|| invalid_data("truncated header".to_string()))?;
+    if &header[..4] != MANIFEST_MAGIC {
+        return Err(invalid_data("bad magic".to_string()));
+    }
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let op_size = match version {
+        1 => OP_SIZE_V1,
+        MANIFEST_VERSION => OP_SIZE,
+        _ => return Err(invalid_data(format!("unsupported version {version}"))),
+    };
+
+    let mut files = BTreeMap::new();
+    let mut removed = BTreeSet::new();
+    let mut offset = MANIFEST_HEADER_SIZE;
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        let torn = |what: &str| {
+            warn!(
+                "Ignoring {what} manifest record at offset {offset} ({} bytes)",
+                rest.len()
+            );
+        };
+        if rest.len() < EDIT_HEADER_SIZE {
+            torn("incomplete");
+            break;
+        }
+        let crc = u32::from_le_bytes(rest[..4].try_into().unwrap());
+        let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+        let Some(payload) = rest.get(EDIT_HEADER_SIZE..EDIT_HEADER_SIZE + len) else {
+            torn("incomplete");
+            break;
+        };
+        let is_last = EDIT_HEADER_SIZE + len == rest.len();
+        if crc32fast::hash(payload) != crc {
+            if is_last {
+                torn("corrupted final");
+                break;
+            }
+            return Err(invalid_data(format!(
+                "checksum mismatch in record at offset {offset}"
+            )));
+        }
+        if !len.is_multiple_of(op_size) {
+            return Err(invalid_data(format!(
+                "bad record length {len} at offset {offset}"
+            )));
+        }
+        for op in payload.chunks_exact(op_size) {
+            let file_id = u64::from_le_bytes(op[1..9].try_into().unwrap());
+            let field = |range: std::ops::Range<usize>| {
+                op.get(range)
+                    .map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+            };
+            match op[0] {
+                OP_ADD_DATA | OP_ADD_WITH_HINT => {
+                    let meta = FileMeta {
+                        has_hint: op[0] == OP_ADD_WITH_HINT,
+                        min_seq: field(9..17),
+                        kept_bytes: field(17..25),
+                    };
+                    files.insert(file_id, meta);
+                }
+                OP_REMOVE => {
+                    files.remove(&file_id);
+                    removed.insert(file_id);
+                }
+                op => {
+                    return Err(invalid_data(format!(
+                        "unknown op {op} in record at offset {offset}"
+                    )));
+                }
+            }
+        }
+        offset += EDIT_HEADER_SIZE + len;
+    }
+    // 被移除后又加入的 id 不会出现，id 不重用
+    removed.retain(|file_id| !files.contains_key(file_id));
+    Ok((files, removed))
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// 目录里的数据文件，以及有没有对应的 hint 文件；不读文件内容，最小 seq 按 0 处理
+async fn scan_dir(base_dir: &Path) -> io::Result<BTreeMap<u64, FileMeta>> {
+    let store_files = list_store_files(base_dir).await?;
+    let mut files = BTreeMap::new();
+    for (file_id, path) in &store_files {
+        if path.extension().and_then(|x| x.to_str()) == Some(DATA_FILE_EXTENSION) {
+            files.insert(*file_id, FileMeta::default());
+        }
+    }
+    for (file_id, path) in &store_files {
+        if path.extension().and_then(|x| x.to_str()) == Some(HINT_FILE_EXTENSION)
+            && let Some(meta) = files.get_mut(file_id)
+        {
+            meta.has_hint = true;
+        }
+    }
+    Ok(files)
+}
+
+/// 目录里名字是 `<id>.data` 或 `<id>.hint` 的文件
+async fn list_store_files(base_dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut store_files = Vec::new();
+    let mut entries = fs::read_dir(base_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let ext = path.extension().and_then(|x| x.to_str());
+        if ext != Some(DATA_FILE_EXTENSION) && ext != Some(HINT_FILE_EXTENSION) {
+            continue;
+        }
+        if let Some(file_id) = path
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.parse::<u64>().ok())
+        {
+            store_files.push((file_id, path));
+        }
+    }
+    Ok(store_files)
+}
+
+/// 把清单重写成只有一条记录，先写临时文件再 rename，返回可以继续追加的句柄
+async fn write_compacted(path: &Path, files: &BTreeMap<u64, FileMeta>) -> io::Result<File> {
+    let ops: Vec<(u8, u64, FileMeta)> = files
+        .iter()
+        .map(|(&file_id, &meta)| add_op(file_id, meta))
+        .collect();
+    let tmp_path = path.with_extension("tmp");
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MANIFEST_MAGIC);
+    bytes.extend_from_slice(&MANIFEST_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&encode_edit(&ops));
+    let mut file = File::create(&tmp_path).await?;
+    file.write_all(&bytes).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp_path, path).await?;
+    File::open(path.parent().unwrap_or(Path::new(".")))
+        .await?
+        .sync_all()
+        .await?;
+
+    OpenOptions::new().append(true).open(path).await
+}
@@ -0,0 +1,488 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncWriteExt, BufReader, BufWriter},
+};
+use tracing::{debug, warn};
+
+use super::{
+    constants::*,
+    file_util::{self, RecordMeta, data_file_path, hint_file_path},
+    io_limiter::IoLimiter,
+    keydir::{Entry, KeyDir},
+    manifest::FileMeta,
+};
+
+/// 一次 merge 的结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    pub input_files: usize,
+    pub output_files: usize,
+    pub live_records: u64,
+    pub dropped_records: u64,
+    // 没参与 merge 的文件里可能还有旧版本，因此保留下来的 tombstone
+    pub kept_tombstones: u64,
+    pub reclaimed_bytes: u64,
+}
+
+impl fmt::Display for MergeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "merged {} files into {}: {} live records kept, {} dropped, {} bytes reclaimed",
+            self.input_files,
+            self.output_files,
+            self.live_records,
+            self.dropped_records,
+            self.reclaimed_bytes
+        )
+    }
+}
+
+/// 用来停止正在进行的 merge，可以 clone 到别的任务里
+///
+/// 被取消的 merge 删除已经写出的输出，keydir 和输入文件保持原样，返回 `Interrupted` 错误。
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+struct OutputFile {
+    id: u64,
+    data_writer: BufWriter<File>,
+    hint_writer: BufWriter<File>,
+    pos: u64,
+    // 输出保留原来的 seq 但顺序不定，记下最小的登记到清单里
+    min_seq: u64,
+    kept_bytes: u64,
+}
+
+/// merge 的输出：和 active file 一样按大小切分，每个数据文件都带一个 hint 文件
+struct MergeOutput<'a, F> {
+    base_dir: PathBuf,
+    max_file_size: u64,
+    // 输入文件的读取和输出文件的写入都经过限速
+    limiter: &'a IoLimiter,
+    // 每次读写前和限速等待期间检查
+    cancel: &'a CancelToken,
+    allocate_id: F,
+    current: Option<OutputFile>,
+    finished: Vec<OutputFile>,
+}
+
+/// merge 成功后要应用到 keydir 上的修改
+#[derive(Default)]
+struct KeydirChanges {
+    // (namespace, key, 旧位置, 新位置)
+    moved: Vec<(u32, Vec<u8>, Entry, Entry)>,
+    expired: Vec<(u32, Vec<u8>, Entry)>,
+}
+
+impl<'a, F: FnMut() -> u64> MergeOutput<'a, F> {
+    fn new(
+        base_dir: &Path,
+        max_file_size: u64,
+        limiter: &'a IoLimiter,
+        cancel: &'a CancelToken,
+        allocate_id: F,
+    ) -> Self {
+        Self {
+            base_dir: base_dir.to_path_buf(),
+            max_file_size,
+            limiter,
+            cancel,
+            allocate_id,
+            current: None,
+            finished: Vec::new(),
+        }
+    }
+
+    /// 按限速等待读写 `bytes` 字节，merge 被取消时（包括等待期间）返回 `Interrupted`
+    async fn throttle(&self, bytes: u64) -> io::Result<()> {
+        self.limiter.acquire(bytes, Some(self.cancel)).await;
+        if self.cancel.is_cancelled() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "merge cancelled",
+            ));
+        }
+        Ok(())
+    }
+
+    /// 把一条存活的记录原样（保留 seq 和时间戳）写到输出文件，返回它的新位置
+    async fn write(&mut self, record: &RecordMeta, value: &[u8]) -> io::Result<Entry> {
+        let record_size = (RECORD_HEADER_SIZE + record.key.len() + value.len()) as u64;
+        self.throttle(record_size + (HINT_HEADER_SIZE + record.key.len()) as u64)
+            .await?;
+
+        let should_rotate = self.current.as_ref().is_some_and(|output| {
+            output.pos > FILE_HEADER_SIZE && output.pos + record_size >= self.max_file_size
+        });
+        if should_rotate && let Some(output) = self.current.take() {
+            self.finished.push(output);
+        }
+
+        let output = match &mut self.current {
+            Some(output) => output,
+            None => {
+                let id = (self.allocate_id)();
+                // 提交之前用临时文件名，崩溃后残留的输出不会被当成数据文件
+                let data_writer = file_util::new_merge_temp_writer(
+                    &data_file_path(&self.base_dir, id),
+                    DATA_FILE_MAGIC,
+                    FILE_WRITER_BUFFER_SIZE,
+                )
+                .await?;
+                let hint_writer = file_util::new_merge_temp_writer(
+                    &hint_file_path(&self.base_dir, id),
+                    HINT_FILE_MAGIC,
+                    FILE_WRITER_BUFFER_SIZE,
+                )
+                .await?;
+                self.current.insert(OutputFile {
+                    id,
+                    data_writer,
+                    hint_writer,
+                    pos: FILE_HEADER_SIZE,
+                    min_seq: record.seq,
+                    kept_bytes: 0,
+                })
+            }
+        };
+
+        output.min_seq = output.min_seq.min(record.seq);
+        let header = file_util::encode_record_header(
+            record.seq,
+            record.timestamp,
+            record.attrs(),
+            &record.key,
+            record.value_size,
+            value,
+        );
+        output.data_writer.write_all(&header).await?;
+        output.data_writer.write_all(&record.key).await?;
+        output.data_writer.write_all(value).await?;
+
+        let value_pos = output.pos + RECORD_HEADER_SIZE as u64 + record.key.len() as u64;
+        output.pos += record_size;
+
+        let hint_header = file_util::encode_hint_header(
+            record.seq,
+            record.timestamp,
+            record.expire_at,
+            record.namespace,
+            &record.key,
+            record.value_size,
+            value_pos,
+        );
+        output.hint_writer.write_all(&hint_header).await?;
+        output.hint_writer.write_all(&record.key).await?;
+
+        Ok(Entry::new(
+            output.id,
+            value_pos,
+            record.value_size as usize,
+            record.seq,
+            record.expire_at,
+        ))
+    }
+
+    /// 刚写出的记录是保留下来的 tombstone
+    fn add_kept(&mut self, bytes: u64) {
+        if let Some(output) = &mut self.current {
+            output.kept_bytes += bytes;
+        }
+    }
+
+    /// 刷盘并封存所有输出文件，返回输出文件和它们在清单里的信息，以及输出数据文件的总字节数
+    async fn finish(&mut self) -> io::Result<(Vec<(u64, FileMeta)>, u64)> {
+        self.finished.extend(self.current.take());
+
+        let mut bytes = 0;
+        for output in &mut self.finished {
+            for writer in [&mut output.data_writer, &mut output.hint_writer] {
+                writer.flush().await?;
+                writer.get_ref().sync_all().await?;
+                file_util::set_readonly(writer.get_ref()).await?;
+            }
+            bytes += output.pos;
+        }
+        Ok((
+            self.finished
+                .iter()
+                .map(|output| {
+                    let meta = FileMeta {
+                        has_hint: true,
+                        min_seq: output.min_seq,
+                        kept_bytes: output.kept_bytes,
+                    };
+                    (output.id, meta)
+                })
+                .collect(),
+            bytes,
+        ))
+    }
+
+    /// merge 失败或被取消时删掉已经写出的输出文件
+    async fn abort(mut self) {
+        self.finished.extend(self.current.take());
+        let output_ids: Vec<u64> = self.finished.iter().map(|output| output.id).collect();
+        remove_outputs(&self.base_dir, &output_ids).await;
+    }
+}
+
+/// 输出已经落盘、还没有提交的 merge
+///
+/// 调用方先在清单里提交（加入输出、移除输入），再 [`install`](Self::install)；提交失败时 [`abort`](Self::abort)。
+pub(crate) struct PreparedMerge {
+    base_dir: PathBuf,
+    outputs: Vec<(u64, FileMeta)>,
+    changes: KeydirChanges,
+    report: MergeReport,
+}
+
+impl PreparedMerge {
+    pub fn outputs(&self) -> &[(u64, FileMeta)] {
+        &self.outputs
+    }
+
+    fn output_ids(&self) -> Vec<u64> {
+        self.outputs.iter().map(|&(file_id, _)| file_id).collect()
+    }
+
+    /// 把输出改成正式的文件名，再把 keydir 指向新位置；期间被覆盖或删除的 key 保持不变
+    ///
+    /// 先改数据文件再改 hint 文件。改名完成之前崩溃的话，打开时按清单补完。
+    pub async fn install(self, keydir: &RwLock<KeyDir>) -> io::Result<MergeReport> {
+        for file_id in self.output_ids() {
+            for path in [
+                data_file_path(&self.base_dir, file_id),
+                hint_file_path(&self.base_dir, file_id),
+            ] {
+                fs::rename(file_util::merge_temp_path(&path), &path).await?;
+            }
+        }
+        file_util::sync_dir(&self.base_dir).await?;
+
+        let mut keydir = keydir.write().expect("keydir lock poisoned");
+        for (namespace, key, old_entry, new_entry) in self.changes.moved {
+            if let Some(entry) = keydir.get_mut(namespace, &key)
+                && *entry == old_entry
+            {
+                *entry = new_entry;
+            }
+        }
+        // 过期的记录没有被搬过去，对应的 key 也从 keydir 中移除
+        for (namespace, key, old_entry) in self.changes.expired {
+            if keydir.get(namespace, &key) == Some(&old_entry) {
+                keydir.remove(namespace, &key);
+            }
+        }
+
+        debug!("Merge finished: {:?}", self.report);
+        Ok(self.report)
+    }
+
+    pub async fn abort(self) {
+        remove_outputs(&self.base_dir, &self.output_ids()).await;
+    }
+}
+
+async fn remove_outputs(base_dir: &Path, output_ids: &[u64]) {
+    for &file_id in output_ids {
+        for path in [
+            data_file_path(base_dir, file_id),
+            hint_file_path(base_dir, file_id),
+        ] {
+            if let Err(e) = remove_if_exists(&file_util::merge_temp_path(&path)).await {
+                warn!("Failed to remove merge output {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+/// 把 `input_ids` 中仍被 keydir 引用的记录搬到新的临时文件里
+///
+/// keydir 和输入文件都不会被修改，由调用方提交之后处理。
+/// 被覆盖的记录总是可以丢弃；已删除的 namespace 的分区不在 keydir 里，它的记录也在这里被回收。
+/// tombstone 和过期的记录只有在没参与 merge 的文件里可能还有同一个 key 的旧版本时才原样保留，
+/// 否则重建时旧版本会复活。`oldest_outside` 是这些文件里最老的记录的 seq 的下界（清单里记录的
+/// 每个文件的最小 seq），比它还老的记录不可能遮住什么，可以丢弃。
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn merge_files(
+    base_dir: &Path,
+    input_ids: &[u64],
+    oldest_outside: Option<u64>,
+    keydir: &RwLock<KeyDir>,
+    max_file_size: u64,
+    now_ms: u64,
+    limiter: &IoLimiter,
+    cancel: &CancelToken,
+    allocate_id: impl FnMut() -> u64,
+) -> io::Result<PreparedMerge> {
+    let mut output = MergeOutput::new(base_dir, max_file_size, limiter, cancel, allocate_id);
+    let mut changes = KeydirChanges::default();
+    let mut report = MergeReport {
+        input_files: input_ids.len(),
+        ..Default::default()
+    };
+    let mut input_bytes = 0;
+
+    for &file_id in input_ids {
+        match copy_live_records(
+            file_id,
+            oldest_outside,
+            keydir,
+            now_ms,
+            &mut output,
+            &mut changes,
+            &mut report,
+        )
+        .await
+        {
+            Ok(bytes) => input_bytes += bytes,
+            Err(e) => {
+                output.abort().await;
+                return Err(e);
+            }
+        }
+    }
+
+    let (outputs, output_bytes) = match output.finish().await {
+        Ok(finished) => finished,
+        Err(e) => {
+            output.abort().await;
+            return Err(e);
+        }
+    };
+    report.output_files = outputs.len();
+    report.reclaimed_bytes = input_bytes.saturating_sub(output_bytes);
+
+    Ok(PreparedMerge {
+        base_dir: base_dir.to_path_buf(),
+        outputs,
+        changes,
+        report,
+    })
+}
+
+async fn copy_live_records(
+    file_id: u64,
+    oldest_outside: Option<u64>,
+    keydir: &RwLock<KeyDir>,
+    now_ms: u64,
+    output: &mut MergeOutput<'_, impl FnMut() -> u64>,
+    changes: &mut KeydirChanges,
+    report: &mut MergeReport,
+) -> io::Result<u64> {
+    let path = data_file_path(&output.base_dir, file_id);
+    let file = OpenOptions::new().read(true).open(&path).await?;
+    let file_len = file.metadata().await?.len();
+    let mut reader = BufReader::with_capacity(FILE_READER_BUFFER_SIZE, file);
+    if !file_util::read_file_header(&mut reader, DATA_FILE_MAGIC, &path).await? {
+        return Ok(file_len);
+    }
+
+    let mut offset = FILE_HEADER_SIZE;
+    while let Some((record, value)) =
+        file_util::read_data_record_with_value(&mut reader, offset).await?
+    {
+        let record_offset = offset;
+        offset = record.end_pos();
+        output.throttle(offset - record_offset).await?;
+
+        let current = keydir
+            .read()
+            .expect("keydir lock poisoned")
+            .get(record.namespace, &record.key)
+            .copied();
+        let live =
+            current.filter(|entry| entry.file_id == file_id && entry.value_pos == record.value_pos);
+        let may_shadow = oldest_outside.is_some_and(|seq| seq < record.seq);
+        // key 之后又被写入的话，tombstone 已经没用了
+        let keep_tombstone = may_shadow && record.is_tombstone() && current.is_none();
+        let old_entry = match live {
+            Some(old_entry) => old_entry,
+            None if keep_tombstone => {
+                check_record(&record, &value, file_id, record_offset)?;
+                output.write(&record, &value).await?;
+                output.add_kept(offset - record_offset);
+                report.kept_tombstones += 1;
+                continue;
+            }
+            None => {
+                report.dropped_records += 1;
+                continue;
+            }
+        };
+        if old_entry.is_expired(now_ms) && !may_shadow {
+            changes
+                .expired
+                .push((record.namespace, record.key, old_entry));
+            report.dropped_records += 1;
+            continue;
+        }
+
+        check_record(&record, &value, file_id, record_offset)?;
+
+        let new_entry = output.write(&record, &value).await?;
+        changes
+            .moved
+            .push((record.namespace, record.key, old_entry, new_entry));
+        report.live_records += 1;
+    }
+
+    Ok(file_len)
+}
+
+fn check_record(record: &RecordMeta, value: &[u8], file_id: u64, offset: u64) -> io::Result<()> {
+    if !record.checksum_matches(value) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("checksum mismatch in file {file_id} at offset {offset}"),
+        ));
+    }
+    Ok(())
+}
+
+/// 删掉上次没有提交的 merge 留下的临时文件；已提交的输出在打开清单时已经改好名了
+pub(crate) async fn recover(base_dir: &Path) -> io::Result<()> {
+    let mut entries = fs::read_dir(base_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|x| x.to_str()) == Some(MERGE_TEMP_EXTENSION) {
+            warn!("Removing unfinished merge output {}", path.display());
+            remove_if_exists(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,164 @@
+//! 按碎片程度自动 merge
+//!
+//! 策略由 [`StorageConfig::merge_policy`](super::config::StorageConfig::merge_policy) 给出。
+//! [`MergeScheduler`] 定期检查每个已封存文件的垃圾占比，挑出候选文件，满足阈值并且在
+//! 允许的时间窗口内时只 merge 这些文件。
+
+use std::{
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info};
+
+use super::{bitcask_impl::BitCaskHandle, config::StorageConfig, stats::FileStats};
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergePolicy {
+    // 能回收的垃圾字节占比达到这个值的已封存文件成为候选
+    pub min_dead_ratio: f64,
+    // 所有候选文件能回收的垃圾字节加起来至少这么多才 merge
+    pub min_dead_bytes: u64,
+    // 候选文件至少这么多个才 merge
+    pub min_files: usize,
+    // 只在这个时间窗口内 merge，`None` 表示任何时候都可以
+    pub window: Option<MergeWindow>,
+    pub check_interval: Duration,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        Self {
+            min_dead_ratio: 0.5,
+            min_dead_bytes: 0,
+            min_files: 1,
+            window: None,
+            check_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl MergePolicy {
+    /// 从 `files` 中挑出要 merge 的文件，不满足阈值时返回空
+    ///
+    /// 只看 merge 能回收的垃圾：保留下来的 tombstone 再 merge 一次也还在，不算。
+    pub fn select(&self, files: &[FileStats], active_file_id: u64) -> Vec<u64> {
+        let candidates: Vec<&FileStats> = files
+            .iter()
+            .filter(|file| file.file_id != active_file_id && file.reclaimable_bytes() > 0)
+            .filter(|file| file.reclaimable_ratio() >= self.min_dead_ratio)
+            .collect();
+        let dead_bytes: u64 = candidates.iter().map(|file| file.reclaimable_bytes()).sum();
+        if candidates.is_empty()
+            || candidates.len() < self.min_files
+            || dead_bytes < self.min_dead_bytes
+        {
+            return Vec::new();
+        }
+        candidates.iter().map(|file| file.file_id).collect()
+    }
+}
+
+/// 一天中允许 merge 的时段（UTC），`start > end` 表示跨过午夜
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergeWindow {
+    // 距离 UTC 零点的偏移
+    pub start: Duration,
+    pub end: Duration,
+}
+
+impl MergeWindow {
+    pub fn contains(&self, now_ms: u64) -> bool {
+        let time_of_day = now_ms % DAY_MS;
+        let start = self.start.as_millis() as u64 % DAY_MS;
+        let end = self.end.as_millis() as u64 % DAY_MS;
+        if start <= end {
+            start <= time_of_day && time_of_day < end
+        } else {
+            time_of_day >= start || time_of_day < end
+        }
+    }
+}
+
+/// 后台自动 merge 的任务，drop 时停止
+pub struct MergeScheduler {
+    paused: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl MergeScheduler {
+    /// 按 `handle` 的配置启动后台任务；没有配置 merge 策略时返回 `None`
+    ///
+    /// 任务只持有 handle 的弱引用，handle 被释放后自动退出。
+    pub fn spawn<C: StorageConfig>(handle: &Arc<BitCaskHandle<C>>) -> Option<Self> {
+        let policy = handle.config().merge_policy()?;
+        let paused = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn(Self::run(Arc::downgrade(handle), policy, paused.clone()));
+        Some(Self { paused, task })
+    }
+
+    /// 暂停自动 merge；正在进行的 merge 会继续做完
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// 停止任务并等它退出，之后任务不再持有 handle
+    pub async fn stop(mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+
+    async fn run<C: StorageConfig>(
+        handle: Weak<BitCaskHandle<C>>,
+        policy: MergePolicy,
+        paused: Arc<AtomicBool>,
+    ) {
+        let mut ticker = tokio::time::interval(policy.check_interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let Some(handle) = handle.upgrade() else {
+                break;
+            };
+            if paused.load(Ordering::SeqCst) {
+                continue;
+            }
+            if let Some(window) = &policy.window
+                && !window.contains(handle.clock().now_ms())
+            {
+                continue;
+            }
+
+            let active_file_id = handle.active_file_id().await;
+            let candidates = policy.select(&handle.file_stats(), active_file_id);
+            if candidates.is_empty() {
+                debug!("No files need merging");
+                continue;
+            }
+            info!("Scheduled merge of {} files", candidates.len());
+            if let Err(e) = handle.merge_files(&candidates).await {
+                error!("Scheduled merge failed: {e}");
+            }
+        }
+    }
+}
+
+impl Drop for MergeScheduler {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
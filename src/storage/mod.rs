@@ -1,8 +1,27 @@
 mod active_file;
 mod constants;
 mod file_util;
+mod io_limiter;
+mod keydir;
+mod manifest;
+mod read_files;
+mod snapshot;
+mod usage;
 
 pub mod bitcask_impl;
+pub mod checkpoint;
 pub mod config;
+pub mod merge;
+pub mod merge_scheduler;
+pub mod namespace;
+pub mod options;
+pub mod read_only;
+pub mod read_view;
+pub mod rebuild;
+pub mod sharded;
+pub mod stats;
+pub mod verify;
 
-use active_file::WriteRecordResult;
+pub(crate) use constants::MAX_VALUE_LEN;
+
+use active_file::{RecordAttrs, WriteRecordResult};
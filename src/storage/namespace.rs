@@ -0,0 +1,229 @@
+//! 同一个存储里的多个逻辑表
+//!
+//! 每个 namespace 有自己的 keydir 分区，记录里带着 namespace id，和默认 namespace
+//! 共用 active file 和 merge。名字和 id 的对应关系（注册表）以普通记录的形式保存在
+//! 内部的系统 namespace 里：key 是 id，value 是名字，flags 标记是否已被删除。
+//! 注册表记录不会被删除，所以 id 不会被重用。
+
+use std::{
+    collections::{HashMap, hash_map},
+    fs::File as StdFile,
+    io,
+    path::Path,
+    sync::Arc,
+};
+
+use super::{
+    bitcask_impl::BitCaskHandle,
+    config::StorageConfig,
+    constants::*,
+    file_util::{self, data_file_path},
+    keydir::KeyDir,
+    options::{PutOptions, PutOutcome, ValueMeta},
+    read_view::Snapshot,
+};
+
+// 注册表记录的 flags：namespace 已被删除
+pub(crate) const NAMESPACE_DROPPED: u32 = 1;
+const MAX_NAMESPACE_NAME_LEN: usize = 255;
+
+/// 一个 namespace 的概况
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NamespaceStats {
+    pub name: String,
+    pub key_count: usize,
+    // 存活的 value 的总字节数
+    pub value_bytes: u64,
+}
+
+/// [`BitCaskHandle::namespace`] 返回的视图，方法和 handle 上的同名方法一样，只是作用于这个 namespace
+///
+/// namespace 被删除后，读返回空，写返回错误。
+pub struct Namespace<'a, C: StorageConfig> {
+    handle: &'a BitCaskHandle<C>,
+    id: u32,
+    name: String,
+}
+
+impl<'a, C: StorageConfig> Namespace<'a, C> {
+    pub(crate) fn new(handle: &'a BitCaskHandle<C>, id: u32, name: &str) -> Self {
+        Self {
+            handle,
+            id,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.handle.get_in(self.id, key).await
+    }
+
+    pub async fn get_with_meta(&self, key: &[u8]) -> io::Result<Option<(Vec<u8>, ValueMeta)>> {
+        self.handle.get_with_meta_in(self.id, key).await
+    }
+
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.put_with(key, value, PutOptions::default()).await?;
+        Ok(())
+    }
+
+    pub async fn put_with(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        options: PutOptions,
+    ) -> io::Result<PutOutcome> {
+        self.handle.put_with_in(self.id, key, value, options).await
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> io::Result<bool> {
+        self.handle.delete_in(self.id, key).await
+    }
+
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.handle.keys_in(self.id)
+    }
+
+    pub async fn scan_prefix(&self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.handle.scan_prefix_in(self.id, prefix).await
+    }
+
+    pub fn len(&self) -> usize {
+        self.handle.partition_stats(self.id).0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.handle.live_entry(self.id, key).is_some()
+    }
+
+    /// 这个 namespace 在此刻的只读视图，见 [`BitCaskHandle::snapshot`]
+    pub fn snapshot(&self) -> Snapshot {
+        self.handle.snapshot_in(self.id)
+    }
+
+    pub fn stats(&self) -> NamespaceStats {
+        let (key_count, value_bytes) = self.handle.partition_stats(self.id);
+        NamespaceStats {
+            name: self.name.clone(),
+            key_count,
+            value_bytes,
+        }
+    }
+}
+
+/// 存活的 namespace 的名字到 id 的映射
+#[derive(Debug)]
+pub(crate) struct NamespaceRegistry {
+    ids: HashMap<String, u32>,
+    next_id: u32,
+}
+
+impl NamespaceRegistry {
+    pub fn id(&self, name: &str) -> Option<u32> {
+        self.ids.get(name).copied()
+    }
+
+    /// 默认 namespace 总是存在
+    pub fn is_live(&self, id: u32) -> bool {
+        id == DEFAULT_NAMESPACE || self.ids.values().any(|&live_id| live_id == id)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.ids.keys().cloned().collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn next_id(&self) -> io::Result<u32> {
+        if self.next_id == SYSTEM_NAMESPACE {
+            return Err(io::Error::other("namespace ids exhausted"));
+        }
+        Ok(self.next_id)
+    }
+
+    pub fn register(&mut self, name: &str, id: u32) {
+        self.ids.insert(name.to_string(), id);
+        self.next_id = self.next_id.max(id + 1);
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Option<u32> {
+        self.ids.remove(name)
+    }
+}
+
+pub(crate) fn validate_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name.len() > MAX_NAMESPACE_NAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("namespace name must be 1 to {MAX_NAMESPACE_NAME_LEN} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+/// 从系统 namespace 的记录里读出注册表，并从 keydir 中去掉已删除的 namespace 的分区
+pub(crate) async fn load_registry(
+    base_dir: &Path,
+    keydir: &mut KeyDir,
+) -> io::Result<NamespaceRegistry> {
+    let mut registry = NamespaceRegistry {
+        ids: HashMap::new(),
+        next_id: DEFAULT_NAMESPACE + 1,
+    };
+    let records: Vec<_> = keydir
+        .partition(SYSTEM_NAMESPACE)
+        .into_iter()
+        .flatten()
+        .map(|(key, entry)| (key.clone(), *entry))
+        .collect();
+
+    let mut files: HashMap<u64, Arc<StdFile>> = HashMap::new();
+    for (key, entry) in records {
+        let id = key
+            .as_slice()
+            .try_into()
+            .map(u32::from_le_bytes)
+            .map_err(|_| invalid_data("invalid namespace id in registry"))?;
+        let file = match files.entry(entry.file_id) {
+            hash_map::Entry::Occupied(file) => file.get().clone(),
+            hash_map::Entry::Vacant(slot) => slot
+                .insert(Arc::new(StdFile::open(data_file_path(
+                    base_dir,
+                    entry.file_id,
+                ))?))
+                .clone(),
+        };
+        let (record, name) =
+            file_util::read_record_at(file, &key, entry.value_pos, entry.value_size).await?;
+        let name = String::from_utf8(name)
+            .map_err(|_| invalid_data("invalid namespace name in registry"))?;
+
+        registry.next_id = registry.next_id.max(id + 1);
+        if record.flags & NAMESPACE_DROPPED == 0 {
+            registry.ids.insert(name, id);
+        }
+    }
+
+    // 已删除的 namespace 的记录在 merge 之前还会被重建出来
+    let dropped: Vec<u32> = keydir
+        .namespaces()
+        .filter(|&id| id != SYSTEM_NAMESPACE && !registry.is_live(id))
+        .collect();
+    for id in dropped {
+        keydir.remove_partition(id);
+    }
+
+    Ok(registry)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,40 @@
+/// [`BitCaskHandle::put_with`](crate::BitCaskHandle::put_with) 的写入条件
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PutCondition {
+    #[default]
+    Always,
+    // key 不存在（或已过期）时才写入
+    Absent,
+    // key 存在时才写入
+    Present,
+    // key 当前的版本号等于给定值时才写入（compare-and-swap）
+    Version(u64),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PutOptions {
+    // 和 value 一起保存的不透明标志位，读取时原样返回
+    pub flags: u32,
+    // 过期时间（毫秒，和 `StorageConfig::clock()` 同一个时间基准），0 表示永不过期
+    pub expire_at_ms: u64,
+    pub condition: PutCondition,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PutOutcome {
+    Stored { version: u64 },
+    // 条件不满足
+    NotStored,
+    // `PutCondition::Version` 要求的 key 不存在
+    NotFound,
+}
+
+/// value 的元信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueMeta {
+    pub flags: u32,
+    // 记录的序列号，每次写入都会变化，可以用作 CAS 的版本号
+    pub version: u64,
+    pub timestamp_ms: u64,
+    pub expire_at_ms: u64,
+}
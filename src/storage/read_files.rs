@@ -0,0 +1,215 @@
+//! 读路径上打开的数据文件，以及 merge 之后旧文件的延迟删除
+//!
+//! 读者在查 keydir 之前 [`pin`](ReadFiles::pin) 当前的 epoch，读完再释放。merge 更新完 keydir 后
+//! 把输入文件 [`retire`](ReadFiles::retire) 到当前 epoch 并进入下一个 epoch：之后开始的读只会看到
+//! 新的位置，之前开始的读可能还拿着旧的 entry，要等它们都结束，旧文件才从缓存里移除并删除。
+//!
+//! 最后一个读结束的地方（guard 的 drop）通常在 runtime 的工作线程上，删除文件交给阻塞线程池去做。
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tokio::{runtime::Handle, task::JoinHandle};
+use tracing::{debug, warn};
+
+use super::{
+    file_util::{self, FileCache, data_file_path, hint_file_path},
+    keydir::Entry,
+    options::ValueMeta,
+};
+
+pub(crate) struct ReadFiles {
+    base_dir: PathBuf,
+    cache: Mutex<FileCache>,
+    epochs: Mutex<Epochs>,
+    // 打开存储时所在的 runtime，删除交给它的阻塞线程池：
+    // 最后一个读可能在别的 runtime 上结束，那个 runtime 关闭时会丢掉还没开始的删除
+    runtime: Option<Handle>,
+}
+
+#[derive(Default)]
+struct Epochs {
+    current: u64,
+    // epoch -> 在这个 epoch 开始、还没结束的读
+    readers: BTreeMap<u64, usize>,
+    // (retire 时的 epoch, 文件 id)
+    retired: Vec<(u64, Vec<u64>)>,
+}
+
+/// 一次读的登记，持有期间它可能引用的文件都不会被删除
+pub(crate) struct ReadGuard {
+    files: Arc<ReadFiles>,
+    epoch: u64,
+}
+
+impl ReadFiles {
+    pub fn new(base_dir: PathBuf, capacity: usize) -> Self {
+        Self {
+            base_dir,
+            cache: Mutex::new(FileCache::new(capacity)),
+            epochs: Mutex::new(Epochs::default()),
+            runtime: Handle::try_current().ok(),
+        }
+    }
+
+    pub fn pin(self: &Arc<Self>) -> ReadGuard {
+        let mut epochs = self.lock_epochs();
+        let epoch = epochs.current;
+        *epochs.readers.entry(epoch).or_default() += 1;
+        ReadGuard {
+            files: self.clone(),
+            epoch,
+        }
+    }
+
+    /// keydir 已经不再引用 `file_ids` 之后调用，没有更早的读时立即删除，等删除完成再返回
+    pub async fn retire(&self, file_ids: &[u64]) {
+        if file_ids.is_empty() {
+            return;
+        }
+        let reclaimable = {
+            let mut epochs = self.lock_epochs();
+            let epoch = epochs.current;
+            epochs.retired.push((epoch, file_ids.to_vec()));
+            epochs.current += 1;
+            epochs.take_reclaimable()
+        };
+        if let Some(removal) = self.remove_files(reclaimable) {
+            let _ = removal.await;
+        }
+    }
+
+    fn unpin(&self, epoch: u64) {
+        let reclaimable = {
+            let mut epochs = self.lock_epochs();
+            if let Some(count) = epochs.readers.get_mut(&epoch) {
+                *count -= 1;
+                if *count == 0 {
+                    epochs.readers.remove(&epoch);
+                }
+            }
+            epochs.take_reclaimable()
+        };
+        // 在 drop 里调用，不等删除完成
+        self.remove_files(reclaimable);
+    }
+
+    /// 从缓存里移除后在阻塞线程池上删除；不是在 runtime 里打开的存储就地删除
+    fn remove_files(&self, file_ids: Vec<u64>) -> Option<JoinHandle<()>> {
+        if file_ids.is_empty() {
+            return None;
+        }
+        {
+            let mut cache = self.cache.lock().expect("file cache lock poisoned");
+            for &file_id in &file_ids {
+                cache.remove(file_id);
+            }
+        }
+        let base_dir = self.base_dir.clone();
+        match &self.runtime {
+            Some(runtime) => {
+                Some(runtime.spawn_blocking(move || delete_files(&base_dir, &file_ids)))
+            }
+            None => {
+                delete_files(&base_dir, &file_ids);
+                None
+            }
+        }
+    }
+
+    fn lock_epochs(&self) -> MutexGuard<'_, Epochs> {
+        self.epochs.lock().expect("epoch lock poisoned")
+    }
+}
+
+fn delete_files(base_dir: &Path, file_ids: &[u64]) {
+    for &file_id in file_ids {
+        for path in [
+            data_file_path(base_dir, file_id),
+            hint_file_path(base_dir, file_id),
+        ] {
+            match fs::remove_file(&path) {
+                Ok(()) => debug!("Removed obsolete file {}", path.display()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                // 清单里已经移除了，下次打开时会再删
+                Err(e) => warn!("Failed to remove {}: {e}", path.display()),
+            }
+        }
+    }
+}
+
+impl Drop for ReadFiles {
+    // handle 和所有只读视图都被释放了，已经没有读了
+    fn drop(&mut self) {
+        let retired = std::mem::take(&mut self.lock_epochs().retired);
+        self.remove_files(retired.into_iter().flat_map(|(_, ids)| ids).collect());
+    }
+}
+
+impl Epochs {
+    /// 比所有还在进行的读都早 retire 的文件
+    fn take_reclaimable(&mut self) -> Vec<u64> {
+        let oldest_reader = self.readers.keys().next().copied().unwrap_or(u64::MAX);
+        let mut reclaimable = Vec::new();
+        self.retired.retain(|(epoch, file_ids)| {
+            if *epoch < oldest_reader {
+                reclaimable.extend_from_slice(file_ids);
+                false
+            } else {
+                true
+            }
+        });
+        reclaimable
+    }
+}
+
+impl ReadGuard {
+    pub async fn open(&self, file_id: u64) -> io::Result<Arc<File>> {
+        if let Ok(file) = self.lock_cache().get(file_id) {
+            return Ok(file);
+        }
+        // 打开文件不能持有缓存的锁；两个读同时打开同一个文件时后插入的那个留在缓存里
+        let path = data_file_path(&self.files.base_dir, file_id);
+        let file = tokio::fs::File::open(path).await?.into_std().await;
+        Ok(self.lock_cache().insert(file_id, file))
+    }
+
+    fn lock_cache(&self) -> MutexGuard<'_, FileCache> {
+        self.files.cache.lock().expect("file cache lock poisoned")
+    }
+
+    /// 读 `entry` 指向的 value
+    pub async fn read_value(&self, entry: &Entry) -> io::Result<Vec<u8>> {
+        let file = self.open(entry.file_id).await?;
+        file_util::read_value_at(file, entry.value_pos, entry.value_size).await
+    }
+
+    /// 读 `entry` 指向的整条记录，校验 key 和校验和，返回 value 和记录的元信息
+    pub async fn read_value_with_meta(
+        &self,
+        key: &[u8],
+        entry: &Entry,
+    ) -> io::Result<(Vec<u8>, ValueMeta)> {
+        let file = self.open(entry.file_id).await?;
+        let (record, value) =
+            file_util::read_record_at(file, key, entry.value_pos, entry.value_size).await?;
+        let meta = ValueMeta {
+            flags: record.flags,
+            version: record.seq,
+            timestamp_ms: record.timestamp,
+            expire_at_ms: record.expire_at,
+        };
+        Ok((value, meta))
+    }
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        self.files.unpin(self.epoch);
+    }
+}
@@ -0,0 +1,130 @@
+//! 只读地打开的存储
+//!
+//! [`BitCaskHandle::open_read_only`](super::bitcask_impl::BitCaskHandle::open_read_only) 只读取清单、
+//! keydir 快照和数据文件：不创建 active file，不改写清单，也不补完或清理 merge 留下的文件。
+//! 命令行的 `get`、`scan`、`keys`、`stats`、`verify` 用它，查看一个存储不会在目录里留下任何东西。
+
+use std::{collections::BTreeMap, io, path::PathBuf, sync::Arc};
+
+use super::{
+    constants::*,
+    io_limiter::IoLimiter,
+    keydir::{Entry, KeyDir},
+    manifest::FileMeta,
+    read_files::ReadFiles,
+    stats::{self, StoreStats},
+    usage::UsageTracker,
+    verify::{self, VerifyReport},
+};
+use crate::utils::time::Clock;
+
+pub struct ReadOnlyStore {
+    base_dir: PathBuf,
+    clock: Arc<dyn Clock>,
+    keydir: KeyDir,
+    // 清单里的数据文件
+    files: BTreeMap<u64, FileMeta>,
+    usage: UsageTracker,
+    read_files: Arc<ReadFiles>,
+    io_limiter: IoLimiter,
+}
+
+impl ReadOnlyStore {
+    pub(crate) fn new(
+        base_dir: PathBuf,
+        clock: Arc<dyn Clock>,
+        keydir: KeyDir,
+        files: BTreeMap<u64, FileMeta>,
+        usage: UsageTracker,
+        io_limiter: IoLimiter,
+    ) -> Self {
+        Self {
+            read_files: Arc::new(ReadFiles::new(base_dir.clone(), READ_FILES_CACHE_SIZE)),
+            base_dir,
+            clock,
+            keydir,
+            files,
+            usage,
+            io_limiter,
+        }
+    }
+
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let Some(entry) = self.live_entry(key) else {
+            return Ok(None);
+        };
+        self.read_files.pin().read_value(&entry).await.map(Some)
+    }
+
+    /// 默认 namespace 中所有存活的 key，按字节序排列
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        let now = self.clock.now_ms();
+        let Some(partition) = self.keydir.partition(DEFAULT_NAMESPACE) else {
+            return Vec::new();
+        };
+        partition
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// 以 `prefix` 开头的所有 key 和对应的 value，按 key 的字节序排列
+    pub async fn scan_prefix(&self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        for key in self.keys() {
+            if !key.starts_with(prefix) {
+                continue;
+            }
+            if let Some(value) = self.get(&key).await? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 同 [`BitCaskHandle::stats`](super::bitcask_impl::BitCaskHandle::stats)；没有 active file，
+    /// `active_file_id` 是最新的数据文件
+    pub async fn stats(&self) -> io::Result<StoreStats> {
+        let now = self.clock.now_ms();
+        let key_count = self
+            .keydir
+            .iter()
+            .filter(|(namespace, _, entry)| {
+                *namespace != SYSTEM_NAMESPACE && !entry.is_expired(now)
+            })
+            .count();
+        let data_file_ids: Vec<u64> = self.files.keys().copied().collect();
+        let hint_files = self.files.values().filter(|meta| meta.has_hint).count();
+        stats::collect(
+            &self.base_dir,
+            key_count,
+            data_file_ids.last().copied().unwrap_or(0),
+            &data_file_ids,
+            hint_files,
+            self.usage.file_stats(),
+        )
+        .await
+    }
+
+    /// 检查数据文件、hint 文件和 keydir 的一致性，只读不修复
+    pub async fn verify(&self) -> io::Result<VerifyReport> {
+        verify::verify(&self.base_dir, &self.keydir, &self.io_limiter).await
+    }
+
+    fn live_entry(&self, key: &[u8]) -> Option<Entry> {
+        let now = self.clock.now_ms();
+        self.keydir
+            .get(DEFAULT_NAMESPACE, key)
+            .filter(|entry| !entry.is_expired(now))
+            .copied()
+    }
+}
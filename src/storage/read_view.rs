@@ -0,0 +1,89 @@
+//! 某一时刻的只读视图
+//!
+//! [`BitCaskHandle::snapshot`](super::bitcask_impl::BitCaskHandle::snapshot) 复制一个 namespace 的 keydir 分区
+//! （分区是持久化的有序表，复制本身很便宜），之后的写入、删除和 merge 在视图里都看不到。
+//! 视图存活期间相当于一次一直没结束的读，merge 取代的文件要等它被 drop 之后才删除，
+//! 所以长时间持有会让磁盘空间晚一些回收。
+
+use std::{io, ops::Bound};
+
+use super::{
+    keydir::{Entry, Partition},
+    options::ValueMeta,
+    read_files::ReadGuard,
+};
+
+/// 一个 namespace 在创建时刻的只读视图，过期时间也按创建时刻判断
+pub struct Snapshot {
+    partition: Partition,
+    now_ms: u64,
+    read: ReadGuard,
+}
+
+impl Snapshot {
+    pub(crate) fn new(partition: Partition, now_ms: u64, read: ReadGuard) -> Self {
+        Self {
+            partition,
+            now_ms,
+            read,
+        }
+    }
+
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let Some(entry) = self.live_entry(key) else {
+            return Ok(None);
+        };
+        self.read.read_value(entry).await.map(Some)
+    }
+
+    pub async fn get_with_meta(&self, key: &[u8]) -> io::Result<Option<(Vec<u8>, ValueMeta)>> {
+        let Some(entry) = self.live_entry(key) else {
+            return Ok(None);
+        };
+        self.read.read_value_with_meta(key, entry).await.map(Some)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.live_entry(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.live_entries().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 所有 key，按字节序排列
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.live_entries().map(|(key, _)| key.clone()).collect()
+    }
+
+    /// 以 `prefix` 开头的所有 key 和对应的 value，按 key 的字节序排列
+    pub async fn scan_prefix(&self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let entries = self
+            .partition
+            .range::<_, [u8]>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, entry)| !entry.is_expired(self.now_ms));
+
+        let mut pairs = Vec::new();
+        for (key, entry) in entries {
+            pairs.push((key.clone(), self.read.read_value(entry).await?));
+        }
+        Ok(pairs)
+    }
+
+    fn live_entry(&self, key: &[u8]) -> Option<&Entry> {
+        self.partition
+            .get(key)
+            .filter(|entry| !entry.is_expired(self.now_ms))
+    }
+
+    fn live_entries(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry)> {
+        self.partition
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(self.now_ms))
+    }
+}
@@ -0,0 +1,202 @@
+use std::{io, path::PathBuf, sync::Arc};
+
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncSeekExt, BufReader},
+    sync::{Semaphore, mpsc as tk_mpsc},
+    task::JoinSet,
+};
+use tracing::{debug, info};
+
+use super::{
+    constants::*,
+    file_util,
+    keydir::{self, Entry, KeyDir},
+};
+
+#[derive(Clone, PartialEq)]
+pub(crate) enum FileType {
+    Hint,
+    Data,
+}
+
+#[derive(Clone)]
+pub(crate) struct FileInfo {
+    pub(crate) file_type: FileType,
+    pub(crate) id: u64,
+    pub(crate) path: PathBuf,
+    // 数据文件从哪个偏移开始读，只有快照覆盖的最后一个文件不为 0
+    pub(crate) start_offset: u64,
+}
+
+pub(crate) struct DataDirScanResult {
+    pub(crate) hint_files: Vec<FileInfo>,
+    pub(crate) data_files: Vec<FileInfo>,
+}
+
+/// 启动时重建 keydir 的进度，每处理完一个文件通过
+/// [`StorageConfig::on_rebuild_progress`](super::config::StorageConfig::on_rebuild_progress) 回调一次
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RebuildProgress {
+    pub total_files: usize,
+    pub processed_files: usize,
+    pub processed_records: u64,
+    pub processed_bytes: u64,
+}
+
+struct FileRebuildResult {
+    entries: KeyDir,
+    max_seq: u64,
+    records: u64,
+    bytes: u64,
+}
+
+/// 并发读取 hint / data 文件并合并到 `keydir`，返回合并后的 keydir 和见过的最大序列号
+///
+/// 同一个 key 的多条记录按 (seq, file id, offset) 取最大者，
+/// 与文件被处理、合并的先后顺序无关，所以多次重建的结果总是一致的。
+pub(crate) async fn build_keydir(
+    mut keydir: KeyDir,
+    mut max_seq: u64,
+    scan_res: DataDirScanResult,
+    on_progress: impl Fn(&RebuildProgress),
+) -> io::Result<(KeyDir, u64)> {
+    let (tx, mut rx) = tk_mpsc::channel(100);
+    let semaphore = Arc::new(Semaphore::new(num_cpus::get() * 2));
+    let mut tasks = JoinSet::<io::Result<()>>::new();
+
+    let mut files: Vec<FileInfo> = scan_res
+        .hint_files
+        .into_iter()
+        .chain(scan_res.data_files)
+        .collect();
+    files.sort_by_key(|f| f.id);
+
+    let mut progress = RebuildProgress {
+        total_files: files.len(),
+        ..Default::default()
+    };
+
+    for file in files {
+        let tx = tx.clone();
+        let semaphore = semaphore.clone();
+
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire().await.map_err(io::Error::other)?;
+
+            let result = match file.file_type {
+                FileType::Hint => process_hint_file(file.path, file.id).await?,
+                FileType::Data => process_data_file(file.path, file.id, file.start_offset).await?,
+            };
+
+            tx.send(result)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+
+            Ok(())
+        });
+    }
+    drop(tx);
+
+    while let Some(result) = rx.recv().await {
+        keydir::merge_entries(&mut keydir, result.entries);
+        max_seq = max_seq.max(result.max_seq);
+
+        progress.processed_files += 1;
+        progress.processed_records += result.records;
+        progress.processed_bytes += result.bytes;
+        debug!(
+            "Keydir rebuild: {}/{} files processed",
+            progress.processed_files, progress.total_files
+        );
+        on_progress(&progress);
+    }
+
+    while let Some(res) = tasks.join_next().await {
+        res??;
+    }
+
+    // 所有文件都合并完之后，tombstone 已经压过了它之前的记录
+    keydir.retain(|_, _, entry| !entry.is_tombstone());
+
+    info!(
+        "Keydir rebuilt from {} files ({} records): {} keys",
+        progress.total_files,
+        progress.processed_records,
+        keydir.len()
+    );
+    Ok((keydir, max_seq))
+}
+
+async fn process_data_file(
+    path: PathBuf,
+    file_id: u64,
+    start_offset: u64,
+) -> io::Result<FileRebuildResult> {
+    let mut entries = KeyDir::new();
+    let file = OpenOptions::new().read(true).open(&path).await?;
+    let mut reader = BufReader::with_capacity(FILE_READER_BUFFER_SIZE, file);
+    // 从快照之后接着读时也先检查文件头
+    let has_header = file_util::read_file_header(&mut reader, DATA_FILE_MAGIC, &path).await?;
+    let start_offset = start_offset.max(FILE_HEADER_SIZE);
+    if has_header && start_offset > FILE_HEADER_SIZE {
+        reader.seek(io::SeekFrom::Start(start_offset)).await?;
+    }
+
+    let mut max_seq = 0u64;
+    let mut records = 0u64;
+    let mut offset = start_offset;
+    while has_header && let Some(record) = file_util::read_data_record(&mut reader, offset).await? {
+        offset = record.end_pos();
+        records += 1;
+        max_seq = max_seq.max(record.seq);
+
+        let new_entry = Entry::new(
+            file_id,
+            record.value_pos,
+            record.value_size as usize,
+            record.seq,
+            record.expire_at,
+        );
+        keydir::merge_entry(&mut entries, record.namespace, record.key, new_entry);
+    }
+
+    Ok(FileRebuildResult {
+        entries,
+        max_seq,
+        records,
+        bytes: offset - start_offset,
+    })
+}
+
+async fn process_hint_file(path: PathBuf, file_id: u64) -> io::Result<FileRebuildResult> {
+    let mut entries = KeyDir::new();
+    let file = OpenOptions::new().read(true).open(&path).await?;
+    let mut reader = BufReader::with_capacity(FILE_READER_BUFFER_SIZE, file);
+    let has_header = file_util::read_file_header(&mut reader, HINT_FILE_MAGIC, &path).await?;
+
+    let mut max_seq = 0u64;
+    let mut records = 0u64;
+    let mut bytes = 0u64;
+    while has_header && let Some(record) = file_util::read_hint_record(&mut reader).await? {
+        records += 1;
+        bytes += (HINT_HEADER_SIZE + record.key.len()) as u64;
+        max_seq = max_seq.max(record.seq);
+
+        let new_entry = Entry::new(
+            file_id,
+            record.value_pos,
+            record.value_size as usize,
+            record.seq,
+            record.expire_at,
+        );
+        keydir::merge_entry(&mut entries, record.namespace, record.key, new_entry);
+    }
+
+    Ok(FileRebuildResult {
+        entries,
+        max_seq,
+        records,
+        bytes,
+    })
+}
@@ -0,0 +1,230 @@
+//! 按 key 的哈希分片的存储
+//!
+//! 单个 [`BitCaskHandle`] 的写入都在 active file 的锁上串行执行。[`ShardedBitCask`]
+//! 在 `shard-000`、`shard-001`…… 子目录里各开一个独立的 handle，写入不同分片的 key 可以并发。
+//! 分片数写在 `SHARDS` 文件里，重新打开时必须一致，否则 key 会被路由到错误的分片。
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::fs;
+use tracing::info;
+
+use super::{
+    bitcask_impl::BitCaskHandle,
+    config::StorageConfig,
+    merge::MergeReport,
+    options::{PutOptions, PutOutcome, ValueMeta},
+    stats::StoreStats,
+};
+
+const SHARD_COUNT_FILE_NAME: &str = "SHARDS";
+
+/// 所有分片的统计汇总
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShardedStats {
+    pub key_count: usize,
+    pub data_files: usize,
+    pub hint_files: usize,
+    pub data_bytes: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    // 按分片序号排列
+    pub shards: Vec<StoreStats>,
+}
+
+/// 和 [`BitCaskHandle`] 一样的接口，key 按 CRC32 路由到固定的分片
+pub struct ShardedBitCask<C: StorageConfig> {
+    shards: Vec<BitCaskHandle<C>>,
+}
+
+impl<C> ShardedBitCask<C>
+where
+    C: StorageConfig + Clone + Default,
+{
+    pub async fn open(dir: impl Into<PathBuf>, shard_count: usize) -> io::Result<Self> {
+        Self::open_with_config(dir, shard_count, C::default()).await
+    }
+}
+
+impl<C: StorageConfig + Clone> ShardedBitCask<C> {
+    /// 每个分片使用 `config` 的一份拷贝
+    pub async fn open_with_config(
+        dir: impl Into<PathBuf>,
+        shard_count: usize,
+        config: C,
+    ) -> io::Result<Self> {
+        if shard_count == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shard count must be positive",
+            ));
+        }
+        let base_dir = dir.into();
+        fs::create_dir_all(&base_dir).await?;
+        check_shard_count(&base_dir, shard_count).await?;
+
+        let mut shards = Vec::with_capacity(shard_count);
+        for index in 0..shard_count {
+            let shard =
+                BitCaskHandle::open_with_config(shard_dir(&base_dir, index), config.clone())
+                    .await?;
+            shards.push(shard);
+        }
+        info!("Opened {shard_count} shards in {}", base_dir.display());
+        Ok(Self { shards })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// `key` 所在的分片
+    pub fn shard(&self, key: &[u8]) -> &BitCaskHandle<C> {
+        let index = crc32fast::hash(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.shard(key).get(key).await
+    }
+
+    pub async fn get_with_meta(&self, key: &[u8]) -> io::Result<Option<(Vec<u8>, ValueMeta)>> {
+        self.shard(key).get_with_meta(key).await
+    }
+
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.shard(key).put(key, value).await
+    }
+
+    /// 版本号只在分片内唯一，CAS 仍然可用，因为同一个 key 总在同一个分片
+    pub async fn put_with(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        options: PutOptions,
+    ) -> io::Result<PutOutcome> {
+        self.shard(key).put_with(key, value, options).await
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> io::Result<bool> {
+        self.shard(key).delete(key).await
+    }
+
+    /// 所有分片中存活的 key，按字节序排列
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        let mut keys: Vec<Vec<u8>> = self.shards.iter().flat_map(|shard| shard.keys()).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// 以 `prefix` 开头的所有 key 和对应的 value，按 key 的字节序排列
+    pub async fn scan_prefix(&self, prefix: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        for shard in &self.shards {
+            pairs.extend(shard.scan_prefix(prefix).await?);
+        }
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(pairs)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.is_empty())
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.shard(key).contains_key(key)
+    }
+
+    pub async fn stats(&self) -> io::Result<ShardedStats> {
+        let mut stats = ShardedStats::default();
+        for shard in &self.shards {
+            let shard_stats = shard.stats().await?;
+            stats.key_count += shard_stats.key_count;
+            stats.data_files += shard_stats.data_files;
+            stats.hint_files += shard_stats.hint_files;
+            stats.data_bytes += shard_stats.data_bytes;
+            stats.live_bytes += shard_stats.live_bytes;
+            stats.dead_bytes += shard_stats.dead_bytes;
+            stats.shards.push(shard_stats);
+        }
+        Ok(stats)
+    }
+
+    /// 依次 merge 每个分片，返回汇总的结果
+    pub async fn merge(&self) -> io::Result<MergeReport> {
+        let mut total = MergeReport::default();
+        for shard in &self.shards {
+            let report = shard.merge().await?;
+            total.input_files += report.input_files;
+            total.output_files += report.output_files;
+            total.live_records += report.live_records;
+            total.dropped_records += report.dropped_records;
+            total.kept_tombstones += report.kept_tombstones;
+            total.reclaimed_bytes += report.reclaimed_bytes;
+        }
+        Ok(total)
+    }
+
+    pub async fn sync(&self) -> io::Result<()> {
+        for shard in &self.shards {
+            shard.sync().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn rotate_now(&self) -> io::Result<()> {
+        for shard in &self.shards {
+            shard.rotate_now().await?;
+        }
+        Ok(())
+    }
+
+    /// 关闭所有分片；某个分片失败时仍然关闭其余的分片，返回第一个错误
+    pub async fn close(self) -> io::Result<()> {
+        let mut result = Ok(());
+        for shard in self.shards {
+            let closed = shard.close().await;
+            if result.is_ok() {
+                result = closed;
+            }
+        }
+        result
+    }
+}
+
+fn shard_dir(base_dir: &Path, index: usize) -> PathBuf {
+    base_dir.join(format!("shard-{index:03}"))
+}
+
+/// 第一次打开时记下分片数，之后打开时检查是否一致
+async fn check_shard_count(base_dir: &Path, shard_count: usize) -> io::Result<()> {
+    let path = base_dir.join(SHARD_COUNT_FILE_NAME);
+    match fs::read_to_string(&path).await {
+        Ok(content) => {
+            let existing: usize = content.trim().parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid shard count in {}", path.display()),
+                )
+            })?;
+            if existing != shard_count {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("store has {existing} shards, cannot open it with {shard_count}"),
+                ));
+            }
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::write(&path, format!("{shard_count}\n")).await
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::{fs, io::AsyncWriteExt};
+
+use super::{
+    constants::*,
+    io_limiter::IoLimiter,
+    keydir::{Entry, KeyDir},
+};
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"BCKS";
+const SNAPSHOT_VERSION: u32 = 5;
+// magic + version + created_at + file_id + offset + max_seq + entry_count
+const SNAPSHOT_HEADER_SIZE: usize = 4 + 4 + 8 + 8 + 8 + 8 + 8;
+// namespace + file_id + value_pos + value_size + seq + expire_at + key_size
+const SNAPSHOT_ENTRY_HEADER_SIZE: usize = 4 + 8 + 8 + 4 + 8 + 8 + 4;
+const SNAPSHOT_CHECKSUM_SIZE: usize = 4;
+
+/// keydir 的持久化快照
+///
+/// 快照覆盖了 `file_id` 之前的所有文件，以及 `file_id` 中 `offset` 之前的记录，
+/// 打开时只需重放这之后写入的数据。
+pub struct KeydirSnapshot {
+    pub file_id: u64,
+    pub offset: u64,
+    // 快照时已分配的最大序列号，被覆盖的记录的 seq 不一定还在 keydir 里
+    pub max_seq: u64,
+    pub created_at: u64,
+    pub keydir: KeyDir,
+}
+
+impl KeydirSnapshot {
+    pub fn referenced_file_ids(&self) -> HashSet<u64> {
+        self.keydir.values().map(|entry| entry.file_id).collect()
+    }
+}
+
+pub fn snapshot_path(base_dir: &Path) -> PathBuf {
+    base_dir.join(KEYDIR_SNAPSHOT_FILE_NAME)
+}
+
+/// 写入 [`encode`] 编码好的快照；先写临时文件再 rename，保证任何时刻磁盘上的快照都是完整的
+pub async fn write(base_dir: &Path, bytes: &[u8], limiter: &IoLimiter) -> io::Result<()> {
+    let path = snapshot_path(base_dir);
+    let tmp_path = path.with_extension("snapshot.tmp");
+
+    let mut file = fs::File::create(&tmp_path).await?;
+    for chunk in bytes.chunks(FILE_WRITER_BUFFER_SIZE) {
+        limiter.acquire(chunk.len() as u64, None).await;
+        file.write_all(chunk).await?;
+    }
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(&tmp_path, &path).await
+}
+
+/// merge 之后快照引用的文件已经不存在，直接删掉
+pub async fn remove(base_dir: &Path) -> io::Result<()> {
+    match fs::remove_file(snapshot_path(base_dir)).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// 快照不存在时返回 `Ok(None)`，校验失败时返回 `InvalidData`
+pub async fn load(base_dir: &Path) -> io::Result<Option<KeydirSnapshot>> {
+    match fs::read(snapshot_path(base_dir)).await {
+        Ok(bytes) => decode(&bytes).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn encode(
+    keydir: &KeyDir,
+    file_id: u64,
+    offset: u64,
+    max_seq: u64,
+    created_at: u64,
+) -> Vec<u8> {
+    let body_size: usize = keydir
+        .iter()
+        .map(|(_, key, _)| SNAPSHOT_ENTRY_HEADER_SIZE + key.len())
+        .sum();
+    let mut buf = Vec::with_capacity(SNAPSHOT_HEADER_SIZE + body_size + SNAPSHOT_CHECKSUM_SIZE);
+
+    buf.extend_from_slice(SNAPSHOT_MAGIC);
+    buf.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&created_at.to_le_bytes());
+    buf.extend_from_slice(&file_id.to_le_bytes());
+    buf.extend_from_slice(&offset.to_le_bytes());
+    buf.extend_from_slice(&max_seq.to_le_bytes());
+    buf.extend_from_slice(&(keydir.len() as u64).to_le_bytes());
+
+    for (namespace, key, entry) in keydir.iter() {
+        buf.extend_from_slice(&namespace.to_le_bytes());
+        buf.extend_from_slice(&entry.file_id.to_le_bytes());
+        buf.extend_from_slice(&entry.value_pos.to_le_bytes());
+        buf.extend_from_slice(&(entry.value_size as u32).to_le_bytes());
+        buf.extend_from_slice(&entry.seq.to_le_bytes());
+        buf.extend_from_slice(&entry.expire_at.to_le_bytes());
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+    }
+
+    let checksum = crc32fast::hash(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+fn decode(bytes: &[u8]) -> io::Result<KeydirSnapshot> {
+    if bytes.len() < SNAPSHOT_HEADER_SIZE + SNAPSHOT_CHECKSUM_SIZE {
+        return Err(invalid_data("keydir snapshot is truncated"));
+    }
+
+    let (body, checksum) = bytes.split_at(bytes.len() - SNAPSHOT_CHECKSUM_SIZE);
+    if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(invalid_data("keydir snapshot checksum mismatch"));
+    }
+    if &body[0..4] != SNAPSHOT_MAGIC {
+        return Err(invalid_data("not a keydir snapshot"));
+    }
+
+    let mut cursor = SnapshotCursor { buf: body, pos: 4 };
+    let version = cursor.read_u32()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported keydir snapshot version: {version}"
+        )));
+    }
+
+    let created_at = cursor.read_u64()?;
+    let file_id = cursor.read_u64()?;
+    let offset = cursor.read_u64()?;
+    let max_seq = cursor.read_u64()?;
+    let entry_count = cursor.read_u64()?;
+
+    let mut keydir = KeyDir::new();
+    for _ in 0..entry_count {
+        let namespace = cursor.read_u32()?;
+        let entry_file_id = cursor.read_u64()?;
+        let value_pos = cursor.read_u64()?;
+        let value_size = cursor.read_u32()?;
+        let seq = cursor.read_u64()?;
+        let expire_at = cursor.read_u64()?;
+        let key_size = cursor.read_u32()?;
+        let key = cursor.read_bytes(key_size as usize)?.to_vec();
+
+        keydir.insert(
+            namespace,
+            key,
+            Entry::new(
+                entry_file_id,
+                value_pos,
+                value_size as usize,
+                seq,
+                expire_at,
+            ),
+        );
+    }
+
+    if cursor.pos != body.len() {
+        return Err(invalid_data("trailing bytes in keydir snapshot"));
+    }
+
+    Ok(KeydirSnapshot {
+        file_id,
+        offset,
+        max_seq,
+        created_at,
+        keydir,
+    })
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+struct SnapshotCursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SnapshotCursor<'a> {
+    fn read_bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or_else(|| invalid_data("keydir snapshot is truncated"))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+}
@@ -0,0 +1,104 @@
+use std::{fmt, io, path::Path};
+
+use super::file_util;
+
+/// 存储目录的概况
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    pub key_count: usize,
+    pub data_files: usize,
+    pub hint_files: usize,
+    // 所有数据文件（包括 active file）的总字节数
+    pub data_bytes: u64,
+    pub active_file_id: u64,
+    // 下面三项来自内存中的统计，`live_bytes + dead_bytes` 和 `data_bytes` 可能有细微差别
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    pub files: Vec<FileStats>,
+}
+
+impl StoreStats {
+    /// 垃圾字节占比，没有数据时为 0
+    pub fn dead_ratio(&self) -> f64 {
+        ratio(self.dead_bytes, self.live_bytes + self.dead_bytes)
+    }
+}
+
+/// 命令行和 admin socket 的 `stats` 输出，每项一行
+impl fmt::Display for StoreStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "keys:           {}", self.key_count)?;
+        writeln!(f, "data files:     {}", self.data_files)?;
+        writeln!(f, "hint files:     {}", self.hint_files)?;
+        writeln!(f, "data bytes:     {}", self.data_bytes)?;
+        writeln!(
+            f,
+            "dead bytes:     {} ({:.1}%)",
+            self.dead_bytes,
+            self.dead_ratio() * 100.0
+        )?;
+        write!(f, "active file id: {}", self.active_file_id)
+    }
+}
+
+/// 一个数据文件里存活的和已作废（被覆盖、删除或 tombstone）的记录
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileStats {
+    pub file_id: u64,
+    pub live_keys: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    // `dead_bytes` 里 merge 为了不让更老的版本复活而保留的 tombstone，单独 merge 这个文件回收不了
+    pub kept_bytes: u64,
+}
+
+impl FileStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.live_bytes + self.dead_bytes
+    }
+
+    /// 垃圾字节占比，空文件为 0
+    pub fn dead_ratio(&self) -> f64 {
+        ratio(self.dead_bytes, self.total_bytes())
+    }
+
+    /// merge 这个文件能回收的字节数
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.dead_bytes.saturating_sub(self.kept_bytes)
+    }
+
+    /// 能回收的字节占比，空文件为 0
+    pub fn reclaimable_ratio(&self) -> f64 {
+        ratio(self.reclaimable_bytes(), self.total_bytes())
+    }
+}
+
+fn ratio(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+pub(crate) async fn collect(
+    base_dir: &Path,
+    key_count: usize,
+    active_file_id: u64,
+    data_file_ids: &[u64],
+    hint_files: usize,
+    files: Vec<FileStats>,
+) -> io::Result<StoreStats> {
+    let data_lens = file_util::data_file_lens(base_dir, data_file_ids).await?;
+
+    Ok(StoreStats {
+        key_count,
+        data_files: data_lens.len(),
+        hint_files,
+        data_bytes: data_lens.values().sum(),
+        active_file_id,
+        live_bytes: files.iter().map(|file| file.live_bytes).sum(),
+        dead_bytes: files.iter().map(|file| file.dead_bytes).sum(),
+        files,
+    })
+}
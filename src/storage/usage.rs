@@ -0,0 +1,101 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::{constants::*, keydir::KeyDir, stats::FileStats};
+
+/// 一个数据文件里存活和已作废的记录
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct FileUsage {
+    live_keys: u64,
+    live_bytes: u64,
+    dead_bytes: u64,
+    // 垃圾里 merge 必须保留的 tombstone
+    kept_bytes: u64,
+}
+
+/// 按文件统计存活字节和垃圾字节，决定哪些文件值得 merge
+///
+/// 被 keydir 引用的记录是存活的（过期但还没被 merge 清掉的也算），其余的都是垃圾：
+/// 被覆盖或删除的旧记录、tombstone，以及已删除 namespace 的记录。merge 输出里保留下来的 tombstone
+/// 也是垃圾，但单独 merge 这个文件回收不了，另外记下来，免得调度器反复 merge 同一批 tombstone。
+#[derive(Debug, Default)]
+pub(crate) struct UsageTracker {
+    files: BTreeMap<u64, FileUsage>,
+}
+
+impl UsageTracker {
+    /// 打开或 merge 之后从 keydir、数据文件的长度和清单里记录的保留字节数重新计算，
+    /// 不在 `file_lens` 里的文件被忽略
+    pub fn rebuild(
+        keydir: &KeyDir,
+        file_lens: &HashMap<u64, u64>,
+        kept_bytes: &HashMap<u64, u64>,
+    ) -> Self {
+        let mut files: BTreeMap<u64, FileUsage> = file_lens
+            .keys()
+            .map(|&file_id| {
+                let usage = FileUsage {
+                    kept_bytes: kept_bytes.get(&file_id).copied().unwrap_or(0),
+                    ..Default::default()
+                };
+                (file_id, usage)
+            })
+            .collect();
+        for (_, key, entry) in keydir.iter() {
+            if let Some(usage) = files.get_mut(&entry.file_id) {
+                usage.live_keys += 1;
+                usage.live_bytes += record_size(key.len(), entry.value_size);
+            }
+        }
+        for (file_id, usage) in &mut files {
+            usage.dead_bytes = file_lens[file_id]
+                .saturating_sub(FILE_HEADER_SIZE)
+                .saturating_sub(usage.live_bytes);
+            usage.kept_bytes = usage.kept_bytes.min(usage.dead_bytes);
+        }
+        Self { files }
+    }
+
+    /// 新写入一条存活的记录
+    pub fn add_live(&mut self, file_id: u64, bytes: u64) {
+        let usage = self.files.entry(file_id).or_default();
+        usage.live_keys += 1;
+        usage.live_bytes += bytes;
+    }
+
+    /// 新写入一条本身就是垃圾的记录（tombstone）
+    pub fn add_dead(&mut self, file_id: u64, bytes: u64) {
+        self.files.entry(file_id).or_default().dead_bytes += bytes;
+    }
+
+    /// 一条存活的记录被覆盖或删除
+    pub fn mark_dead(&mut self, file_id: u64, bytes: u64) {
+        let usage = self.files.entry(file_id).or_default();
+        usage.live_keys = usage.live_keys.saturating_sub(1);
+        usage.live_bytes = usage.live_bytes.saturating_sub(bytes);
+        usage.dead_bytes += bytes;
+    }
+
+    /// 按文件 id 升序
+    pub fn file_stats(&self) -> Vec<FileStats> {
+        self.files
+            .iter()
+            .map(|(&file_id, usage)| FileStats {
+                file_id,
+                live_keys: usage.live_keys,
+                live_bytes: usage.live_bytes,
+                dead_bytes: usage.dead_bytes,
+                kept_bytes: usage.kept_bytes,
+            })
+            .collect()
+    }
+}
+
+/// 一条记录在数据文件里占用的字节数
+pub(crate) fn record_size(key_len: usize, value_size: usize) -> u64 {
+    let value_len = if value_size == TOMBSTONE_VALUE_SIZE as usize {
+        0
+    } else {
+        value_size
+    };
+    (RECORD_HEADER_SIZE + key_len + value_len) as u64
+}
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, fmt, io, path::Path};
+
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::BufReader,
+};
+
+use super::{
+    constants::*,
+    file_util::{self, data_file_path, hint_file_path},
+    io_limiter::IoLimiter,
+    keydir::KeyDir,
+};
+
+/// [`verify`] 发现的一个问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyIssue {
+    pub file_id: u64,
+    pub offset: u64,
+    pub problem: String,
+}
+
+impl fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "file {:08} offset {}: {}",
+            self.file_id, self.offset, self.problem
+        )
+    }
+}
+
+/// 一次完整性检查的结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub data_files: usize,
+    pub hint_files: usize,
+    pub records: u64,
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// 每个问题一行，最后一行是汇总
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in &self.issues {
+            writeln!(f, "{issue}")?;
+        }
+        write!(
+            f,
+            "checked {} data files, {} hint files, {} records: {} problems",
+            self.data_files,
+            self.hint_files,
+            self.records,
+            self.issues.len()
+        )
+    }
+}
+
+/// 检查数据文件的校验和和尾部截断、hint 文件和 keydir 是否指向数据文件内的有效范围
+pub(crate) async fn verify(
+    base_dir: &Path,
+    keydir: &KeyDir,
+    limiter: &IoLimiter,
+) -> io::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+
+    let mut file_lens = HashMap::new();
+    for file_id in file_util::list_file_ids(base_dir, DATA_FILE_EXTENSION).await? {
+        let len = verify_data_file(base_dir, file_id, limiter, &mut report).await?;
+        file_lens.insert(file_id, len);
+        report.data_files += 1;
+    }
+
+    for file_id in file_util::list_file_ids(base_dir, HINT_FILE_EXTENSION).await? {
+        verify_hint_file(
+            base_dir,
+            file_id,
+            file_lens.get(&file_id).copied(),
+            limiter,
+            &mut report,
+        )
+        .await?;
+        report.hint_files += 1;
+    }
+
+    let mut keys: Vec<_> = keydir.iter().collect();
+    keys.sort_unstable_by_key(|(_, _, entry)| (entry.file_id, entry.value_pos));
+    for (_, key, entry) in keys {
+        let end = entry.value_pos + entry.value_size as u64;
+        match file_lens.get(&entry.file_id) {
+            Some(&len) if end <= len => {}
+            Some(&len) => report.issues.push(VerifyIssue {
+                file_id: entry.file_id,
+                offset: entry.value_pos,
+                problem: format!(
+                    "keydir entry for key {} ends at {end}, past end of file ({len} bytes)",
+                    key.escape_ascii()
+                ),
+            }),
+            None => report.issues.push(VerifyIssue {
+                file_id: entry.file_id,
+                offset: entry.value_pos,
+                problem: format!(
+                    "keydir entry for key {} points to a missing data file",
+                    key.escape_ascii()
+                ),
+            }),
+        }
+    }
+
+    Ok(report)
+}
+
+/// 返回数据文件的长度
+async fn verify_data_file(
+    base_dir: &Path,
+    file_id: u64,
+    limiter: &IoLimiter,
+    report: &mut VerifyReport,
+) -> io::Result<u64> {
+    let path = data_file_path(base_dir, file_id);
+    let file = OpenOptions::new().read(true).open(&path).await?;
+    let file_len = file.metadata().await?.len();
+    let mut reader = BufReader::with_capacity(FILE_READER_BUFFER_SIZE, file);
+    if !check_file_header(&mut reader, DATA_FILE_MAGIC, &path, file_id, report).await? {
+        return Ok(file_len);
+    }
+
+    let mut offset = FILE_HEADER_SIZE;
+    while let Some((record, value)) =
+        file_util::read_data_record_with_value(&mut reader, offset).await?
+    {
+        limiter.acquire(record.end_pos() - offset, None).await;
+        if !record.checksum_matches(&value) {
+            report.issues.push(VerifyIssue {
+                file_id,
+                offset,
+                problem: format!("checksum mismatch (stored {:#010x})", record.crc),
+            });
+        }
+        report.records += 1;
+        offset = record.end_pos();
+    }
+
+    if offset < file_len {
+        report.issues.push(VerifyIssue {
+            file_id,
+            offset,
+            problem: format!("truncated record, {} trailing bytes", file_len - offset),
+        });
+    }
+
+    Ok(file_len)
+}
+
+async fn verify_hint_file(
+    base_dir: &Path,
+    file_id: u64,
+    data_len: Option<u64>,
+    limiter: &IoLimiter,
+    report: &mut VerifyReport,
+) -> io::Result<()> {
+    let path = hint_file_path(base_dir, file_id);
+    let hint_len = fs::metadata(&path).await?.len();
+    let Some(data_len) = data_len else {
+        report.issues.push(VerifyIssue {
+            file_id,
+            offset: 0,
+            problem: "hint file without a data file".to_string(),
+        });
+        return Ok(());
+    };
+
+    let file = OpenOptions::new().read(true).open(&path).await?;
+    let mut reader = BufReader::with_capacity(FILE_READER_BUFFER_SIZE, file);
+    if !check_file_header(&mut reader, HINT_FILE_MAGIC, &path, file_id, report).await? {
+        return Ok(());
+    }
+
+    let mut offset = FILE_HEADER_SIZE;
+    while let Some(record) = file_util::read_hint_record(&mut reader).await? {
+        let record_size = (HINT_HEADER_SIZE + record.key.len()) as u64;
+        limiter.acquire(record_size, None).await;
+        if record.end_pos() > data_len {
+            report.issues.push(VerifyIssue {
+                file_id,
+                offset,
+                problem: format!(
+                    "hint for key {} points past end of data file ({} > {data_len})",
+                    record.key.escape_ascii(),
+                    record.end_pos()
+                ),
+            });
+        }
+        offset += record_size;
+    }
+
+    if offset < hint_len {
+        report.issues.push(VerifyIssue {
+            file_id,
+            offset,
+            problem: format!(
+                "truncated hint record, {} trailing bytes",
+                hint_len - offset
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+/// 文件头有问题时记一个问题并返回 `false`，不再往下读
+async fn check_file_header(
+    reader: &mut BufReader<File>,
+    magic: &[u8; 4],
+    path: &Path,
+    file_id: u64,
+    report: &mut VerifyReport,
+) -> io::Result<bool> {
+    let problem = match file_util::read_file_header(reader, magic, path).await {
+        Ok(true) => return Ok(true),
+        Ok(false) => "truncated file header".to_string(),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => e.to_string(),
+        Err(e) => return Err(e),
+    };
+    report.issues.push(VerifyIssue {
+        file_id,
+        offset: 0,
+        problem,
+    });
+    Ok(false)
+}
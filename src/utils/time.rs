@@ -1,15 +1,72 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-pub fn current_timestamp_sec() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs()
-}
+// 墙上时间只作为元信息，时钟被调到 1970 年之前时返回 0 而不是 panic
 
 pub fn current_timestamp_ms() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_millis() as u64
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// 写记录时打时间戳用的时钟，测试中可以换成 [`ManualClock`]
+pub trait Clock: Send + Sync + 'static {
+    /// 自 UNIX epoch 起的毫秒数
+    fn now_ms(&self) -> u64;
+
+    fn now_sec(&self) -> u64 {
+        self.now_ms() / 1000
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        current_timestamp_ms()
+    }
+}
+
+/// 只在显式调用 `set` / `advance` 时才走动的时钟，clone 出来的实例共享同一个时间
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    now_ms: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new(now_ms: u64) -> Self {
+        Self {
+            now_ms: Arc::new(AtomicU64::new(now_ms)),
+        }
+    }
+
+    pub fn set(&self, now_ms: u64) {
+        self.now_ms.store(now_ms, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now_ms.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for ManualClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ManualClock")
+            .field("now_ms", &self.now_ms())
+            .finish()
+    }
 }
@@ -0,0 +1,91 @@
+/* 通过 C 接口读写存储，由 tests/unit_tests.rs 编译运行：bitcask_test <data-dir> */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "bitcask.h"
+
+#define CHECK(cond)                                                              \
+    do {                                                                         \
+        if (!(cond)) {                                                           \
+            const char *err = bitcask_last_error();                              \
+            fprintf(stderr, "%s:%d: check failed: %s (last error: %s)\n",        \
+                    __FILE__, __LINE__, #cond, err ? err : "none");              \
+            exit(1);                                                             \
+        }                                                                        \
+    } while (0)
+
+static int put_str(Bitcask *db, const char *key, const char *value) {
+    return bitcask_put(db, (const uint8_t *)key, strlen(key), (const uint8_t *)value,
+                       strlen(value));
+}
+
+int main(int argc, char **argv) {
+    CHECK(argc == 2);
+
+    Bitcask *db = bitcask_open(argv[1]);
+    CHECK(db != NULL);
+
+    CHECK(put_str(db, "user:1", "alice") == BITCASK_OK);
+    CHECK(put_str(db, "user:2", "bob") == BITCASK_OK);
+    CHECK(put_str(db, "order:1", "book") == BITCASK_OK);
+    /* key 里可以有 NUL */
+    const uint8_t binary_key[] = {'b', 0, 'k'};
+    CHECK(bitcask_put(db, binary_key, sizeof(binary_key), NULL, 0) == BITCASK_OK);
+
+    uint8_t *value = NULL;
+    size_t value_len = 0;
+    CHECK(bitcask_get(db, (const uint8_t *)"user:1", 6, &value, &value_len) == BITCASK_OK);
+    CHECK(value_len == 5 && memcmp(value, "alice", 5) == 0);
+    bitcask_free(value, value_len);
+
+    CHECK(bitcask_get(db, binary_key, sizeof(binary_key), &value, &value_len) == BITCASK_OK);
+    CHECK(value_len == 0);
+    bitcask_free(value, value_len);
+
+    CHECK(bitcask_get(db, (const uint8_t *)"nope", 4, &value, &value_len) == BITCASK_NOT_FOUND);
+    CHECK(bitcask_delete(db, (const uint8_t *)"user:2", 6) == BITCASK_OK);
+    CHECK(bitcask_delete(db, (const uint8_t *)"user:2", 6) == BITCASK_NOT_FOUND);
+
+    BitcaskIter *iter = bitcask_iter_new(db, (const uint8_t *)"user:", 5);
+    CHECK(iter != NULL);
+    /* 有迭代器时不能关闭 */
+    CHECK(bitcask_close(db) == BITCASK_ERROR);
+    CHECK(bitcask_last_error() != NULL);
+    /* 迭代器看到的是创建时刻的数据 */
+    CHECK(put_str(db, "user:1", "carol") == BITCASK_OK);
+    CHECK(put_str(db, "user:3", "dave") == BITCASK_OK);
+
+    int count = 0;
+    uint8_t *key = NULL;
+    size_t key_len = 0;
+    int rc;
+    while ((rc = bitcask_iter_next(iter, &key, &key_len, &value, &value_len)) == BITCASK_OK) {
+        CHECK(key_len == 6 && memcmp(key, "user:1", 6) == 0);
+        CHECK(value_len == 5 && memcmp(value, "alice", 5) == 0);
+        bitcask_free(key, key_len);
+        bitcask_free(value, value_len);
+        count++;
+    }
+    CHECK(rc == BITCASK_NOT_FOUND);
+    CHECK(count == 1);
+    bitcask_iter_free(iter);
+
+    CHECK(bitcask_close(db) == BITCASK_OK);
+    CHECK(bitcask_last_error() == NULL);
+
+    /* 重新打开能看到数据 */
+    db = bitcask_open(argv[1]);
+    CHECK(db != NULL);
+    CHECK(bitcask_get(db, (const uint8_t *)"order:1", 7, &value, &value_len) == BITCASK_OK);
+    CHECK(value_len == 4 && memcmp(value, "book", 4) == 0);
+    bitcask_free(value, value_len);
+    CHECK(bitcask_close(db) == BITCASK_OK);
+
+    CHECK(bitcask_open(NULL) == NULL);
+    CHECK(bitcask_last_error() != NULL);
+
+    printf("ok\n");
+    return 0;
+}
@@ -2,10 +2,12 @@ use std::{
     fs::OpenOptions,
     io::{BufWriter, Write},
     path::Path,
-    u64,
+    time::Duration as StdDuration,
 };
 
-use bitcask::{BitCaskConfig, BitCaskHandle, StorageConfig};
+use std::sync::{Arc, Mutex};
+
+use bitcask::{BitCaskConfig, BitCaskHandle, Clock, ManualClock, RebuildProgress, StorageConfig};
 use ctor::ctor;
 use tempfile::tempdir;
 use tokio::time::{Duration, sleep};
@@ -51,7 +53,7 @@ async fn test_active_file_rotation() {
     let base_dir = tempdir().unwrap();
 
     let config = TestConfig { max_file_size: 100 };
-    let mut handle = BitCaskHandle::<TestConfig>::open_with_config(base_dir.path(), config)
+    let handle = BitCaskHandle::<TestConfig>::open_with_config(base_dir.path(), config)
         .await
         .unwrap();
 
@@ -89,7 +91,7 @@ async fn test_active_file_rotation() {
 
     assert!(file_metas.len() >= 2, "File rotation did not occur");
 
-    let active_file_id = handle.active_file_id();
+    let active_file_id = handle.active_file_id().await;
     for (path, meta) in file_metas {
         let file_id = path
             .file_stem()
@@ -109,20 +111,2768 @@ async fn test_active_file_rotation() {
     }
 }
 
-fn create_mock_hint_file(dir: &Path, file_id: u64, entries: &[(u32, u64, &[u8])]) {
-    let path = dir.join(format!("{file_id:08}"));
-    let file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(path)
+#[tokio::test]
+async fn test_get_after_put() {
+    let base_dir = tempdir().unwrap();
+
+    let config = TestConfig { max_file_size: 100 };
+    let handle = BitCaskHandle::<TestConfig>::open_with_config(base_dir.path(), config)
+        .await
         .unwrap();
-    let mut writer = BufWriter::new(file);
-    for (value_size, value_pos, key) in entries {
-        writer.write_all(&0u64.to_le_bytes()).unwrap();
-        writer.write_all(&(key.len() as u32).to_le_bytes()).unwrap();
-        writer.write_all(&value_size.to_le_bytes()).unwrap();
-        writer.write_all(&value_pos.to_le_bytes()).unwrap();
-        writer.write_all(key).unwrap();
+
+    for i in 0..20 {
+        let key = format!("key_{i}").into_bytes();
+        let value = format!("value_{i}").into_bytes();
+        handle.put(&key, &value).await.unwrap();
     }
-    writer.flush().unwrap();
+    handle.put(b"key_3", b"new_value").await.unwrap();
+
+    assert_eq!(
+        handle.get(b"key_0").await.unwrap(),
+        Some(b"value_0".to_vec())
+    );
+    assert_eq!(
+        handle.get(b"key_19").await.unwrap(),
+        Some(b"value_19".to_vec())
+    );
+    assert_eq!(
+        handle.get(b"key_3").await.unwrap(),
+        Some(b"new_value".to_vec())
+    );
+    assert_eq!(handle.get(b"missing").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_reopen_rebuilds_keydir_without_snapshot() {
+    let base_dir = tempdir().unwrap();
+
+    let config = TestConfig { max_file_size: 100 };
+    let handle = BitCaskHandle::<TestConfig>::open_with_config(base_dir.path(), config.clone())
+        .await
+        .unwrap();
+    for i in 0..20 {
+        handle
+            .put(
+                format!("key_{i}").as_bytes(),
+                format!("value_{i}").as_bytes(),
+            )
+            .await
+            .unwrap();
+    }
+    handle.put(b"key_3", b"new_value").await.unwrap();
+    drop(handle);
+
+    assert!(!base_dir.path().join("keydir.snapshot").exists());
+
+    let handle = BitCaskHandle::<TestConfig>::open_with_config(base_dir.path(), config)
+        .await
+        .unwrap();
+    assert_eq!(
+        handle.get(b"key_0").await.unwrap(),
+        Some(b"value_0".to_vec())
+    );
+    assert_eq!(
+        handle.get(b"key_3").await.unwrap(),
+        Some(b"new_value".to_vec())
+    );
+    assert_eq!(
+        handle.get(b"key_19").await.unwrap(),
+        Some(b"value_19".to_vec())
+    );
+}
+
+#[tokio::test]
+async fn test_keydir_snapshot_written_on_close() {
+    let base_dir = tempdir().unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.close().await.unwrap();
+
+    assert!(base_dir.path().join("keydir.snapshot").exists());
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(handle.get(b"b").await.unwrap(), Some(b"2".to_vec()));
+}
+
+#[tokio::test]
+async fn test_reopen_replays_records_after_snapshot() {
+    let base_dir = tempdir().unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.close().await.unwrap();
+
+    // 快照之后追加到同一个文件的写入，且不调用 close
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"b", b"22").await.unwrap();
+    handle.put(b"c", b"3").await.unwrap();
+    drop(handle);
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(handle.get(b"b").await.unwrap(), Some(b"22".to_vec()));
+    assert_eq!(handle.get(b"c").await.unwrap(), Some(b"3".to_vec()));
+}
+
+#[tokio::test]
+async fn test_periodic_keydir_snapshot() {
+    let base_dir = tempdir().unwrap();
+
+    let config = BitCaskConfig {
+        keydir_snapshot_interval: Some(StdDuration::from_millis(10)),
+        ..Default::default()
+    };
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config)
+        .await
+        .unwrap();
+    let snapshot_path = base_dir.path().join("keydir.snapshot");
+    // 没有写入时不写快照
+    sleep(Duration::from_millis(50)).await;
+    assert!(!snapshot_path.exists());
+
+    handle.put(b"a", b"1").await.unwrap();
+    for _ in 0..100 {
+        if snapshot_path.exists() {
+            break;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(snapshot_path.exists());
+
+    handle.put(b"a", b"11").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.put(b"c", b"3").await.unwrap();
+    drop(handle);
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"11".to_vec()));
+    assert_eq!(handle.get(b"b").await.unwrap(), Some(b"2".to_vec()));
+    assert_eq!(handle.get(b"c").await.unwrap(), Some(b"3".to_vec()));
+}
+
+#[tokio::test]
+async fn test_corrupted_keydir_snapshot_falls_back_to_rebuild() {
+    let base_dir = tempdir().unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.close().await.unwrap();
+
+    let snapshot_path = base_dir.path().join("keydir.snapshot");
+    let mut bytes = std::fs::read(&snapshot_path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&snapshot_path, bytes).unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(handle.get(b"b").await.unwrap(), Some(b"2".to_vec()));
+}
+
+#[tokio::test]
+async fn test_stale_keydir_snapshot_falls_back_to_rebuild() {
+    let base_dir = tempdir().unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.close().await.unwrap();
+
+    // 快照覆盖的文件被截断后，快照里的偏移已经不可信
+    let data_path = base_dir.path().join(format!("{:08}.data", 0));
+    let file = OpenOptions::new().write(true).open(&data_path).unwrap();
+    file.set_len(FILE_HEADER_SIZE + RECORD_SIZE_A).unwrap();
+    drop(file);
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(handle.get(b"b").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_open_loads_hint_file() {
+    let base_dir = tempdir().unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"hello").await.unwrap();
+    drop(handle);
+
+    // 文件头(8) + header(44) + key(1)
+    create_mock_hint_file(base_dir.path(), 0, &[(5, 53, b"a")]);
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"hello".to_vec()));
+}
+
+#[tokio::test]
+async fn test_rebuild_resolves_seq_ties_deterministically() {
+    let base_dir = tempdir().unwrap();
+
+    // 所有记录的 seq 都相同，同一个 key 出现在多个文件里时应当总是由 id 最大的文件胜出
+    const FILES: u64 = 8;
+    const KEYS: u64 = 200;
+    for file_id in 0..FILES {
+        let records: Vec<(u64, Vec<u8>, Vec<u8>)> = (0..KEYS)
+            .filter(|k| k.is_multiple_of(file_id + 1))
+            .map(|k| {
+                (
+                    100,
+                    format!("key_{k}").into_bytes(),
+                    format!("value_{k}_{file_id}").into_bytes(),
+                )
+            })
+            .collect();
+        create_mock_data_file(base_dir.path(), file_id, &records);
+    }
+
+    let expected = |k: u64| {
+        let file_id = (0..FILES).rev().find(|f| k.is_multiple_of(f + 1)).unwrap();
+        format!("value_{k}_{file_id}").into_bytes()
+    };
+
+    for _ in 0..20 {
+        let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+            .await
+            .unwrap();
+        for k in 0..KEYS {
+            let value = handle.get(format!("key_{k}").as_bytes()).await.unwrap();
+            assert_eq!(value, Some(expected(k)), "key_{k}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_rebuild_orders_by_seq_then_file_then_offset() {
+    let base_dir = tempdir().unwrap();
+
+    create_mock_data_file(
+        base_dir.path(),
+        0,
+        &[
+            (200, b"newer_seq".to_vec(), b"file_0".to_vec()),
+            (100, b"same_file".to_vec(), b"first".to_vec()),
+            (100, b"same_file".to_vec(), b"second".to_vec()),
+        ],
+    );
+    create_mock_data_file(
+        base_dir.path(),
+        1,
+        &[
+            (100, b"newer_seq".to_vec(), b"file_1".to_vec()),
+            (100, b"only_in_data".to_vec(), b"new_key".to_vec()),
+        ],
+    );
+
+    for _ in 0..20 {
+        let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+            .await
+            .unwrap();
+        assert_eq!(
+            handle.get(b"newer_seq").await.unwrap(),
+            Some(b"file_0".to_vec())
+        );
+        assert_eq!(
+            handle.get(b"same_file").await.unwrap(),
+            Some(b"second".to_vec())
+        );
+        assert_eq!(
+            handle.get(b"only_in_data").await.unwrap(),
+            Some(b"new_key".to_vec())
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_put_in_same_millisecond_overwrites() {
+    let base_dir = tempdir().unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    for i in 0..100 {
+        handle.put(b"key", format!("{i}").as_bytes()).await.unwrap();
+    }
+    assert_eq!(handle.get(b"key").await.unwrap(), Some(b"99".to_vec()));
+    drop(handle);
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"key").await.unwrap(), Some(b"99".to_vec()));
+}
+
+#[tokio::test]
+async fn test_seq_continues_after_reopen() {
+    let base_dir = tempdir().unwrap();
+
+    // 磁盘上已有的记录 seq 远大于写入次数，新写入的 seq 必须在它之后
+    create_mock_data_file(
+        base_dir.path(),
+        0,
+        &[
+            (1000, b"key".to_vec(), b"old".to_vec()),
+            (1001, b"other".to_vec(), b"old".to_vec()),
+        ],
+    );
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"key", b"new").await.unwrap();
+    handle.close().await.unwrap();
+
+    // 快照里的 max_seq 也要保留下来
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"other", b"new").await.unwrap();
+    drop(handle);
+
+    std::fs::remove_file(base_dir.path().join("keydir.snapshot")).unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"key").await.unwrap(), Some(b"new".to_vec()));
+    assert_eq!(handle.get(b"other").await.unwrap(), Some(b"new".to_vec()));
+}
+
+#[derive(Clone, Default)]
+struct ProgressConfig {
+    progress: Arc<Mutex<Vec<RebuildProgress>>>,
+}
+
+impl StorageConfig for ProgressConfig {
+    fn max_active_file_size(&self) -> u64 {
+        100
+    }
+
+    fn on_rebuild_progress(&self, progress: &RebuildProgress) {
+        self.progress.lock().unwrap().push(progress.clone());
+    }
+}
+
+#[tokio::test]
+async fn test_rebuild_reports_progress() {
+    let base_dir = tempdir().unwrap();
+
+    let handle = BitCaskHandle::<ProgressConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    for i in 0..20 {
+        handle
+            .put(format!("key_{i}").as_bytes(), b"value")
+            .await
+            .unwrap();
+    }
+    drop(handle);
+
+    let config = ProgressConfig::default();
+    let _handle = BitCaskHandle::open_with_config(base_dir.path(), config.clone())
+        .await
+        .unwrap();
+
+    let progress = config.progress.lock().unwrap();
+    assert!(!progress.is_empty());
+    for (i, p) in progress.iter().enumerate() {
+        assert_eq!(p.processed_files, i + 1);
+        assert_eq!(p.total_files, progress.len());
+    }
+    let last = progress.last().unwrap();
+    assert_eq!(last.processed_records, 20);
+    assert!(last.processed_bytes > 0);
+}
+
+#[derive(Clone)]
+struct ClockConfig {
+    clock: ManualClock,
+}
+
+impl StorageConfig for ClockConfig {
+    fn max_active_file_size(&self) -> u64 {
+        64 * 1024 * 1024
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(self.clock.clone())
+    }
+}
+
+#[tokio::test]
+async fn test_records_are_stamped_by_config_clock() {
+    let base_dir = tempdir().unwrap();
+
+    let clock = ManualClock::new(1_000);
+    let config = ClockConfig {
+        clock: clock.clone(),
+    };
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config)
+        .await
+        .unwrap();
+
+    handle.put(b"a", b"1").await.unwrap();
+    clock.advance(StdDuration::from_secs(5));
+    handle.put(b"b", b"2").await.unwrap();
+    // 时钟回拨不影响新旧判断
+    clock.set(0);
+    handle.put(b"a", b"3").await.unwrap();
+
+    let bytes = std::fs::read(base_dir.path().join(format!("{:08}.data", 0))).unwrap();
+    let timestamps: Vec<u64> = (0..3)
+        .map(|i| {
+            let start = (FILE_HEADER_SIZE + i * RECORD_SIZE_A) as usize + 12;
+            u64::from_le_bytes(bytes[start..start + 8].try_into().unwrap())
+        })
+        .collect();
+    assert_eq!(timestamps, vec![1_000, 6_000, 0]);
+
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"3".to_vec()));
+    drop(handle);
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"3".to_vec()));
+}
+
+#[derive(Clone)]
+struct AgeConfig {
+    clock: ManualClock,
+    check_interval: StdDuration,
+}
+
+impl StorageConfig for AgeConfig {
+    fn max_active_file_size(&self) -> u64 {
+        64 * 1024 * 1024
+    }
+
+    fn max_active_file_age(&self) -> Option<StdDuration> {
+        Some(StdDuration::from_secs(3600))
+    }
+
+    fn rotation_check_interval(&self) -> StdDuration {
+        self.check_interval
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(self.clock.clone())
+    }
+}
+
+#[tokio::test]
+async fn test_rotation_by_age_on_write() {
+    let base_dir = tempdir().unwrap();
+
+    let clock = ManualClock::new(0);
+    let config = AgeConfig {
+        clock: clock.clone(),
+        check_interval: StdDuration::from_secs(3600),
+    };
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config)
+        .await
+        .unwrap();
+
+    handle.put(b"a", b"1").await.unwrap();
+    clock.advance(StdDuration::from_secs(1800));
+    handle.put(b"b", b"2").await.unwrap();
+    assert_eq!(handle.active_file_id().await, 0);
+
+    clock.advance(StdDuration::from_secs(1800));
+    handle.put(b"c", b"3").await.unwrap();
+    assert_eq!(handle.active_file_id().await, 1);
+
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(handle.get(b"c").await.unwrap(), Some(b"3".to_vec()));
+}
+
+#[tokio::test]
+async fn test_rotation_by_age_in_background() {
+    let base_dir = tempdir().unwrap();
+
+    let clock = ManualClock::new(0);
+    let config = AgeConfig {
+        clock: clock.clone(),
+        check_interval: StdDuration::from_millis(10),
+    };
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config)
+        .await
+        .unwrap();
+
+    // 空文件不会因为超时被轮转
+    clock.advance(StdDuration::from_secs(7200));
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(handle.active_file_id().await, 0);
+
+    handle.put(b"a", b"1").await.unwrap();
+    clock.advance(StdDuration::from_secs(3600));
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(handle.active_file_id().await, 1);
+
+    // 新的 active file 还是空的，不会继续轮转
+    clock.advance(StdDuration::from_secs(7200));
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(handle.active_file_id().await, 1);
+
+    let meta = std::fs::metadata(base_dir.path().join(format!("{:08}.data", 0))).unwrap();
+    assert!(meta.permissions().readonly());
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+}
+
+#[tokio::test]
+async fn test_rotate_now() {
+    let base_dir = tempdir().unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+
+    handle.rotate_now().await.unwrap();
+    assert_eq!(handle.active_file_id().await, 0);
+
+    handle.put(b"a", b"1").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    assert_eq!(handle.active_file_id().await, 1);
+
+    handle.put(b"a", b"2").await.unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"2".to_vec()));
+    drop(handle);
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"2".to_vec()));
+}
+
+#[tokio::test]
+async fn test_delete_survives_reopen() {
+    let base_dir = tempdir().unwrap();
+    let config = TestConfig { max_file_size: 64 };
+
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config.clone())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.put(b"c", b"3").await.unwrap();
+
+    assert!(handle.delete(b"a").await.unwrap());
+    assert!(!handle.delete(b"missing").await.unwrap());
+    assert_eq!(handle.get(b"a").await.unwrap(), None);
+    // 没有快照，靠重放 tombstone 恢复删除
+    drop(handle);
+
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config)
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), None);
+    assert_eq!(handle.keys(), vec![b"b".to_vec(), b"c".to_vec()]);
+}
+
+#[tokio::test]
+async fn test_scan_prefix() {
+    let base_dir = tempdir().unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"user:2", b"bob").await.unwrap();
+    handle.put(b"user:1", b"alice").await.unwrap();
+    handle.put(b"order:1", b"book").await.unwrap();
+    handle.put(b"user:1", b"carol").await.unwrap();
+
+    let pairs = handle.scan_prefix(b"user:").await.unwrap();
+    assert_eq!(
+        pairs,
+        vec![
+            (b"user:1".to_vec(), b"carol".to_vec()),
+            (b"user:2".to_vec(), b"bob".to_vec()),
+        ]
+    );
+    assert_eq!(handle.scan_prefix(b"").await.unwrap().len(), 3);
+    assert!(handle.scan_prefix(b"none").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_merge_drops_stale_records() {
+    let base_dir = tempdir().unwrap();
+    let config = TestConfig { max_file_size: 100 };
+
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config.clone())
+        .await
+        .unwrap();
+    for i in 0..20u8 {
+        handle.put(&[b'k', i % 4], &[i]).await.unwrap();
+    }
+    handle.delete(&[b'k', 0]).await.unwrap();
+    handle.put(b"x", b"last").await.unwrap();
+
+    let before = handle.stats().await.unwrap();
+    let report = handle.merge().await.unwrap();
+    assert_eq!(report.live_records, 3);
+    assert!(report.reclaimed_bytes > 0);
+
+    let after = handle.stats().await.unwrap();
+    assert!(after.data_bytes < before.data_bytes);
+    assert_eq!(after.hint_files, report.output_files);
+    for i in 1..4u8 {
+        assert_eq!(
+            handle.get(&[b'k', i]).await.unwrap(),
+            Some(vec![16 + i]),
+            "key k{i}"
+        );
+    }
+    assert_eq!(handle.get(&[b'k', 0]).await.unwrap(), None);
+    assert_eq!(handle.get(b"x").await.unwrap(), Some(b"last".to_vec()));
+
+    // merge 之后继续写入，重新打开（从 hint 文件重建）结果不变
+    handle.put(&[b'k', 1], b"new").await.unwrap();
+    drop(handle);
+
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config)
+        .await
+        .unwrap();
+    assert_eq!(handle.get(&[b'k', 0]).await.unwrap(), None);
+    assert_eq!(handle.get(&[b'k', 1]).await.unwrap(), Some(b"new".to_vec()));
+    assert_eq!(handle.get(&[b'k', 2]).await.unwrap(), Some(vec![18]));
+    assert_eq!(handle.get(b"x").await.unwrap(), Some(b"last".to_vec()));
+    assert!(handle.verify().await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_verify_detects_corruption() {
+    let base_dir = tempdir().unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    let report = handle.verify().await.unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.records, 2);
+    drop(handle);
+
+    // 改掉第一条记录的 value，再在文件尾部追加半条记录
+    let data_path = base_dir.path().join(format!("{:08}.data", 0));
+    let mut bytes = std::fs::read(&data_path).unwrap();
+    bytes[(FILE_HEADER_SIZE + RECORD_SIZE_A) as usize - 1] = b'9';
+    bytes.extend_from_slice(&[0u8; 10]);
+    std::fs::write(&data_path, &bytes).unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    let report = handle.verify().await.unwrap();
+    assert_eq!(report.issues.len(), 2, "{:?}", report.issues);
+    assert_eq!(report.issues[0].offset, FILE_HEADER_SIZE);
+    assert_eq!(
+        report.issues[1].offset,
+        FILE_HEADER_SIZE + 2 * RECORD_SIZE_A
+    );
+}
+
+#[tokio::test]
+async fn test_concurrent_writers_share_handle() {
+    let base_dir = tempdir().unwrap();
+    let config = TestConfig { max_file_size: 256 };
+    let handle = Arc::new(
+        BitCaskHandle::open_with_config(base_dir.path(), config.clone())
+            .await
+            .unwrap(),
+    );
+
+    let mut tasks = Vec::new();
+    for t in 0..8u8 {
+        let handle = handle.clone();
+        tasks.push(tokio::spawn(async move {
+            for i in 0..50u8 {
+                handle.put(&[t, i % 5], &[t, i]).await.unwrap();
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(handle.len(), 40);
+    drop(handle);
+
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config)
+        .await
+        .unwrap();
+    for t in 0..8u8 {
+        for k in 0..5u8 {
+            assert_eq!(handle.get(&[t, k]).await.unwrap(), Some(vec![t, 45 + k]));
+        }
+    }
+}
+
+async fn start_resp_server(
+    dir: &Path,
+) -> (
+    std::net::SocketAddr,
+    tokio::sync::oneshot::Sender<()>,
+    tokio::task::JoinHandle<std::io::Result<()>>,
+) {
+    let handle = Arc::new(BitCaskHandle::<BitCaskConfig>::open(dir).await.unwrap());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let server = tokio::spawn(bitcask::server::resp::serve(listener, handle, async {
+        let _ = rx.await;
+    }));
+    (addr, tx, server)
+}
+
+async fn resp_roundtrip(stream: &mut tokio::net::TcpStream, request: &[u8], expected: &[u8]) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    stream.write_all(request).await.unwrap();
+    let mut response = vec![0u8; expected.len()];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(
+        response.escape_ascii().to_string(),
+        expected.escape_ascii().to_string(),
+        "request: {}",
+        request.escape_ascii()
+    );
+}
+
+#[tokio::test]
+async fn test_resp_server_commands() {
+    let base_dir = tempdir().unwrap();
+    let (addr, shutdown, server) = start_resp_server(base_dir.path()).await;
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+    resp_roundtrip(&mut stream, b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n").await;
+    resp_roundtrip(
+        &mut stream,
+        b"*3\r\n$3\r\nSET\r\n$6\r\nuser:1\r\n$5\r\nalice\r\n",
+        b"+OK\r\n",
+    )
+    .await;
+    // inline 命令
+    resp_roundtrip(&mut stream, b"set user:2 bob\r\n", b"+OK\r\n").await;
+    resp_roundtrip(&mut stream, b"SET order:1 book\r\n", b"+OK\r\n").await;
+    resp_roundtrip(
+        &mut stream,
+        b"*2\r\n$3\r\nGET\r\n$6\r\nuser:1\r\n",
+        b"$5\r\nalice\r\n",
+    )
+    .await;
+    resp_roundtrip(&mut stream, b"GET nope\r\n", b"$-1\r\n").await;
+    resp_roundtrip(&mut stream, b"EXISTS user:1 user:2 nope\r\n", b":2\r\n").await;
+    resp_roundtrip(&mut stream, b"DBSIZE\r\n", b":3\r\n").await;
+    resp_roundtrip(
+        &mut stream,
+        b"KEYS user:*\r\n",
+        b"*2\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n",
+    )
+    .await;
+    resp_roundtrip(
+        &mut stream,
+        b"SCAN 0 COUNT 2\r\n",
+        b"*2\r\n$1\r\n1\r\n*2\r\n$7\r\norder:1\r\n$6\r\nuser:1\r\n",
+    )
+    .await;
+    resp_roundtrip(
+        &mut stream,
+        b"SCAN 1 MATCH user:? COUNT 2\r\n",
+        b"*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:2\r\n",
+    )
+    .await;
+    resp_roundtrip(&mut stream, b"DEL user:1 nope\r\n", b":1\r\n").await;
+    resp_roundtrip(&mut stream, b"DBSIZE\r\n", b":2\r\n").await;
+    resp_roundtrip(
+        &mut stream,
+        b"GET\r\n",
+        b"-ERR wrong number of arguments for 'get' command\r\n",
+    )
+    .await;
+    resp_roundtrip(
+        &mut stream,
+        b"FLUSHALL\r\n",
+        b"-ERR unknown command 'FLUSHALL'\r\n",
+    )
+    .await;
+    // pipeline
+    resp_roundtrip(
+        &mut stream,
+        b"SET a 1\r\nGET a\r\nPING\r\n",
+        b"+OK\r\n$1\r\n1\r\n+PONG\r\n",
+    )
+    .await;
+
+    shutdown.send(()).unwrap();
+    server.await.unwrap().unwrap();
+
+    // 服务停止后 handle 已经释放，重新打开能看到写入的数据
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"user:2").await.unwrap(), Some(b"bob".to_vec()));
+    assert_eq!(handle.get(b"user:1").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_resp_server_info_and_protocol_error() {
+    use tokio::io::AsyncReadExt;
+
+    let base_dir = tempdir().unwrap();
+    let (addr, shutdown, server) = start_resp_server(base_dir.path()).await;
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+    resp_roundtrip(&mut stream, b"SET k v\r\n", b"+OK\r\n").await;
+    tokio::io::AsyncWriteExt::write_all(&mut stream, b"INFO\r\n")
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 4096];
+    let n = stream.read(&mut buf).await.unwrap();
+    let info = String::from_utf8_lossy(&buf[..n]);
+    assert!(info.starts_with('$'), "{info}");
+    assert!(info.contains("db0:keys=1"), "{info}");
+
+    resp_roundtrip(
+        &mut stream,
+        b"*1\r\n$x\r\n",
+        b"-ERR Protocol error: invalid bulk length\r\n",
+    )
+    .await;
+    // 协议错误后连接被关闭
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+    shutdown.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_memcached_server_commands() {
+    use tokio::io::AsyncReadExt;
+
+    let base_dir = tempdir().unwrap();
+    let clock = ManualClock::new(1_000_000);
+    let config = ClockConfig {
+        clock: clock.clone(),
+    };
+    let handle = Arc::new(
+        BitCaskHandle::open_with_config(base_dir.path(), config)
+            .await
+            .unwrap(),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown, rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(bitcask::server::memcached::serve(
+        listener,
+        handle.clone(),
+        async {
+            let _ = rx.await;
+        },
+    ));
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+    resp_roundtrip(&mut stream, b"set a 42 0 5\r\nhello\r\n", b"STORED\r\n").await;
+    resp_roundtrip(
+        &mut stream,
+        b"get a nope\r\n",
+        b"VALUE a 42 5\r\nhello\r\nEND\r\n",
+    )
+    .await;
+    resp_roundtrip(&mut stream, b"add a 0 0 1\r\nx\r\n", b"NOT_STORED\r\n").await;
+    resp_roundtrip(&mut stream, b"replace b 0 0 1\r\nx\r\n", b"NOT_STORED\r\n").await;
+    resp_roundtrip(&mut stream, b"add b 7 0 1\r\nx\r\n", b"STORED\r\n").await;
+
+    let version = handle.get_with_meta(b"a").await.unwrap().unwrap().1.version;
+    let expected = format!("VALUE a 42 5 {version}\r\nhello\r\nEND\r\n");
+    resp_roundtrip(&mut stream, b"gets a\r\n", expected.as_bytes()).await;
+    let stale = format!("cas a 1 0 2 {}\r\nhi\r\n", version + 100);
+    resp_roundtrip(&mut stream, stale.as_bytes(), b"EXISTS\r\n").await;
+    let current = format!("cas a 1 0 2 {version}\r\nhi\r\n");
+    resp_roundtrip(&mut stream, current.as_bytes(), b"STORED\r\n").await;
+    resp_roundtrip(&mut stream, b"cas c 0 0 1 1\r\nx\r\n", b"NOT_FOUND\r\n").await;
+
+    // noreply 不回复，紧跟的 get 能看到结果
+    resp_roundtrip(
+        &mut stream,
+        b"set t 0 10 1 noreply\r\nx\r\nget t\r\n",
+        b"VALUE t 0 1\r\nx\r\nEND\r\n",
+    )
+    .await;
+    clock.advance(StdDuration::from_secs(11));
+    resp_roundtrip(&mut stream, b"get t\r\n", b"END\r\n").await;
+
+    resp_roundtrip(&mut stream, b"delete b\r\n", b"DELETED\r\n").await;
+    resp_roundtrip(&mut stream, b"delete b\r\n", b"NOT_FOUND\r\n").await;
+    resp_roundtrip(
+        &mut stream,
+        b"set a x 0 1\r\nx\r\n",
+        b"CLIENT_ERROR bad command line format\r\n",
+    )
+    .await;
+    // 参数错误时数据块也被读掉，连接还能继续用
+    resp_roundtrip(
+        &mut stream,
+        b"cas a 0 0 2 nope\r\nzz\r\nget a\r\n",
+        b"CLIENT_ERROR bad command line format\r\nVALUE a 1 2\r\nhi\r\nEND\r\n",
+    )
+    .await;
+    resp_roundtrip(&mut stream, b"flush_all\r\n", b"ERROR\r\n").await;
+    // 很大的绝对过期时间不会溢出
+    resp_roundtrip(
+        &mut stream,
+        b"set far 0 9223372036854775807 1\r\nx\r\nget far\r\n",
+        b"STORED\r\nVALUE far 0 1\r\nx\r\nEND\r\n",
+    )
+    .await;
+
+    // 数据块长度加上 `\r\n` 溢出时回复错误并断开
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    resp_roundtrip(
+        &mut stream,
+        b"set k 0 0 18446744073709551615\r\n",
+        b"CLIENT_ERROR bad data chunk\r\n",
+    )
+    .await;
+    let mut buf = [0u8; 16];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+    shutdown.send(()).unwrap();
+    server.await.unwrap().unwrap();
+
+    let handle = Arc::into_inner(handle).unwrap();
+    let (value, meta) = handle.get_with_meta(b"a").await.unwrap().unwrap();
+    assert_eq!((value, meta.flags), (b"hi".to_vec(), 1));
+}
+
+#[tokio::test]
+async fn test_put_with_conditions_and_expiry() {
+    use bitcask::{PutCondition, PutOptions, PutOutcome};
+
+    let base_dir = tempdir().unwrap();
+    let clock = ManualClock::new(1_000);
+    let config = ClockConfig {
+        clock: clock.clone(),
+    };
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config.clone())
+        .await
+        .unwrap();
+
+    let absent = PutOptions {
+        condition: PutCondition::Absent,
+        ..Default::default()
+    };
+    let PutOutcome::Stored { version } = handle.put_with(b"k", b"1", absent).await.unwrap() else {
+        panic!("first add should be stored");
+    };
+    assert_eq!(
+        handle.put_with(b"k", b"2", absent).await.unwrap(),
+        PutOutcome::NotStored
+    );
+    let cas = |version| PutOptions {
+        condition: PutCondition::Version(version),
+        ..Default::default()
+    };
+    assert_eq!(
+        handle.put_with(b"k", b"2", cas(version + 1)).await.unwrap(),
+        PutOutcome::NotStored
+    );
+    assert!(matches!(
+        handle.put_with(b"k", b"2", cas(version)).await.unwrap(),
+        PutOutcome::Stored { .. }
+    ));
+    assert_eq!(
+        handle.put_with(b"x", b"2", cas(version)).await.unwrap(),
+        PutOutcome::NotFound
+    );
+
+    let expiring = PutOptions {
+        flags: 9,
+        expire_at_ms: 2_000,
+        ..Default::default()
+    };
+    handle.put_with(b"t", b"v", expiring).await.unwrap();
+    assert_eq!(handle.len(), 2);
+    clock.set(2_000);
+    assert_eq!(handle.get(b"t").await.unwrap(), None);
+    assert_eq!(handle.keys(), vec![b"k".to_vec()]);
+    // 过期的 key 可以重新 add
+    assert!(matches!(
+        handle.put_with(b"t", b"w", absent).await.unwrap(),
+        PutOutcome::Stored { .. }
+    ));
+    handle.put_with(b"u", b"v", expiring).await.unwrap();
+    handle.merge().await.unwrap();
+    drop(handle);
+
+    let handle = BitCaskHandle::open_with_config(base_dir.path(), config)
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"k").await.unwrap(), Some(b"2".to_vec()));
+    assert_eq!(handle.get(b"t").await.unwrap(), Some(b"w".to_vec()));
+    assert!(!handle.contains_key(b"u"));
+    assert_eq!(handle.len(), 2);
+}
+
+// header(44) + key(1) + value(1)
+const RECORD_SIZE_A: u64 = 46;
+// 数据文件和 hint 文件开头的 magic + 格式版本
+const FILE_HEADER_SIZE: u64 = 8;
+
+/// 新建的 mock 文件先写文件头
+fn write_mock_file_header(writer: &mut BufWriter<std::fs::File>, magic: &[u8; 4]) {
+    if writer.get_ref().metadata().unwrap().len() == 0 {
+        writer.write_all(magic).unwrap();
+        writer.write_all(&1u32.to_le_bytes()).unwrap();
+    }
+}
+
+fn create_mock_hint_file(dir: &Path, file_id: u64, entries: &[(u32, u64, &[u8])]) {
+    let path = dir.join(format!("{file_id:08}.hint"));
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap();
+    let mut writer = BufWriter::new(file);
+    write_mock_file_header(&mut writer, b"BCKH");
+    for (value_size, value_pos, key) in entries {
+        // seq、timestamp、expire_at、namespace
+        writer.write_all(&0u64.to_le_bytes()).unwrap();
+        writer.write_all(&0u64.to_le_bytes()).unwrap();
+        writer.write_all(&0u64.to_le_bytes()).unwrap();
+        writer.write_all(&0u32.to_le_bytes()).unwrap();
+        writer.write_all(&(key.len() as u32).to_le_bytes()).unwrap();
+        writer.write_all(&value_size.to_le_bytes()).unwrap();
+        writer.write_all(&value_pos.to_le_bytes()).unwrap();
+        writer.write_all(key).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+fn create_mock_data_file(dir: &Path, file_id: u64, records: &[(u64, Vec<u8>, Vec<u8>)]) {
+    let path = dir.join(format!("{file_id:08}.data"));
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap();
+    let mut writer = BufWriter::new(file);
+    write_mock_file_header(&mut writer, b"BCKD");
+    for (seq, key, value) in records {
+        let mut header = Vec::with_capacity(40);
+        header.extend_from_slice(&seq.to_le_bytes());
+        // timestamp、expire_at、flags、namespace
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(value.len() as u32).to_le_bytes());
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        hasher.update(key);
+        hasher.update(value);
+        writer.write_all(&hasher.finalize().to_le_bytes()).unwrap();
+        writer.write_all(&header).unwrap();
+        writer.write_all(key).unwrap();
+        writer.write_all(value).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+#[tokio::test]
+async fn test_binary_client_over_tcp_and_unix_socket() {
+    use bitcask::client::{BatchOp, BatchResult, Client};
+
+    let base_dir = tempdir().unwrap();
+    let socket_dir = tempdir().unwrap();
+    let socket_path = socket_dir.path().join("bitcask.sock");
+    let handle = Arc::new(
+        BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+            .await
+            .unwrap(),
+    );
+
+    let (shutdown, rx) = tokio::sync::watch::channel(());
+    let shutdown_signal = |mut rx: tokio::sync::watch::Receiver<()>| async move {
+        let _ = rx.changed().await;
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tcp_server = tokio::spawn(bitcask::server::binary::serve(
+        listener,
+        handle.clone(),
+        shutdown_signal(rx.clone()),
+    ));
+    let listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+    let unix_server = tokio::spawn(bitcask::server::binary::serve_unix(
+        listener,
+        handle.clone(),
+        shutdown_signal(rx),
+    ));
+
+    let tcp = Client::connect(addr).await.unwrap();
+    let unix = Client::connect_unix(&socket_path).await.unwrap();
+
+    tcp.put(b"user:1", b"alice").await.unwrap();
+    assert_eq!(unix.get(b"user:1").await.unwrap(), Some(b"alice".to_vec()));
+    assert_eq!(unix.get(b"nope").await.unwrap(), None);
+    // 空 value 和空 key 也能正确编码
+    unix.put(b"", b"").await.unwrap();
+    assert_eq!(tcp.get(b"").await.unwrap(), Some(Vec::new()));
+    assert!(tcp.delete(b"").await.unwrap());
+    assert!(!tcp.delete(b"").await.unwrap());
+
+    let results = tcp
+        .batch(vec![
+            BatchOp::Put(b"user:2".to_vec(), b"bob".to_vec()),
+            BatchOp::Put(b"user:3".to_vec(), b"carol".to_vec()),
+            BatchOp::Get(b"user:2".to_vec()),
+            BatchOp::Delete(b"user:3".to_vec()),
+            BatchOp::Get(b"user:3".to_vec()),
+            BatchOp::Delete(b"nope".to_vec()),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(
+        results,
+        vec![
+            BatchResult::Stored,
+            BatchResult::Stored,
+            BatchResult::Value(Some(b"bob".to_vec())),
+            BatchResult::Deleted(true),
+            BatchResult::Value(None),
+            BatchResult::Deleted(false),
+        ]
+    );
+
+    for i in 0..5 {
+        unix.put(format!("order:{i}").as_bytes(), b"x")
+            .await
+            .unwrap();
+    }
+    let page = unix.scan(b"order:", None, 2).await.unwrap();
+    let keys: Vec<Vec<u8>> = page.entries.iter().map(|(key, _)| key.clone()).collect();
+    assert_eq!(keys, vec![b"order:0".to_vec(), b"order:1".to_vec()]);
+    let page = unix
+        .scan(b"order:", page.next_cursor.as_deref(), 3)
+        .await
+        .unwrap();
+    assert_eq!(page.entries.len(), 3);
+    assert_eq!(page.next_cursor, None);
+    let users = tcp.scan_prefix(b"user:").await.unwrap();
+    assert_eq!(
+        users,
+        vec![
+            (b"user:1".to_vec(), b"alice".to_vec()),
+            (b"user:2".to_vec(), b"bob".to_vec()),
+        ]
+    );
+    // 第一页不会跳过空 key
+    tcp.put(b"", b"").await.unwrap();
+    let page = tcp.scan(b"", None, 1).await.unwrap();
+    assert_eq!(page.entries, vec![(Vec::new(), Vec::new())]);
+
+    // 调用中途被取消之后连接不能再用，而不是读到上一个请求的响应
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let interrupted = Client::connect(silent.local_addr().unwrap()).await.unwrap();
+    let call = interrupted.get(b"user:1");
+    assert!(
+        tokio::time::timeout(StdDuration::from_millis(20), call)
+            .await
+            .is_err()
+    );
+    let err = interrupted.get(b"user:2").await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+    drop((tcp, unix));
+    shutdown.send(()).unwrap();
+    tcp_server.await.unwrap().unwrap();
+    unix_server.await.unwrap().unwrap();
+    assert_eq!(Arc::into_inner(handle).unwrap().len(), 8);
+}
+
+async fn http_request(addr: std::net::SocketAddr, request: &[u8]) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[tokio::test]
+async fn test_http_server() {
+    let base_dir = tempdir().unwrap();
+    let handle = Arc::new(
+        BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+            .await
+            .unwrap(),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown, rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(bitcask::server::http::serve(
+        listener,
+        handle.clone(),
+        async {
+            let _ = rx.await;
+        },
+    ));
+
+    // keep-alive 连接上的多个请求，key 里有 `/` 和不可打印字节
+    let response = http_request(
+        addr,
+        b"PUT /kv/a%2Fb%00 HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello\
+          GET /kv/a%2Fb%00 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(
+        response,
+        "HTTP/1.1 204 No Content\r\n\r\n\
+         HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: 5\r\n\
+         Connection: close\r\n\r\nhello"
+    );
+    assert_eq!(handle.get(b"a/b\0").await.unwrap(), Some(b"hello".to_vec()));
+
+    let response = http_request(
+        addr,
+        b"PUT /kv/big HTTP/1.1\r\nContent-Length: 3\r\nExpect: 100-continue\r\nConnection: close\r\n\r\nxyz",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 204 No Content\r\n"));
+
+    // 超过其他协议上限的 value 也能写入和读出
+    let large = vec![b'v'; 17 * 1024 * 1024];
+    let mut request = format!(
+        "PUT /kv/large HTTP/1.0\r\nContent-Length: {}\r\n\r\n",
+        large.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&large);
+    let response = http_request(addr, &request).await;
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+    let response = http_request(addr, b"GET /kv/large HTTP/1.0\r\n\r\n").await;
+    assert!(response.contains(&format!("Content-Length: {}\r\n", large.len())));
+    assert!(handle.delete(b"large").await.unwrap());
+
+    let response = http_request(addr, b"GET /keys?prefix=a HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\na%2Fb%00\n"));
+
+    let response = http_request(addr, b"GET /stats HTTP/1.0\r\n\r\n").await;
+    assert!(response.contains("\"key_count\":2"), "{response}");
+
+    let response = http_request(addr, b"DELETE /kv/big HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+    let response = http_request(addr, b"DELETE /kv/big HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    let response = http_request(addr, b"GET /kv/big HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    let response = http_request(addr, b"GET /kv/%zz HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    let response = http_request(addr, b"PUT /kv/x HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 411 Length Required\r\n"));
+    // 声明的长度超过上限时直接拒绝，不会按它分配内存
+    let response = http_request(
+        addr,
+        b"PUT /kv/x HTTP/1.0\r\nContent-Length: 4294967295\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    let response = http_request(addr, b"POST /stats HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(response.contains("Allow: GET, HEAD\r\n"));
+    let response = http_request(addr, b"garbage\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    shutdown.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_admin_socket_commands() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tracing_subscriber::{Registry, reload};
+
+    let base_dir = tempdir().unwrap();
+    let socket_dir = tempdir().unwrap();
+    let socket_path = socket_dir.path().join("admin.sock");
+    let handle = Arc::new(
+        BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+            .await
+            .unwrap(),
+    );
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"a", b"2").await.unwrap();
+
+    // layer 要一直活着，handle 才能修改它
+    let (_filter_layer, log_control) = reload::Layer::<_, Registry>::new(EnvFilter::new("warn"));
+    let listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+    let (shutdown, rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(bitcask::server::admin::serve(
+        listener,
+        handle.clone(),
+        Some(Arc::new(log_control)),
+        async {
+            let _ = rx.await;
+        },
+    ));
+
+    let stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut command = async |line: &str| {
+        writer
+            .write_all(format!("{line}\n").as_bytes())
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        loop {
+            let line = lines.next_line().await.unwrap().unwrap();
+            if line == "OK" || line.starts_with("ERR ") {
+                output.push(line);
+                return output;
+            }
+            output.push(line);
+        }
+    };
+
+    assert_eq!(command("sync").await, vec!["OK"]);
+    assert_eq!(command("rotate").await, vec!["active file id: 1", "OK"]);
+    let output = command("merge").await;
+    assert!(output[0].starts_with("merged 1 files into 1: 1 live records kept, 1 dropped"));
+    let output = command("stats").await;
+    assert_eq!(output[0], "keys:           1");
+    let output = command("verify").await;
+    assert!(output[0].ends_with(": 0 problems"), "{output:?}");
+    assert_eq!(command("log-level").await, vec!["warn", "OK"]);
+    assert_eq!(
+        command("log-level bitcask=debug").await,
+        vec!["bitcask=debug", "OK"]
+    );
+    assert!(command("log-level [").await[0].starts_with("ERR "));
+    assert_eq!(command("io-rate").await, vec!["off", "OK"]);
+    assert_eq!(
+        command("io-rate 1048576").await,
+        vec!["1048576 bytes/s", "OK"]
+    );
+    assert_eq!(handle.background_io_rate(), Some(1048576));
+    assert!(command("io-rate fast").await[0].starts_with("ERR "));
+    assert!(command("merge now").await[0].starts_with("ERR "));
+    assert!(command("checkpoint").await[0].starts_with("ERR "));
+    assert!(command("flush").await[0].starts_with("ERR unknown command"));
+
+    shutdown.send(()).unwrap();
+    server.await.unwrap().unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"2".to_vec()));
+}
+
+#[test]
+fn test_blocking_store_from_plain_threads() {
+    use bitcask::blocking::Store;
+
+    let base_dir = tempdir().unwrap();
+    let store = Arc::new(Store::<BitCaskConfig>::open(base_dir.path()).unwrap());
+
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for i in 0..25 {
+                    let key = format!("t{t}:{i:02}");
+                    store.put(key.as_bytes(), key.as_bytes()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get(b"t2:07").unwrap(), Some(b"t2:07".to_vec()));
+    assert!(store.delete(b"t2:07").unwrap());
+    assert!(!store.delete(b"t2:07").unwrap());
+    assert_eq!(store.get(b"t2:07").unwrap(), None);
+    let pairs = store.scan_prefix(b"t3:").unwrap();
+    assert_eq!(pairs.len(), 25);
+    assert_eq!(pairs[0], (b"t3:00".to_vec(), b"t3:00".to_vec()));
+
+    Arc::into_inner(store).unwrap().close().unwrap();
+
+    let store = Store::<BitCaskConfig>::open(base_dir.path()).unwrap();
+    assert_eq!(store.len(), 99);
+    assert_eq!(store.get(b"t0:24").unwrap(), Some(b"t0:24".to_vec()));
+    // 不调用 close 直接 drop 也能正常停掉 runtime 线程
+    drop(store);
+}
+
+#[test]
+fn test_c_ffi_program() {
+    use std::process::Command;
+
+    // 测试可执行文件在 target/<profile>/deps 下，cdylib 在 target/<profile> 下
+    let exe = std::env::current_exe().unwrap();
+    let lib_dir = exe.parent().unwrap().parent().unwrap();
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let build_dir = tempdir().unwrap();
+    let program = build_dir.path().join("bitcask_test");
+
+    let compiled = Command::new("cc")
+        .arg(manifest_dir.join("tests/ffi/bitcask_test.c"))
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg("-L")
+        .arg(lib_dir)
+        .arg("-lbitcask")
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .unwrap();
+    assert!(compiled.success());
+
+    let data_dir = tempdir().unwrap();
+    let output = Command::new(&program)
+        .arg(data_dir.path())
+        .env("LD_LIBRARY_PATH", lib_dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, b"ok\n");
+}
+
+#[tokio::test]
+async fn test_namespaces_are_isolated_and_dropped_by_merge() {
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+
+    handle.put(b"k", b"default").await.unwrap();
+    let users = handle.namespace("users").await.unwrap();
+    let orders = handle.namespace("orders").await.unwrap();
+    users.put(b"k", b"alice").await.unwrap();
+    users.put(b"k2", b"bob").await.unwrap();
+    orders.put(b"k", b"order-1").await.unwrap();
+    assert!(orders.delete(b"k").await.unwrap());
+    orders.put(b"o1", b"x").await.unwrap();
+
+    assert_eq!(handle.get(b"k").await.unwrap(), Some(b"default".to_vec()));
+    assert_eq!(users.get(b"k").await.unwrap(), Some(b"alice".to_vec()));
+    assert_eq!(orders.get(b"k").await.unwrap(), None);
+    assert_eq!(handle.keys(), vec![b"k".to_vec()]);
+    assert_eq!(users.keys(), vec![b"k".to_vec(), b"k2".to_vec()]);
+    assert_eq!(users.stats().key_count, 2);
+    assert_eq!(users.stats().value_bytes, 8);
+    assert_eq!(handle.len(), 1);
+    assert_eq!(handle.stats().await.unwrap().key_count, 4);
+    assert_eq!(handle.namespaces(), vec!["orders", "users"]);
+    assert!(handle.namespace("").await.is_err());
+
+    assert!(handle.drop_namespace("users").await.unwrap());
+    assert!(!handle.drop_namespace("users").await.unwrap());
+    assert_eq!(users.get(b"k").await.unwrap(), None);
+    assert!(users.put(b"k", b"again").await.is_err());
+    assert_eq!(handle.namespaces(), vec!["orders"]);
+    // 同名的 namespace 重新创建后是空的
+    let users = handle.namespace("users").await.unwrap();
+    assert!(users.is_empty());
+    users.put(b"new", b"1").await.unwrap();
+    drop(handle);
+
+    // 被删除的 namespace 在重启后也不可见
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.namespaces(), vec!["orders", "users"]);
+    let users = handle.namespace("users").await.unwrap();
+    assert_eq!(users.keys(), vec![b"new".to_vec()]);
+    assert_eq!(
+        handle.namespace("orders").await.unwrap().keys(),
+        vec![b"o1".to_vec()]
+    );
+
+    handle.rotate_now().await.unwrap();
+    let report = handle.merge().await.unwrap();
+    // default/k、orders/o1、users/new 和三条注册表记录
+    assert_eq!(report.live_records, 6);
+    assert!(report.dropped_records > 0);
+    handle.close().await.unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"k").await.unwrap(), Some(b"default".to_vec()));
+    let users = handle.namespace("users").await.unwrap();
+    assert_eq!(users.get(b"new").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(users.get(b"k").await.unwrap(), None);
+    assert!(handle.verify().await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_sharded_store_routes_and_aggregates() {
+    use bitcask::ShardedBitCask;
+
+    let base_dir = tempdir().unwrap();
+    let store = Arc::new(
+        ShardedBitCask::<BitCaskConfig>::open(base_dir.path(), 4)
+            .await
+            .unwrap(),
+    );
+
+    let mut tasks = Vec::new();
+    for task in 0..4 {
+        let store = store.clone();
+        tasks.push(tokio::spawn(async move {
+            for i in 0..25 {
+                let key = format!("key_{:03}", task * 25 + i);
+                store.put(key.as_bytes(), b"value").await.unwrap();
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    assert_eq!(store.len(), 100);
+    let keys = store.keys();
+    assert_eq!(keys.len(), 100);
+    assert!(keys.is_sorted());
+    let stats = store.stats().await.unwrap();
+    assert_eq!(stats.key_count, 100);
+    assert_eq!(stats.shards.len(), 4);
+    // 所有分片都分到了 key
+    assert!(stats.shards.iter().all(|shard| shard.key_count > 0));
+
+    assert!(store.delete(b"key_000").await.unwrap());
+    store.put(b"key_001", b"new").await.unwrap();
+    let pairs = store.scan_prefix(b"key_00").await.unwrap();
+    assert_eq!(pairs.len(), 9);
+    assert_eq!(pairs[0], (b"key_001".to_vec(), b"new".to_vec()));
+
+    store.rotate_now().await.unwrap();
+    let report = store.merge().await.unwrap();
+    assert_eq!(report.live_records, 99);
+    Arc::into_inner(store).unwrap().close().await.unwrap();
+
+    // 分片数必须和第一次打开时一致
+    assert!(
+        ShardedBitCask::<BitCaskConfig>::open(base_dir.path(), 8)
+            .await
+            .is_err()
+    );
+    let store = ShardedBitCask::<BitCaskConfig>::open(base_dir.path(), 4)
+        .await
+        .unwrap();
+    assert_eq!(store.len(), 99);
+    assert_eq!(store.get(b"key_001").await.unwrap(), Some(b"new".to_vec()));
+    assert_eq!(store.get(b"key_000").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_file_stats_track_live_and_dead_bytes() {
+    use bitcask::FileStats;
+
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.put(b"c", b"3").await.unwrap();
+    handle.put(b"a", b"4").await.unwrap();
+    handle.delete(b"b").await.unwrap();
+
+    // 覆盖掉的 a、删除的 b 和 tombstone（没有 value）都是垃圾
+    let expected = FileStats {
+        file_id: 0,
+        live_keys: 2,
+        live_bytes: 2 * RECORD_SIZE_A,
+        dead_bytes: 2 * RECORD_SIZE_A + RECORD_SIZE_A - 1,
+        kept_bytes: 0,
+    };
+    assert_eq!(handle.file_stats(), vec![expected.clone()]);
+    let stats = handle.stats().await.unwrap();
+    // 文件头不算存活也不算垃圾
+    assert_eq!(
+        stats.live_bytes + stats.dead_bytes + FILE_HEADER_SIZE,
+        stats.data_bytes
+    );
+    assert!(stats.dead_ratio() > 0.5);
+    drop(handle);
+
+    // 重新打开后从 keydir 和文件长度算出同样的结果
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.file_stats()[0], expected);
+
+    handle.rotate_now().await.unwrap();
+    handle.merge().await.unwrap();
+    let stats = handle.stats().await.unwrap();
+    assert_eq!(stats.dead_bytes, 0);
+    assert_eq!(stats.live_bytes, 2 * RECORD_SIZE_A);
+    assert!(stats.files.iter().all(|file| file.file_id != 0));
+}
+
+#[tokio::test]
+async fn test_merge_scheduler_merges_fragmented_files() {
+    use bitcask::{MergePolicy, MergeScheduler, MergeWindow};
+
+    let hour = Duration::from_secs(60 * 60);
+    let window = MergeWindow {
+        start: 22 * hour,
+        end: 2 * hour,
+    };
+    assert!(window.contains((23 * hour).as_millis() as u64));
+    assert!(window.contains((24 * hour + hour).as_millis() as u64));
+    assert!(!window.contains((12 * hour).as_millis() as u64));
+
+    let base_dir = tempdir().unwrap();
+    let config = BitCaskConfig {
+        merge_policy: Some(MergePolicy {
+            min_dead_ratio: 0.5,
+            check_interval: Duration::from_millis(10),
+            ..MergePolicy::default()
+        }),
+        ..BitCaskConfig::default()
+    };
+    let handle = Arc::new(
+        BitCaskHandle::open_with_config(base_dir.path(), config)
+            .await
+            .unwrap(),
+    );
+    // 文件 0 里三条记录有两条变成垃圾，文件 1 里只有新的 a 和 c 的 tombstone
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.put(b"c", b"3").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.put(b"a", b"4").await.unwrap();
+    handle.delete(b"c").await.unwrap();
+    handle.rotate_now().await.unwrap();
+
+    // 只 merge 文件 1 时，c 在文件 0 里还有旧版本，tombstone 必须留下
+    let report = handle.merge_files(&[1]).await.unwrap();
+    assert_eq!(report.input_files, 1);
+    assert_eq!(report.live_records, 1);
+    assert_eq!(report.kept_tombstones, 1);
+    let has_file = |id| handle.file_stats().iter().any(|file| file.file_id == id);
+    assert!(has_file(0));
+
+    // 暂停时不会 merge
+    let scheduler = MergeScheduler::spawn(&handle).unwrap();
+    scheduler.pause();
+    sleep(Duration::from_millis(50)).await;
+    assert!(has_file(0));
+
+    scheduler.resume();
+    for _ in 0..100 {
+        if !has_file(0) {
+            break;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(!has_file(0));
+    scheduler.stop().await;
+
+    Arc::into_inner(handle).unwrap().close().await.unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"4".to_vec()));
+    assert_eq!(handle.get(b"b").await.unwrap(), Some(b"2".to_vec()));
+    assert_eq!(handle.get(b"c").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_background_io_rate_limits_merge() {
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    let value = vec![b'x'; 1000];
+    for i in 0..20 {
+        let key = format!("key_{i:02}");
+        handle.put(key.as_bytes(), &value).await.unwrap();
+        handle.put(key.as_bytes(), &value).await.unwrap();
+    }
+    handle.rotate_now().await.unwrap();
+
+    // 读 40KB、写 20KB 多一点，按 100KB/s 至少要 0.6 秒
+    handle.set_background_io_rate(Some(100_000));
+    assert_eq!(handle.background_io_rate(), Some(100_000));
+    let started = std::time::Instant::now();
+    let report = handle.merge().await.unwrap();
+    assert_eq!(report.live_records, 20);
+    assert!(started.elapsed() >= StdDuration::from_millis(500));
+
+    handle.set_background_io_rate(None);
+    let started = std::time::Instant::now();
+    assert!(handle.verify().await.unwrap().is_ok());
+    assert!(started.elapsed() < StdDuration::from_millis(500));
+    assert_eq!(handle.len(), 20);
+}
+
+#[tokio::test]
+async fn test_merge_cancel_and_crash_recovery() {
+    use bitcask::CancelToken;
+
+    let file_names = |dir: &Path| {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"a", b"2").await.unwrap();
+    handle.put(b"b", b"3").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    assert!(!handle.cancel_merge());
+
+    // 取消的 merge 不留下输出，也不改动任何东西
+    let before = file_names(base_dir.path());
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let err = handle.merge_with_cancel(None, &cancel).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+    assert_eq!(file_names(base_dir.path()), before);
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"2".to_vec()));
+
+    // 模拟 merge 提交后、删完输入文件前崩溃，以及没提交的输出文件
+    let input = std::fs::read(base_dir.path().join("00000000.data")).unwrap();
+    handle.merge().await.unwrap();
+    handle.close().await.unwrap();
+    let restored = base_dir.path().join("00000000.data");
+    std::fs::write(&restored, &input).unwrap();
+    std::fs::write(base_dir.path().join("00000099.data.merging"), b"garbage").unwrap();
+    std::fs::write(base_dir.path().join("00000099.hint.merging"), b"garbage").unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    let names = file_names(base_dir.path());
+    assert!(!names.contains(&"00000000.data".to_string()), "{names:?}");
+    assert!(
+        !names.iter().any(|name| name.ends_with(".merging")),
+        "{names:?}"
+    );
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"2".to_vec()));
+    assert_eq!(handle.get(b"b").await.unwrap(), Some(b"3".to_vec()));
+    assert!(handle.verify().await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_manifest_decides_which_files_are_loaded() {
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.close().await.unwrap();
+    assert!(base_dir.path().join("MANIFEST").exists());
+
+    // 不在清单里的文件被忽略，它的 id 也不会被新文件占用
+    create_mock_data_file(
+        base_dir.path(),
+        50,
+        &[(1, b"stray".to_vec(), b"x".to_vec())],
+    );
+    // 写到一半的清单记录被丢弃
+    let mut manifest = std::fs::OpenOptions::new()
+        .append(true)
+        .open(base_dir.path().join("MANIFEST"))
+        .unwrap();
+    std::io::Write::write_all(&mut manifest, &[1, 2, 3]).unwrap();
+    drop(manifest);
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"stray").await.unwrap(), None);
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(handle.get(b"b").await.unwrap(), Some(b"2".to_vec()));
+    assert!(handle.active_file_id().await > 50);
+    assert_eq!(handle.stats().await.unwrap().data_files, 3);
+
+    // merge 之后清单只剩下输出文件和 active file
+    handle.rotate_now().await.unwrap();
+    handle.merge().await.unwrap();
+    handle.close().await.unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.len(), 2);
+    assert_eq!(handle.stats().await.unwrap().hint_files, 1);
+    handle.close().await.unwrap();
+
+    // 从目录扫描重建清单后，之前被忽略的文件也被登记
+    let data_files = BitCaskHandle::<BitCaskConfig>::repair_manifest(base_dir.path())
+        .await
+        .unwrap();
+    assert!(data_files >= 3);
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"stray").await.unwrap(), Some(b"x".to_vec()));
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+}
+
+#[test]
+fn test_reads_during_merge_never_miss_files() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let runtime = || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    };
+    let base_dir = tempdir().unwrap();
+    let rt = runtime();
+    let handle = Arc::new(
+        rt.block_on(BitCaskHandle::<BitCaskConfig>::open(base_dir.path()))
+            .unwrap(),
+    );
+    rt.block_on(async {
+        for i in 0..20 {
+            let key = format!("k{i}");
+            handle.put(key.as_bytes(), b"0").await.unwrap();
+        }
+    });
+
+    // 每个读线程有自己的 runtime，查 keydir 和打开文件之间可能插进一次 merge
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let handle = handle.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut reads = 0;
+                runtime().block_on(async {
+                    while !stop.load(Ordering::Relaxed) {
+                        for i in 0..20 {
+                            let key = format!("k{i}");
+                            let value = handle.get(key.as_bytes()).await.unwrap();
+                            assert!(value.is_some(), "{key} missing");
+                            let (_, meta) =
+                                handle.get_with_meta(key.as_bytes()).await.unwrap().unwrap();
+                            assert!(meta.version > 0);
+                            reads += 2;
+                        }
+                    }
+                });
+                reads
+            })
+        })
+        .collect();
+
+    rt.block_on(async {
+        for round in 1..=30 {
+            for i in 0..20 {
+                let key = format!("k{i}");
+                handle
+                    .put(key.as_bytes(), round.to_string().as_bytes())
+                    .await
+                    .unwrap();
+            }
+            handle.rotate_now().await.unwrap();
+            handle.merge().await.unwrap();
+        }
+    });
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+        assert!(reader.join().unwrap() > 0);
+    }
+
+    // 读都结束了，被 merge 取代的文件都会删掉（在阻塞线程池上，稍后完成）
+    let count_data_files = || {
+        std::fs::read_dir(base_dir.path())
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .and_then(|x| x.to_str())
+                    == Some("data")
+            })
+            .count()
+    };
+    let handle = Arc::into_inner(handle).unwrap();
+    rt.block_on(async {
+        let data_files = handle.stats().await.unwrap().data_files;
+        let started = std::time::Instant::now();
+        while count_data_files() != data_files {
+            assert!(started.elapsed() < StdDuration::from_secs(10));
+            tokio::time::sleep(StdDuration::from_millis(5)).await;
+        }
+        assert_eq!(handle.get(b"k7").await.unwrap(), Some(b"30".to_vec()));
+        handle.close().await.unwrap();
+    });
+}
+
+#[tokio::test]
+async fn test_merge_drops_tombstones_only_without_older_versions_outside() {
+    let base_dir = tempdir().unwrap();
+    // 不用 keydir 快照，每次都从文件重建，旧版本复活的话能看出来
+    let reopen = |handle: BitCaskHandle<BitCaskConfig>| async {
+        handle.close().await.unwrap();
+        std::fs::remove_file(base_dir.path().join("keydir.snapshot")).unwrap();
+        BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+            .await
+            .unwrap()
+    };
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    // 文件 0: a b c；文件 1: 删除 a、x=1 又删除 x；文件 2: 删除 b、写 c
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"1").await.unwrap();
+    handle.put(b"c", b"1").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.delete(b"a").await.unwrap();
+    handle.put(b"x", b"1").await.unwrap();
+    handle.delete(b"x").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.delete(b"b").await.unwrap();
+    handle.put(b"c", b"2").await.unwrap();
+    handle.rotate_now().await.unwrap();
+
+    // 文件 0 里有比两个 tombstone 都老的记录，a 和 x 的 tombstone 都要留下
+    let report = handle.merge_files(&[1]).await.unwrap();
+    assert_eq!(report.kept_tombstones, 2);
+    assert_eq!(report.dropped_records, 1);
+    let handle = reopen(handle).await;
+    assert_eq!(handle.get(b"a").await.unwrap(), None);
+    assert_eq!(handle.get(b"x").await.unwrap(), None);
+
+    // 文件 0 和上次的输出一起 merge：剩下的文件 2 只有更新的记录，tombstone 可以丢弃
+    let active_id = handle.active_file_id().await;
+    let oldest: Vec<u64> = handle
+        .file_stats()
+        .iter()
+        .map(|file| file.file_id)
+        .filter(|&id| id != 2 && id != active_id)
+        .collect();
+    let report = handle.merge_files(&oldest).await.unwrap();
+    assert_eq!(report.kept_tombstones, 0);
+    assert_eq!(report.live_records, 0);
+    let handle = reopen(handle).await;
+    assert_eq!(handle.get(b"a").await.unwrap(), None);
+    assert_eq!(handle.get(b"b").await.unwrap(), None);
+    assert_eq!(handle.get(b"x").await.unwrap(), None);
+    assert_eq!(handle.get(b"c").await.unwrap(), Some(b"2".to_vec()));
+
+    // 全部 merge 之后没有 tombstone 剩下
+    handle.rotate_now().await.unwrap();
+    let report = handle.merge().await.unwrap();
+    assert_eq!(report.kept_tombstones, 0);
+    let handle = reopen(handle).await;
+    assert_eq!(handle.len(), 1);
+    for key in [&b"a"[..], b"b", b"x"] {
+        assert_eq!(handle.get(key).await.unwrap(), None);
+    }
+}
+
+#[tokio::test]
+async fn test_checkpoint_is_an_independent_store() {
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"1").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.delete(b"b").await.unwrap();
+    handle.put(b"c", b"1").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.merge_files(&[0]).await.unwrap();
+    handle
+        .namespace("users")
+        .await
+        .unwrap()
+        .put(b"u", b"1")
+        .await
+        .unwrap();
+    // 还在 active file 里的写入也要进备份
+    handle.put(b"d", b"1").await.unwrap();
+
+    let backup = tempdir().unwrap();
+    let dest = backup.path().join("checkpoint");
+    let report = handle.checkpoint(&dest).await.unwrap();
+    assert_eq!(report.hint_files, 1);
+    assert_eq!(report.data_files, 3);
+    assert!(report.bytes > 0);
+    let err = handle.checkpoint(&dest).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+    // 备份之后源存储的写入和 merge 不影响备份
+    handle.put(b"e", b"1").await.unwrap();
+    handle.delete(b"a").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.merge().await.unwrap();
+
+    let copy = BitCaskHandle::<BitCaskConfig>::open(&dest).await.unwrap();
+    assert!(copy.verify().await.unwrap().is_ok());
+    assert_eq!(copy.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(copy.get(b"b").await.unwrap(), None);
+    assert_eq!(copy.get(b"d").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(copy.get(b"e").await.unwrap(), None);
+    assert_eq!(
+        copy.namespace("users")
+            .await
+            .unwrap()
+            .get(b"u")
+            .await
+            .unwrap(),
+        Some(b"1".to_vec())
+    );
+    copy.put(b"f", b"1").await.unwrap();
+    copy.close().await.unwrap();
+
+    assert_eq!(handle.get(b"a").await.unwrap(), None);
+    assert_eq!(handle.get(b"e").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(handle.get(b"f").await.unwrap(), None);
+    assert!(handle.verify().await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_incremental_backups_restore_as_a_chain() {
+    let base_dir = tempdir().unwrap();
+    let backups = tempdir().unwrap();
+    let full = backups.path().join("full");
+    let inc1 = backups.path().join("inc1");
+    let inc2 = backups.path().join("inc2");
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"1").await.unwrap();
+    handle.checkpoint(&full).await.unwrap();
+
+    // merge 掉全量备份里的文件之后，增量备份只带 merge 的输出，并记下被移除的文件
+    handle.put(b"a", b"2").await.unwrap();
+    handle.put(b"c", b"1").await.unwrap();
+    handle.delete(b"b").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.merge().await.unwrap();
+    let report = handle
+        .incremental_backup(full.join("MANIFEST"), &inc1)
+        .await
+        .unwrap();
+    assert_eq!(report.data_files, 1);
+    assert_eq!(report.hint_files, 1);
+    assert_eq!(report.removed_files, 1);
+
+    handle.put(b"d", b"1").await.unwrap();
+    let report = handle
+        .incremental_backup(inc1.join("MANIFEST"), &inc2)
+        .await
+        .unwrap();
+    assert_eq!(report.data_files, 1);
+    assert_eq!(report.removed_files, 0);
+    handle.put(b"e", b"1").await.unwrap();
+
+    let restored = tempdir().unwrap();
+    let dest = restored.path().join("all");
+    BitCaskHandle::<BitCaskConfig>::restore(&[&full, &inc1, &inc2], &dest)
+        .await
+        .unwrap();
+    let copy = BitCaskHandle::<BitCaskConfig>::open(&dest).await.unwrap();
+    assert!(copy.verify().await.unwrap().is_ok());
+    assert_eq!(copy.get(b"a").await.unwrap(), Some(b"2".to_vec()));
+    assert_eq!(copy.get(b"b").await.unwrap(), None);
+    assert_eq!(copy.get(b"c").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(copy.get(b"d").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(copy.get(b"e").await.unwrap(), None);
+    copy.close().await.unwrap();
+
+    // 只恢复到第一个增量备份
+    let dest = restored.path().join("inc1");
+    BitCaskHandle::<BitCaskConfig>::restore(&[&full, &inc1], &dest)
+        .await
+        .unwrap();
+    let copy = BitCaskHandle::<BitCaskConfig>::open(&dest).await.unwrap();
+    assert_eq!(copy.get(b"a").await.unwrap(), Some(b"2".to_vec()));
+    assert_eq!(copy.get(b"d").await.unwrap(), None);
+    copy.close().await.unwrap();
+
+    // 缺了中间的增量备份时拒绝恢复
+    let dest = restored.path().join("broken");
+    let err = BitCaskHandle::<BitCaskConfig>::restore(&[&full, &inc2], &dest)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert!(!dest.exists());
+
+    // 别的存储的备份不能作为基准
+    let other = tempdir().unwrap();
+    let other_handle = BitCaskHandle::<BitCaskConfig>::open(other.path())
+        .await
+        .unwrap();
+    let err = other_handle
+        .incremental_backup(inc2.join("MANIFEST"), backups.path().join("other"))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn test_snapshot_ignores_later_writes_and_keeps_files() {
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"k1", b"1").await.unwrap();
+    handle.put(b"k2", b"1").await.unwrap();
+    handle.put(b"x", b"1").await.unwrap();
+
+    let snapshot = handle.snapshot();
+    handle.put(b"k1", b"2").await.unwrap();
+    handle.delete(b"k2").await.unwrap();
+    handle.put(b"k3", b"1").await.unwrap();
+
+    assert_eq!(snapshot.len(), 3);
+    assert_eq!(
+        snapshot.keys(),
+        vec![b"k1".to_vec(), b"k2".to_vec(), b"x".to_vec()]
+    );
+    assert_eq!(snapshot.get(b"k1").await.unwrap(), Some(b"1".to_vec()));
+    assert!(snapshot.contains_key(b"k2"));
+    assert!(!snapshot.contains_key(b"k3"));
+    let (_, meta) = snapshot.get_with_meta(b"k1").await.unwrap().unwrap();
+    assert_eq!(meta.version, 1);
+    assert_eq!(handle.get(b"k1").await.unwrap(), Some(b"2".to_vec()));
+    assert_eq!(handle.get(b"k2").await.unwrap(), None);
+
+    // merge 掉视图引用的文件后，文件留到视图被 drop
+    handle.rotate_now().await.unwrap();
+    handle.merge().await.unwrap();
+    let first_file = base_dir.path().join("00000000.data");
+    assert!(first_file.exists());
+    assert_eq!(
+        snapshot.scan_prefix(b"k").await.unwrap(),
+        vec![
+            (b"k1".to_vec(), b"1".to_vec()),
+            (b"k2".to_vec(), b"1".to_vec())
+        ]
+    );
+    drop(snapshot);
+    // drop 之后在阻塞线程池上删除
+    let started = std::time::Instant::now();
+    while first_file.exists() {
+        assert!(started.elapsed() < StdDuration::from_secs(10));
+        tokio::time::sleep(StdDuration::from_millis(5)).await;
+    }
+
+    let snapshot = handle.snapshot();
+    assert_eq!(snapshot.get(b"k1").await.unwrap(), Some(b"2".to_vec()));
+    assert_eq!(snapshot.get(b"k2").await.unwrap(), None);
+    assert_eq!(snapshot.len(), 3);
+
+    // namespace 的视图只包含这个 namespace
+    let users = handle.namespace("users").await.unwrap();
+    users.put(b"k1", b"alice").await.unwrap();
+    let users_snapshot = users.snapshot();
+    users.put(b"k1", b"bob").await.unwrap();
+    assert_eq!(users_snapshot.keys(), vec![b"k1".to_vec()]);
+    assert_eq!(
+        users_snapshot.get(b"k1").await.unwrap(),
+        Some(b"alice".to_vec())
+    );
+}
+
+#[tokio::test]
+async fn test_open_rejects_files_without_format_header() {
+    let base_dir = tempdir().unwrap();
+    create_mock_data_file(base_dir.path(), 0, &[(1, b"a".to_vec(), b"1".to_vec())]);
+    let data_path = base_dir.path().join(format!("{:08}.data", 0));
+    let bytes = std::fs::read(&data_path).unwrap();
+
+    // 旧版本写的文件没有文件头，不能按新的布局去解析
+    std::fs::write(&data_path, &bytes[FILE_HEADER_SIZE as usize..]).unwrap();
+    let err = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // 不认识的格式版本同样拒绝
+    let mut future_version = bytes.clone();
+    future_version[4..8].copy_from_slice(&2u32.to_le_bytes());
+    std::fs::write(&data_path, &future_version).unwrap();
+    let err = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("format version 2"), "{err}");
+
+    std::fs::write(&data_path, &bytes).unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+}
+
+#[tokio::test]
+async fn test_corrupted_manifest_record_fails_open() {
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.close().await.unwrap();
+
+    // 损坏的不是最后一条记录，不能当成写到一半的尾部丢掉后面的修改
+    let manifest_path = base_dir.path().join("MANIFEST");
+    let mut bytes = std::fs::read(&manifest_path).unwrap();
+    // magic + version + crc + len 之后是第一条记录的内容
+    bytes[16] ^= 0xff;
+    std::fs::write(&manifest_path, &bytes).unwrap();
+    let err = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("repair-manifest"), "{err}");
+    // 打开失败时清单保持原样
+    assert_eq!(std::fs::read(&manifest_path).unwrap(), bytes);
+
+    BitCaskHandle::<BitCaskConfig>::repair_manifest(base_dir.path())
+        .await
+        .unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(handle.get(b"b").await.unwrap(), Some(b"2".to_vec()));
+}
+
+#[tokio::test]
+async fn test_merge_uses_min_seq_from_manifest() {
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    // 文件 0: a=1 又删除 a；文件 1: b；tombstone 比文件 1 和 active file 里的记录都老
+    handle.put(b"a", b"1").await.unwrap();
+    handle.delete(b"a").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.put(b"b", b"1").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    let report = handle.merge_files(&[0]).await.unwrap();
+    assert_eq!(report.kept_tombstones, 0);
+
+    // 文件 2: c=1 又删除 c；文件 3: d
+    handle.put(b"c", b"1").await.unwrap();
+    handle.delete(b"c").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.put(b"d", b"1").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    // 文件 0 里全是不需要的记录，merge 没有输出
+    let mut ids: Vec<u64> = handle
+        .file_stats()
+        .iter()
+        .map(|file| file.file_id)
+        .collect();
+    ids.push(handle.active_file_id().await);
+    assert_eq!(ids, [1, 2, 3, 4]);
+    handle.close().await.unwrap();
+
+    // 换成版本 1 的清单：不知道每个文件的最小 seq，只能保守地保留 tombstone
+    let mut payload = Vec::new();
+    for &id in &ids {
+        payload.push(1);
+        payload.extend_from_slice(&id.to_le_bytes());
+    }
+    let mut manifest = b"BCKM".to_vec();
+    manifest.extend_from_slice(&1u32.to_le_bytes());
+    manifest.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    manifest.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    manifest.extend_from_slice(&payload);
+    std::fs::write(base_dir.path().join("MANIFEST"), manifest).unwrap();
+
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"b").await.unwrap(), Some(b"1".to_vec()));
+    assert_eq!(handle.get(b"d").await.unwrap(), Some(b"1".to_vec()));
+    let report = handle.merge_files(&[2]).await.unwrap();
+    assert_eq!(report.kept_tombstones, 1);
+    assert_eq!(handle.get(b"c").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_merge_scheduler_skips_kept_tombstones() {
+    use bitcask::{MergePolicy, MergeScheduler};
+
+    let base_dir = tempdir().unwrap();
+    let config = || BitCaskConfig {
+        merge_policy: Some(MergePolicy {
+            min_dead_ratio: 0.5,
+            check_interval: Duration::from_millis(10),
+            ..MergePolicy::default()
+        }),
+        ..BitCaskConfig::default()
+    };
+    let handle = Arc::new(
+        BitCaskHandle::open_with_config(base_dir.path(), config())
+            .await
+            .unwrap(),
+    );
+    // 文件 0 里只有 a 会变成垃圾，占比不到一半；文件 1 只有 a 的 tombstone
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.put(b"c", b"3").await.unwrap();
+    handle.put(b"d", b"4").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.delete(b"a").await.unwrap();
+    handle.rotate_now().await.unwrap();
+
+    // 输出里只有必须保留的 tombstone，全是垃圾但一个字节也回收不了
+    let report = handle.merge_files(&[1]).await.unwrap();
+    assert_eq!(report.kept_tombstones, 1);
+    let file_ids = |handle: &BitCaskHandle<BitCaskConfig>| -> Vec<u64> {
+        handle
+            .file_stats()
+            .iter()
+            .map(|file| file.file_id)
+            .collect()
+    };
+    let before = file_ids(&handle);
+    let output = handle
+        .file_stats()
+        .into_iter()
+        .find(|file| file.file_id > 1 && file.dead_bytes > 0)
+        .unwrap();
+    assert_eq!(output.dead_ratio(), 1.0);
+    assert_eq!(output.kept_bytes, output.dead_bytes);
+    assert_eq!(output.reclaimable_bytes(), 0);
+
+    // 调度器跑了好几轮也不会再 merge 它
+    let scheduler = MergeScheduler::spawn(&handle).unwrap();
+    sleep(Duration::from_millis(100)).await;
+    scheduler.stop().await;
+    assert_eq!(file_ids(&handle), before);
+
+    // 保留的字节数记在清单里，重新打开之后也一样
+    Arc::into_inner(handle).unwrap().close().await.unwrap();
+    let handle = Arc::new(
+        BitCaskHandle::open_with_config(base_dir.path(), config())
+            .await
+            .unwrap(),
+    );
+    let reopened = handle
+        .file_stats()
+        .into_iter()
+        .find(|file| file.file_id == output.file_id)
+        .unwrap();
+    assert_eq!(reopened, output);
+    let scheduler = MergeScheduler::spawn(&handle).unwrap();
+    sleep(Duration::from_millis(100)).await;
+    scheduler.stop().await;
+    assert_eq!(file_ids(&handle), before);
+    assert_eq!(handle.get(b"a").await.unwrap(), None);
+    assert_eq!(handle.get(b"b").await.unwrap(), Some(b"2".to_vec()));
+}
+
+#[tokio::test]
+async fn test_writes_proceed_during_throttled_merge() {
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    let value = vec![b'x'; 1000];
+    for i in 0..20 {
+        let key = format!("key_{i:02}");
+        handle.put(key.as_bytes(), &value).await.unwrap();
+        handle.put(key.as_bytes(), &value).await.unwrap();
+    }
+    handle.rotate_now().await.unwrap();
+
+    // merge 按 100KB/s 要跑 0.6 秒以上，期间的写入、删除和轮转不用等它
+    handle.set_background_io_rate(Some(100_000));
+    let merge = async {
+        let started = std::time::Instant::now();
+        let report = handle.merge().await.unwrap();
+        (report, started.elapsed())
+    };
+    let writes = async {
+        sleep(Duration::from_millis(100)).await;
+        let started = std::time::Instant::now();
+        handle.put(b"key_00", b"new").await.unwrap();
+        handle.delete(b"key_01").await.unwrap();
+        handle.rotate_now().await.unwrap();
+        handle.put(b"key_20", b"added").await.unwrap();
+        started.elapsed()
+    };
+    let ((report, merge_elapsed), write_elapsed) = tokio::join!(merge, writes);
+    assert!(merge_elapsed >= StdDuration::from_millis(500));
+    assert!(
+        write_elapsed < StdDuration::from_millis(100),
+        "{write_elapsed:?}"
+    );
+    assert_eq!(report.input_files, 1);
+
+    // merge 搬过去的旧位置不会盖住期间的写入
+    handle.set_background_io_rate(None);
+    assert_eq!(handle.get(b"key_00").await.unwrap(), Some(b"new".to_vec()));
+    assert_eq!(handle.get(b"key_01").await.unwrap(), None);
+    assert_eq!(handle.get(b"key_02").await.unwrap(), Some(value.clone()));
+    assert_eq!(
+        handle.get(b"key_20").await.unwrap(),
+        Some(b"added".to_vec())
+    );
+    assert_eq!(handle.len(), 20);
+    assert!(handle.verify().await.unwrap().is_ok());
+
+    handle.close().await.unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(handle.get(b"key_00").await.unwrap(), Some(b"new".to_vec()));
+    assert_eq!(handle.get(b"key_01").await.unwrap(), None);
+    assert_eq!(handle.len(), 20);
+}
+
+#[tokio::test]
+async fn test_open_read_only_leaves_directory_untouched() {
+    let missing = tempdir().unwrap().path().join("missing");
+    let err = BitCaskHandle::<BitCaskConfig>::open_read_only(&missing)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert!(!missing.exists());
+
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    handle.put(b"a", b"1").await.unwrap();
+    handle.put(b"b", b"2").await.unwrap();
+    handle.rotate_now().await.unwrap();
+    handle.put(b"a", b"3").await.unwrap();
+    handle.delete(b"b").await.unwrap();
+    handle.put(b"c", b"4").await.unwrap();
+    // 不写快照，只读打开时从文件重建 keydir
+    drop(handle);
+
+    let contents = |dir: &Path| {
+        let mut files: Vec<(String, Vec<u8>)> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, std::fs::read(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = contents(base_dir.path());
+
+    let store = BitCaskHandle::<BitCaskConfig>::open_read_only(base_dir.path())
+        .await
+        .unwrap();
+    assert_eq!(store.get(b"a").await.unwrap(), Some(b"3".to_vec()));
+    assert_eq!(store.get(b"b").await.unwrap(), None);
+    assert_eq!(store.keys(), vec![b"a".to_vec(), b"c".to_vec()]);
+    assert_eq!(
+        store.scan_prefix(b"c").await.unwrap(),
+        vec![(b"c".to_vec(), b"4".to_vec())]
+    );
+    let stats = store.stats().await.unwrap();
+    assert_eq!(stats.key_count, 2);
+    assert_eq!(stats.data_files, 2);
+    assert!(stats.dead_bytes > 0);
+    assert!(store.verify().await.unwrap().is_ok());
+    drop(store);
+
+    assert_eq!(contents(base_dir.path()), before);
+}
+
+#[tokio::test]
+async fn test_io_rate_change_and_cancel_wake_waiting_merge() {
+    let base_dir = tempdir().unwrap();
+    let handle = BitCaskHandle::<BitCaskConfig>::open(base_dir.path())
+        .await
+        .unwrap();
+    let value = vec![b'x'; 200_000];
+    handle.put(b"a", &value).await.unwrap();
+    handle.put(b"a", &value).await.unwrap();
+    handle.rotate_now().await.unwrap();
+
+    // 按 1KB/s 读一条 200KB 的记录要等几分钟，改成不限速之后马上继续
+    handle.set_background_io_rate(Some(1000));
+    let started = std::time::Instant::now();
+    let (report, ()) = tokio::join!(handle.merge(), async {
+        sleep(Duration::from_millis(100)).await;
+        handle.set_background_io_rate(None);
+    });
+    assert_eq!(report.unwrap().live_records, 1);
+    assert!(
+        started.elapsed() < StdDuration::from_secs(2),
+        "{:?}",
+        started.elapsed()
+    );
+
+    handle.put(b"a", &value).await.unwrap();
+    handle.rotate_now().await.unwrap();
+
+    // 取消也不用等限速
+    handle.set_background_io_rate(Some(1000));
+    let started = std::time::Instant::now();
+    let (result, ()) = tokio::join!(handle.merge(), async {
+        sleep(Duration::from_millis(100)).await;
+        assert!(handle.cancel_merge());
+    });
+    assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::Interrupted);
+    assert!(
+        started.elapsed() < StdDuration::from_secs(2),
+        "{:?}",
+        started.elapsed()
+    );
+
+    handle.set_background_io_rate(None);
+    assert_eq!(handle.get(b"a").await.unwrap(), Some(value));
+    assert!(handle.verify().await.unwrap().is_ok());
+}
+
+fn run_cli(args: &[&std::ffi::OsStr], stdin: &[u8]) -> (i32, Vec<u8>) {
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bitcask"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (output.status.code().unwrap(), output.stdout)
+}
+
+#[test]
+fn test_cli_exit_codes() {
+    let base_dir = tempdir().unwrap();
+    let dir = base_dir.path().join("store");
+    let cli = |args: &[&str], stdin: &[u8]| {
+        let mut full: Vec<&std::ffi::OsStr> = vec![dir.as_os_str()];
+        full.extend(args.iter().map(std::ffi::OsStr::new));
+        run_cli(&full, stdin)
+    };
+
+    // 参数错误
+    assert_eq!(run_cli(&[], b"").0, 2);
+    assert_eq!(cli(&["get"], b"").0, 2);
+    assert_eq!(cli(&["bogus"], b"").0, 2);
+    assert_eq!(cli(&["scan", "--bogus", "x"], b"").0, 2);
+
+    // 目录不存在时除了 put 都不创建它
+    for args in [&["get", "k"][..], &["delete", "k"], &["merge"], &["verify"]] {
+        assert_eq!(cli(args, b"").0, 1, "{args:?}");
+    }
+    assert!(!dir.exists());
+
+    assert_eq!(cli(&["put", "k"], b"hello"), (0, Vec::new()));
+    assert_eq!(cli(&["get", "k"], b""), (0, b"hello".to_vec()));
+    assert_eq!(cli(&["get", "missing"], b"").0, 1);
+    assert_eq!(cli(&["keys"], b""), (0, b"k\n".to_vec()));
+    assert_eq!(cli(&["verify"], b"").0, 0);
+    assert_eq!(cli(&["delete", "k"], b"").0, 0);
+    assert_eq!(cli(&["delete", "k"], b"").0, 1);
+    assert_eq!(cli(&["get", "k"], b"").0, 1);
+
+    // 改掉 value 的最后一个字节，校验和对不上
+    assert_eq!(cli(&["put", "v", "--file", "/dev/null"], b"").0, 0);
+    assert_eq!(cli(&["put", "v"], b"value").0, 0);
+    let data_path = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "data"))
+        .max()
+        .unwrap();
+    std::fs::set_permissions(
+        &data_path,
+        std::os::unix::fs::PermissionsExt::from_mode(0o644),
+    )
+    .unwrap();
+    let mut bytes = std::fs::read(&data_path).unwrap();
+    *bytes.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&data_path, &bytes).unwrap();
+    let (code, stdout) = cli(&["verify"], b"");
+    assert_eq!(code, 4);
+    assert!(
+        String::from_utf8_lossy(&stdout).contains("1 problems"),
+        "{}",
+        stdout.escape_ascii()
+    );
+}
+
+#[test]
+fn test_cli_admin_socket_permissions() {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::process::{Command, Stdio};
+
+    let base_dir = tempdir().unwrap();
+    let dir = base_dir.path().join("store");
+    let socket = base_dir.path().join("admin.sock");
+    let serve = || {
+        Command::new(env!("CARGO_BIN_EXE_bitcask"))
+            .arg(&dir)
+            .args(["serve", "--addr", "127.0.0.1:0", "--admin-socket"])
+            .arg(&socket)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap()
+    };
+
+    // 路径上已经有一个普通文件时不删除它，直接退出
+    std::fs::write(&socket, b"keep me").unwrap();
+    assert_eq!(serve().wait().unwrap().code(), Some(3));
+    assert_eq!(std::fs::read(&socket).unwrap(), b"keep me");
+    std::fs::remove_file(&socket).unwrap();
+
+    // socket 出现之后马上被改成只有所有者能连接
+    let mut child = serve();
+    let started = std::time::Instant::now();
+    while !std::fs::symlink_metadata(&socket)
+        .is_ok_and(|m| m.file_type().is_socket() && m.permissions().mode() & 0o777 == 0o600)
+    {
+        assert!(started.elapsed() < StdDuration::from_secs(10));
+        std::thread::sleep(StdDuration::from_millis(10));
+    }
+    let status = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(child.wait().unwrap().success());
+    assert!(!socket.exists());
+}
+
+#[tokio::test]
+async fn test_resp_scan_cursor_is_a_key_position() {
+    let base_dir = tempdir().unwrap();
+    let (addr, shutdown, server) = start_resp_server(base_dir.path()).await;
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    for key in ["a", "b", "c", "d", "e"] {
+        resp_roundtrip(
+            &mut stream,
+            format!("SET {key} 1\r\n").as_bytes(),
+            b"+OK\r\n",
+        )
+        .await;
+    }
+
+    resp_roundtrip(
+        &mut stream,
+        b"SCAN 0 COUNT 2\r\n",
+        b"*2\r\n$1\r\n1\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n",
+    )
+    .await;
+    // cursor 对应的 key 被删掉之后，下一页照样从它之后开始
+    resp_roundtrip(&mut stream, b"DEL b\r\n", b":1\r\n").await;
+    resp_roundtrip(
+        &mut stream,
+        b"SCAN 1 COUNT 2\r\n",
+        b"*2\r\n$1\r\n2\r\n*2\r\n$1\r\nc\r\n$1\r\nd\r\n",
+    )
+    .await;
+    resp_roundtrip(&mut stream, b"SCAN 99\r\n", b"-ERR invalid cursor\r\n").await;
+    // 换一个连接也能继续迭代
+    let mut other = tokio::net::TcpStream::connect(addr).await.unwrap();
+    resp_roundtrip(
+        &mut other,
+        b"SCAN 2 COUNT 2\r\n",
+        b"*2\r\n$1\r\n0\r\n*1\r\n$1\r\ne\r\n",
+    )
+    .await;
+
+    drop((stream, other));
+    shutdown.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[test]
+fn test_cli_serve_closes_store_with_open_connections() {
+    use std::os::unix::fs::FileTypeExt;
+    use std::process::{Command, Stdio};
+
+    let base_dir = tempdir().unwrap();
+    let dir = base_dir.path().join("store");
+    let socket = base_dir.path().join("binary.sock");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bitcask"))
+        .arg(&dir)
+        .args(["serve", "--addr", "127.0.0.1:0", "--binary-socket"])
+        .arg(&socket)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = std::time::Instant::now();
+    while !std::fs::symlink_metadata(&socket).is_ok_and(|m| m.file_type().is_socket()) {
+        assert!(started.elapsed() < StdDuration::from_secs(10));
+        std::thread::sleep(StdDuration::from_millis(10));
+    }
+    // 关闭时还连着的客户端不能让 close 被跳过
+    let _idle = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+    assert!(!dir.join("keydir.snapshot").exists());
+
+    let status = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(child.wait().unwrap().success());
+    assert!(dir.join("keydir.snapshot").exists());
 }